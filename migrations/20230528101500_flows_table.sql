@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE flows (
+    id          CHAR(26) PRIMARY KEY NOT NULL, -- ULID
+    user_id     CHAR(26) NOT NULL REFERENCES users (id),
+    name        VARCHAR(255) NOT NULL,
+    definition  TEXT NOT NULL -- UserDefinedFlow as JSON
+);
+CREATE INDEX flows_user_id ON flows (user_id);
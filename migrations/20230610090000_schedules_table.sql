@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE schedules (
+    id           CHAR(26) PRIMARY KEY NOT NULL, -- ULID
+    flow_id      CHAR(26) NOT NULL REFERENCES flows (id),
+    user_id      CHAR(26) NOT NULL REFERENCES users (id),
+    cron_expr    VARCHAR(255) NOT NULL, -- minute hour day-of-month month day-of-week
+    next_run_at  INTEGER NOT NULL, -- Unix timestamp, in seconds
+    enabled      BOOLEAN NOT NULL DEFAULT TRUE
+);
+CREATE INDEX schedules_next_run_at ON schedules (enabled, next_run_at);
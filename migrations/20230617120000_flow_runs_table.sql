@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE flow_runs (
+    id           CHAR(26) PRIMARY KEY NOT NULL, -- ULID
+    flow_id      CHAR(26) NOT NULL REFERENCES flows (id),
+    user_id      CHAR(26) NOT NULL REFERENCES users (id),
+    status       VARCHAR(16) NOT NULL, -- running, succeeded, failed or cancelled
+    started_at   INTEGER NOT NULL, -- Unix timestamp, in seconds
+    finished_at  INTEGER, -- Unix timestamp, in seconds
+    report       TEXT, -- ExecutionReport as JSON, once succeeded
+    error        TEXT -- The error message, once failed
+);
+CREATE INDEX flow_runs_flow_id ON flow_runs (flow_id);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN spotify_country CHAR(2); -- ISO 3166-1 alpha-2, from me().country
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE flows ADD COLUMN last_run_at INTEGER; -- Unix timestamp, in seconds, of the start of the last successful run
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN spotify_product VARCHAR(16); -- Subscription level, from me().product
@@ -6,6 +6,11 @@ use rust_embed::RustEmbed;
 #[folder = "src/html"]
 struct HtmlAssets;
 
+/// FlowExamples holds the built-in example flows, as [`FlowDocument`](crate::handlers::flows::FlowDocument) YAML files.
+#[derive(RustEmbed)]
+#[folder = "src/examples"]
+pub struct FlowExamples;
+
 /// Returns an HttpResponse containing eaither the requested asset, or 404 Not Found
 ///
 /// # Arguments
@@ -7,7 +7,7 @@ use mobc_redis::{
 use serde::{de::DeserializeOwned, Serialize};
 use std::time::Duration;
 
-use crate::error::PublicError;
+use crate::{error::PublicError, metrics};
 
 pub type RedisPool = Pool<RedisConnectionManager>;
 pub type RedisCon = Connection<RedisConnectionManager>;
@@ -17,6 +17,11 @@ const CACHE_POOL_MAX_IDLE: u64 = 8;
 const CACHE_POOL_TIMEOUT_SECONDS: u64 = 1;
 const CACHE_POOL_EXPIRE_SECONDS: u64 = 60;
 
+/// Values larger than this (once serialized) are returned by `get_or_create_with_stats`, but not
+/// cached, and aren't stored by `set`.
+/// n.b. Huge values, e.g. a flow producing thousands of tracks, would otherwise bloat Redis.
+pub const CACHE_MAX_VALUE_BYTES: usize = 1024 * 1024;
+
 #[derive(Debug, Display, Error)]
 pub enum Error {
     #[display(fmt = "could not get redis connection from pool : {}", _0)]
@@ -52,6 +57,12 @@ pub async fn connect(
     Ok(pool)
 }
 
+// Build a cache key namespaced to the given user, e.g. `user:{user_id}:playlists`.
+// n.b. Every per-user cache entry should use this, so it is removed by `flush_user`.
+pub fn user_key(user_id: &str, name: &str) -> String {
+    format!("user:{user_id}:{name}")
+}
+
 async fn get_con(pool: &RedisPool) -> Result<RedisCon, Error> {
     pool.get().await.map_err(|e| {
         eprintln!("error connecting to redis: {}", e);
@@ -59,15 +70,27 @@ async fn get_con(pool: &RedisPool) -> Result<RedisCon, Error> {
     })
 }
 
-// Get or create a cached value with a given TTL in seconds.
+/// CacheStatus reports where a value returned by `get_or_create_with_stats` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// The value was read from the cache.
+    Hit,
+    /// The value wasn't cached, or had expired, so was created.
+    Miss,
+    /// The value was created because the caller asked for it to be reset.
+    Forced,
+}
+
+// Get or create a cached value with a given TTL in seconds, also returning whether it was cached.
 // n.b. This only excutes the given closure when the value is not value, expired, or reset=true
-pub async fn get_or_create<T, C>(
+// n.b. Values over CACHE_MAX_VALUE_BYTES are returned without being cached
+pub async fn get_or_create_with_stats<T, C>(
     pool: &RedisPool,
     key: &str,
     ttl: usize,
     reset: bool,
     callback: C,
-) -> Result<T, PublicError>
+) -> Result<(T, CacheStatus), PublicError>
 where
     T: Serialize + DeserializeOwned,
     C: Fn() -> Result<T, PublicError>,
@@ -86,7 +109,8 @@ where
             let res: String = con.get(key).await.map_err(Error::RedisCMDError)?;
             let data: T = serde_json::from_str(&res).unwrap();
 
-            return Ok(data);
+            metrics::global().record_cache(true);
+            return Ok((data, CacheStatus::Hit));
         }
     }
 
@@ -94,12 +118,273 @@ where
     // 1. Run the callback,
     // 2. Serialize to JSON string,
     // 3. Save/overwrite value in Redis
+    metrics::global().record_cache(false);
+    let status = match reset {
+        true => CacheStatus::Forced,
+        false => CacheStatus::Miss,
+    };
     let data: T = callback()?;
     let serialized: String = serde_json::to_string(&data)?;
 
-    con.set_ex(key, serialized, ttl)
+    if serialized.len() > CACHE_MAX_VALUE_BYTES {
+        log::warn!(
+            "not caching {}: value is {} bytes, the maximum is {}",
+            key,
+            serialized.len(),
+            CACHE_MAX_VALUE_BYTES
+        );
+        return Ok((data, status));
+    }
+
+    con.set_ex::<_, _, ()>(key, serialized, ttl)
+        .await
+        .map_err(Error::RedisCMDError)?;
+
+    Ok((data, status))
+}
+
+// Get a value stored with `set`, returning None if it doesn't exist or has expired.
+pub async fn get<T: DeserializeOwned>(
+    pool: &RedisPool,
+    key: &str,
+) -> Result<Option<T>, PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+    let res: Option<String> = con.get(key).await.map_err(Error::RedisCMDError)?;
+
+    Ok(res.map(|res| serde_json::from_str(&res)).transpose()?)
+}
+
+// Store a value with a given TTL in seconds, overwriting any existing value.
+// n.b. Values over CACHE_MAX_VALUE_BYTES aren't stored, and the existing value is removed instead
+pub async fn set<T: Serialize>(
+    pool: &RedisPool,
+    key: &str,
+    value: &T,
+    ttl: usize,
+) -> Result<(), PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+    let serialized: String = serde_json::to_string(value)?;
+
+    if serialized.len() > CACHE_MAX_VALUE_BYTES {
+        log::warn!(
+            "not storing {}: value is {} bytes, the maximum is {}",
+            key,
+            serialized.len(),
+            CACHE_MAX_VALUE_BYTES
+        );
+        con.del::<_, ()>(key).await.map_err(Error::RedisCMDError)?;
+        return Ok(());
+    }
+
+    con.set_ex::<_, _, ()>(key, serialized, ttl)
+        .await
+        .map_err(Error::RedisCMDError)?;
+
+    Ok(())
+}
+
+// Delete the lock (KEYS[1]) only while it still holds the caller's token (ARGV[1]).
+pub(crate) const RELEASE_LOCK_SCRIPT: &str = "\
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0";
+
+// Try to acquire a lock with the given key, returning false if it is already held.
+// The token identifies the holder, and must be unique to it (e.g. a run id) - see `release_lock`.
+// n.b. The lock expires after `ttl` seconds, so a crashed holder can't keep it forever.
+pub async fn acquire_lock(
+    pool: &RedisPool,
+    key: &str,
+    token: &str,
+    ttl: usize,
+) -> Result<bool, PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+
+    // SET NX only sets the key when it doesn't already exist, replying with nil otherwise
+    let res: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(token)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut *con)
+        .await
+        .map_err(Error::RedisCMDError)?;
+
+    Ok(res.is_some())
+}
+
+// Release a lock acquired with `acquire_lock`, if it is still held with the given token.
+// n.b. A holder that outlived the TTL may find the lock taken by someone else, which it must
+// leave alone - so the compare and delete run as one script.
+pub async fn release_lock(pool: &RedisPool, key: &str, token: &str) -> Result<(), PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+    redis::Script::new(RELEASE_LOCK_SCRIPT)
+        .key(key)
+        .arg(token)
+        .invoke_async::<_, ()>(&mut *con)
+        .await
+        .map_err(Error::RedisCMDError)?;
+
+    Ok(())
+}
+
+// Set a flag that expires after the given TTL in seconds, e.g. to signal another task.
+pub async fn set_flag(pool: &RedisPool, key: &str, ttl: usize) -> Result<(), PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+    con.set_ex::<_, _, ()>(key, 1, ttl)
         .await
         .map_err(Error::RedisCMDError)?;
 
-    Ok(data)
+    Ok(())
+}
+
+// Check whether a flag set with `set_flag` is still set.
+pub async fn is_flag_set(pool: &RedisPool, key: &str) -> Result<bool, PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+    let exists: bool = con.exists(key).await.map_err(Error::RedisCMDError)?;
+
+    Ok(exists)
+}
+
+// Delete every cache entry namespaced to the given user, returning the number of keys deleted.
+pub async fn flush_user(pool: &RedisPool, user_id: &str) -> Result<usize, PublicError> {
+    let mut con: RedisCon = get_con(pool).await?;
+
+    // Collect the keys first - SCAN borrows the connection until it is exhausted
+    let mut keys: Vec<String> = Vec::new();
+    {
+        let mut iter = con
+            .scan_match::<_, String>(user_key(user_id, "*"))
+            .await
+            .map_err(Error::RedisCMDError)?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    if keys.is_empty() {
+        return Ok(0);
+    }
+
+    let deleted: usize = con.del(keys).await.map_err(Error::RedisCMDError)?;
+    Ok(deleted)
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[actix_web::test]
+    async fn reports_cache_status() {
+        let state = testing::state().await;
+        let get = |reset| get_or_create_with_stats(&state.cache, "stats:a", 60, reset, || Ok(1));
+
+        assert_eq!(get(false).await.unwrap(), (1, CacheStatus::Miss));
+        assert_eq!(get(false).await.unwrap(), (1, CacheStatus::Hit));
+        assert_eq!(get(true).await.unwrap(), (1, CacheStatus::Forced));
+    }
+
+    #[actix_web::test]
+    async fn can_acquire_and_release_lock() {
+        let state = testing::state().await;
+
+        assert!(acquire_lock(&state.cache, "lock:a", "one", 60)
+            .await
+            .unwrap());
+        assert!(!acquire_lock(&state.cache, "lock:a", "two", 60)
+            .await
+            .unwrap());
+
+        // Locks are independent of each other
+        assert!(acquire_lock(&state.cache, "lock:b", "two", 60)
+            .await
+            .unwrap());
+
+        release_lock(&state.cache, "lock:a", "one").await.unwrap();
+        assert!(acquire_lock(&state.cache, "lock:a", "two", 60)
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn cannot_release_lock_held_by_another_token() {
+        let state = testing::state().await;
+
+        assert!(acquire_lock(&state.cache, "lock:c", "one", 60)
+            .await
+            .unwrap());
+        release_lock(&state.cache, "lock:c", "two").await.unwrap();
+        assert!(!acquire_lock(&state.cache, "lock:c", "two", 60)
+            .await
+            .unwrap());
+
+        release_lock(&state.cache, "lock:c", "one").await.unwrap();
+        assert!(acquire_lock(&state.cache, "lock:c", "two", 60)
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn can_flush_user_keys() {
+        let state = testing::state().await;
+        for key in [
+            user_key("01A", "playlists"),
+            user_key("01A", "node:1"),
+            user_key("01AB", "playlists"),
+            user_key("01B", "playlists"),
+        ] {
+            get_or_create_with_stats(&state.cache, &key, 60, false, || Ok(1))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(flush_user(&state.cache, "01A").await.unwrap(), 2);
+
+        // Other users keys are untouched, even those sharing a prefix
+        let mut con = get_con(&state.cache).await.unwrap();
+        let mut keys: Vec<String> = con.keys("*").await.unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [user_key("01AB", "playlists"), user_key("01B", "playlists")]
+        );
+    }
+
+    #[actix_web::test]
+    async fn does_not_cache_oversized_values() {
+        let state = testing::state().await;
+        let large = "x".repeat(CACHE_MAX_VALUE_BYTES);
+
+        let (res, _) =
+            get_or_create_with_stats(&state.cache, "large", 60, false, || Ok(large.clone()))
+                .await
+                .unwrap();
+        assert_eq!(res, large);
+
+        // Small values are still cached
+        get_or_create_with_stats(&state.cache, "small", 60, false, || Ok(1))
+            .await
+            .unwrap();
+
+        let mut con = get_con(&state.cache).await.unwrap();
+        let keys: Vec<String> = con.keys("*").await.unwrap();
+        assert_eq!(keys, ["small"]);
+    }
+
+    #[actix_web::test]
+    async fn does_not_store_oversized_values() {
+        let state = testing::state().await;
+        set(&state.cache, "value", &1, 60).await.unwrap();
+        assert_eq!(get::<u32>(&state.cache, "value").await.unwrap(), Some(1));
+
+        // An oversized value replaces the stored one with nothing, rather than leaving it stale
+        let large = "x".repeat(CACHE_MAX_VALUE_BYTES);
+        set(&state.cache, "value", &large, 60).await.unwrap();
+        assert_eq!(get::<String>(&state.cache, "value").await.unwrap(), None);
+    }
 }
@@ -1 +1,804 @@
 //! Combiners take 1-to-many TrackLists, and combine them to return a single TrackList
+use rand::seq::SliceRandom;
+use rspotify::{prelude::Id, AuthCodeSpotify as Client};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashSet,
+};
+
+use super::Result;
+use super::*;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct FirstNonEmptyArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FirstNonEmpty;
+
+impl Executable for FirstNonEmpty {
+    type Args = FirstNonEmptyArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Return the first non-empty input, in edge order
+    // E.g. "try source A, then B, then C" fallback chains
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Ok(prev
+            .into_iter()
+            .find(|tracks| !tracks.is_empty())
+            .unwrap_or_default())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MergeByPopularityArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MergeByPopularity;
+
+impl Executable for MergeByPopularity {
+    type Args = MergeByPopularityArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Concatenate every input, remove duplicate tracks, then sort the most popular tracks first
+    // Note: Tracks without an id can't be deduplicated, so they are always kept
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = util::dedup_tracks(prev.into_iter().flatten());
+
+        // n.b. The sort is stable, so equally popular tracks keep their input order
+        tracks.sort_by_key(|t| Reverse(t.popularity));
+        Ok(tracks)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ConcatLimitedArgs {
+    pub per_source: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ConcatLimited;
+
+impl Executable for ConcatLimited {
+    type Args = ConcatLimitedArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "per_source": {"type": "integer", "minimum": 1},
+            },
+            "required": ["per_source"],
+        })
+    }
+
+    // Concatenate the first `per_source` tracks of every input, in edge order
+    // Note: Inputs shorter than `per_source` contribute all of their tracks
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        Ok(prev
+            .into_iter()
+            .flat_map(|tracks| tracks.into_iter().take(args.per_source as usize))
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if args.per_source == 0 {
+            return Err(ComponentError::invalid_argument(
+                "per_source",
+                "must be greater than 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UnionFuzzyArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UnionFuzzy;
+
+impl Executable for UnionFuzzy {
+    type Args = UnionFuzzyArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Concatenate every input, keeping only the first version of each song
+    // Note: Songs are matched by name and primary artist (see `Track::fuzzy_key`), so e.g. the
+    // single and album versions of a song are collapsed, even though their ids differ
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Ok(util::dedup_by_key(prev.into_iter().flatten(), |t| {
+            Some(t.fuzzy_key())
+        }))
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RoundRobinArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RoundRobin;
+
+impl Executable for RoundRobin {
+    type Args = RoundRobinArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Take one track from each input in turn, in edge order, until every input is drained
+    // Note: Once the shorter inputs run out, the rest of the longer inputs are appended in turn
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = TrackList::with_capacity(prev.iter().map(Vec::len).sum());
+        let mut inputs: Vec<_> = prev.into_iter().map(Vec::into_iter).collect();
+
+        while !inputs.is_empty() {
+            inputs.retain_mut(|input| match input.next() {
+                Some(t) => {
+                    tracks.push(t);
+                    true
+                }
+                None => false,
+            });
+        }
+
+        Ok(tracks)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ZipNArgs {
+    pub group: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ZipN;
+
+impl Executable for ZipN {
+    type Args = ZipNArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "group": {"type": "integer", "minimum": 1},
+            },
+            "required": ["group"],
+        })
+    }
+
+    // Take `group` tracks from each input in turn, in edge order, until every input is drained
+    // Note: As with `combiner:round_robin` (a group of 1), leftovers of longer inputs are appended
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let group = args.group.max(1) as usize;
+        let mut tracks = TrackList::with_capacity(prev.iter().map(Vec::len).sum());
+        let mut inputs: Vec<_> = prev.into_iter().map(Vec::into_iter).collect();
+
+        while !inputs.is_empty() {
+            inputs.retain_mut(|input| {
+                let before = tracks.len();
+                tracks.extend(input.take(group));
+                tracks.len() - before == group
+            });
+        }
+
+        Ok(tracks)
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if args.group == 0 {
+            return Err(ComponentError::invalid_argument(
+                "group",
+                "must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PatternArgs {
+    /// The order to draw from the inputs, one letter per track - "A" is the first input, "B"
+    /// the second, and so on, e.g. "AABAB".
+    pub pattern: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Pattern;
+
+impl Executable for Pattern {
+    type Args = PatternArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": { "type": "string", "pattern": "^[A-Za-z]+$" },
+            },
+            "required": ["pattern"],
+        })
+    }
+
+    // Cycle through the pattern, taking the next track from the input named by each letter,
+    // until every input in the pattern is drained
+    // Note: Letters whose input has run out are skipped, inputs not in the pattern are ignored
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let pattern = pattern_indices(&args.pattern)?;
+        if let Some(c) = args
+            .pattern
+            .chars()
+            .zip(&pattern)
+            .find(|(_, i)| **i >= prev.len())
+        {
+            return Err(ComponentError::invalid_argument(
+                "pattern",
+                format!(
+                    "{} has no matching input, there are {} inputs",
+                    c.0,
+                    prev.len()
+                ),
+            ));
+        }
+
+        let mut inputs: Vec<_> = prev.into_iter().map(Vec::into_iter).collect();
+        let mut tracks = TrackList::new();
+        loop {
+            let before = tracks.len();
+            tracks.extend(pattern.iter().filter_map(|i| inputs[*i].next()));
+            // A full pass without a track means every input in the pattern is drained
+            if tracks.len() == before {
+                return Ok(tracks);
+            }
+        }
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        args.pattern = args.pattern.trim().to_uppercase();
+        pattern_indices(&args.pattern).map(|_| ())
+    }
+}
+
+/// Map each letter of the pattern to the index of its input, e.g. "ABA" -> [0, 1, 0].
+fn pattern_indices(pattern: &str) -> Result<Vec<usize>> {
+    if pattern.is_empty() {
+        return Err(ComponentError::invalid_argument(
+            "pattern",
+            "must not be empty",
+        ));
+    }
+
+    pattern
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => Ok(c as usize - 'A' as usize),
+            _ => Err(ComponentError::invalid_argument(
+                "pattern",
+                format!("{} is not a letter", c),
+            )),
+        })
+        .collect()
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ShuffleUnionArgs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ShuffleUnion;
+
+impl Executable for ShuffleUnion {
+    type Args = ShuffleUnionArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "seed": {"type": "integer", "minimum": 0},
+            },
+            "required": [],
+        })
+    }
+
+    // Concatenate every input, remove duplicate tracks, then shuffle what's left
+    // Note: Duplicates are removed before shuffling, so the same seed always gives the same
+    // order for the same inputs. Tracks without an id can't be deduplicated, so they are kept.
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = util::dedup_tracks(prev.into_iter().flatten());
+        tracks.shuffle(&mut rng(args.seed));
+        Ok(tracks)
+    }
+
+    fn seed(args: &mut Self::Args) -> Option<&mut Option<u64>> {
+        Some(&mut args.seed)
+    }
+}
+
+// --
+
+/// The track fields `combiner:dovetail` can merge by.
+const DOVETAIL_FIELDS: [&str; 4] = ["popularity", "duration_ms", "track_number", "name"];
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DovetailArgs {
+    /// One of DOVETAIL_FIELDS, the field every input is already sorted by.
+    pub by: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Dovetail;
+
+impl Executable for Dovetail {
+    type Args = DovetailArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "by": {"type": "string", "enum": DOVETAIL_FIELDS},
+            },
+            "required": ["by"],
+        })
+    }
+
+    // Merge inputs that are each sorted by the same field into one list that stays sorted,
+    // e.g. two lists sorted by popularity
+    // Note: The direction is taken from the inputs - if any runs from high to low the merge does
+    // too. Ties are taken from the earlier input first.
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        let compare = |a: &Track, b: &Track| compare_by(&args.by, a, b);
+        let descending = prev
+            .iter()
+            .any(|tracks| match (tracks.first(), tracks.last()) {
+                (Some(first), Some(last)) => compare(first, last) == Ordering::Greater,
+                _ => false,
+            });
+        let in_order = |a: &Track, b: &Track| match descending {
+            true => compare(a, b) != Ordering::Less,
+            false => compare(a, b) != Ordering::Greater,
+        };
+
+        Ok(prev.into_iter().fold(TrackList::new(), |merged, tracks| {
+            let mut res = TrackList::with_capacity(merged.len() + tracks.len());
+            let (mut lhs, mut rhs) = (merged.into_iter().peekable(), tracks.into_iter().peekable());
+            while let (Some(a), Some(b)) = (lhs.peek(), rhs.peek()) {
+                let next = match in_order(a, b) {
+                    true => lhs.next(),
+                    false => rhs.next(),
+                };
+                res.extend(next);
+            }
+            res.extend(lhs);
+            res.extend(rhs);
+            res
+        }))
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if !DOVETAIL_FIELDS.contains(&args.by.as_str()) {
+            return Err(ComponentError::invalid_argument(
+                "by",
+                format!(
+                    "expected one of {}, found {}",
+                    DOVETAIL_FIELDS.join(", "),
+                    args.by
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Compare two tracks by one of DOVETAIL_FIELDS.
+fn compare_by(field: &str, a: &Track, b: &Track) -> Ordering {
+    match field {
+        "popularity" => a.popularity.cmp(&b.popularity),
+        "duration_ms" => a.duration.cmp(&b.duration),
+        "track_number" => a.track_number.cmp(&b.track_number),
+        "name" => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        _ => Ordering::Equal,
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UnionWeightedArgs {
+    /// The relative weight of each input, in edge order. Inputs without a weight default to 1.
+    #[serde(default)]
+    pub weights: Vec<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UnionWeighted;
+
+impl Executable for UnionWeighted {
+    type Args = UnionWeightedArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "weights": {
+                    "type": "array",
+                    "items": {"type": "number", "exclusiveMinimum": 0},
+                },
+                "seed": {"type": "integer", "minimum": 0},
+            },
+            "required": [],
+        })
+    }
+
+    // Repeatedly pick an input at random, in proportion to its weight, and take its next track
+    // that hasn't been taken already, until every input is drained
+    // Note: Duplicates are skipped rather than re-picked, so an input whose remaining tracks
+    // were all taken from another input is treated as drained. Tracks without an id are kept.
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        let mut rng = rng(args.seed);
+        let mut weights: Vec<f64> = (0..prev.len())
+            .map(|i| args.weights.get(i).copied().unwrap_or(1.0))
+            .collect();
+        let mut inputs: Vec<_> = prev.into_iter().map(Vec::into_iter).collect();
+
+        let mut seen = HashSet::new();
+        let mut tracks = TrackList::new();
+        // n.b. Drained inputs have their weight zeroed, so this fails once all of them are
+        let indexes: Vec<usize> = (0..inputs.len()).collect();
+        while let Ok(&i) = indexes.choose_weighted(&mut rng, |&i| weights[i]) {
+            let next = inputs[i].find(|t| match &t.id {
+                Some(id) => seen.insert(id.id().to_owned()),
+                None => true,
+            });
+            match next {
+                Some(track) => tracks.push(track),
+                None => weights[i] = 0.0,
+            }
+        }
+        Ok(tracks)
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if let Some(w) = args.weights.iter().find(|w| !w.is_finite() || **w <= 0.0) {
+            return Err(ComponentError::invalid_argument(
+                "weights",
+                format!("must be greater than 0, found {}", w),
+            ));
+        }
+        Ok(())
+    }
+
+    fn seed(args: &mut Self::Args) -> Option<&mut Option<u64>> {
+        Some(&mut args.seed)
+    }
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{FlowLimits, UserDefinedFlow};
+    use crate::error::PublicError;
+    use crate::testing::{artist, ids, track};
+
+    #[test]
+    fn first_non_empty_skips_empty_inputs() {
+        let prev = vec![vec![], vec![], vec![track("c")], vec![track("d")]];
+        let res = FirstNonEmpty::execute(&Client::default(), FirstNonEmptyArgs {}, prev).unwrap();
+
+        assert_eq!(ids(&res), ["c"]);
+    }
+
+    #[test]
+    fn first_non_empty_returns_empty_when_all_inputs_are_empty() {
+        let prev = vec![vec![], vec![]];
+        let res = FirstNonEmpty::execute(&Client::default(), FirstNonEmptyArgs {}, prev).unwrap();
+
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn merge_by_popularity_dedups_then_sorts() {
+        let popular = |id: &str, popularity: u32| {
+            let mut t = track(id);
+            t.popularity = popularity;
+            t
+        };
+        let prev = vec![
+            vec![popular("a", 10), popular("b", 50), popular("c", 30)],
+            vec![popular("d", 30), popular("a", 90), popular("e", 70)],
+        ];
+
+        let res =
+            MergeByPopularity::execute(&Client::default(), MergeByPopularityArgs {}, prev).unwrap();
+
+        // The first "a" wins the dedup, so it keeps its popularity of 10
+        assert_eq!(ids(&res), ["e", "b", "c", "d", "a"]);
+    }
+
+    #[test]
+    fn concat_limited_takes_first_tracks_of_each_input() {
+        let prev = vec![
+            vec![track("a1"), track("a2"), track("a3"), track("a4")],
+            vec![track("b1")],
+            vec![],
+            vec![track("c1"), track("c2"), track("c3")],
+        ];
+        let args = ConcatLimitedArgs { per_source: 2 };
+        let res = ConcatLimited::execute(&Client::default(), args, prev).unwrap();
+
+        assert_eq!(ids(&res), ["a1", "a2", "b1", "c1", "c2"]);
+    }
+
+    #[test]
+    fn concat_limited_rejects_zero_per_source() {
+        let args = ConcatLimitedArgs { per_source: 0 };
+        let res = ConcatLimited::execute(&Client::default(), args, vec![vec![track("a")]]);
+
+        assert!(matches!(
+            res,
+            Err(ComponentError::InvalidArgument {
+                name: "per_source",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn concat_limited_per_source_is_checked_before_running() {
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    377033c8-c36c-4f04-a716-5e1736f4dfdc:
+        component: combiner:concat_limited
+        parameters:
+            per_source: 0
+edges: []
+"#,
+        )
+        .unwrap();
+
+        let Err(PublicError::Invalid { errors }) = flow.check(&FlowLimits::default()) else {
+            panic!("expected the flow to be invalid");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field.as_deref(), Some("parameters.per_source"));
+    }
+
+    #[test]
+    fn union_fuzzy_collapses_duplicates_across_inputs() {
+        let named = |id: &str, name: &str, artist_id: &str| {
+            let mut t = track(id);
+            t.name = name.to_owned();
+            t.artists = vec![artist(artist_id)];
+            t
+        };
+
+        let singles = vec![
+            named("single", "Song (Radio Edit)", "a"),
+            named("other", "Other Song", "a"),
+        ];
+        let albums = vec![
+            named("album", "Song", "a"),
+            named("remaster", "song - Remastered 2011", "a"),
+            named("cover", "Song", "b"),
+            named("other2", "Other  Song!", "a"),
+        ];
+        let res = UnionFuzzy::execute(&Client::default(), UnionFuzzyArgs {}, vec![singles, albums])
+            .unwrap();
+
+        // The first version of each song is kept, a different artist's song is not a duplicate
+        assert_eq!(ids(&res), ["single", "other", "cover"]);
+    }
+
+    #[test]
+    fn round_robin_interleaves_until_all_inputs_are_drained() {
+        let short = vec![track("a1"), track("a2"), track("a3")];
+        let long = (1..=7).map(|i| track(&format!("b{i}"))).collect();
+        let res =
+            RoundRobin::execute(&Client::default(), RoundRobinArgs {}, vec![short, long]).unwrap();
+
+        assert_eq!(
+            ids(&res),
+            ["a1", "b1", "a2", "b2", "a3", "b3", "b4", "b5", "b6", "b7"]
+        );
+    }
+
+    #[test]
+    fn zip_n_draws_groups_from_each_input() {
+        let a: TrackList = (1..=5).map(|i| track(&format!("a{i}"))).collect();
+        let b: TrackList = (1..=2).map(|i| track(&format!("b{i}"))).collect();
+        let run = |group| {
+            let prev = vec![a.clone(), b.clone()];
+            ids(&ZipN::execute(&Client::default(), ZipNArgs { group }, prev).unwrap())
+        };
+
+        let zipped = RoundRobin::execute(
+            &Client::default(),
+            RoundRobinArgs {},
+            vec![a.clone(), b.clone()],
+        )
+        .unwrap();
+        assert_eq!(run(1), ids(&zipped));
+        assert_eq!(run(2), ["a1", "a2", "b1", "b2", "a3", "a4", "a5"]);
+    }
+
+    #[test]
+    fn pattern_draws_from_inputs_in_order_until_drained() {
+        let a = vec![
+            track("a1"),
+            track("a2"),
+            track("a3"),
+            track("a4"),
+            track("a5"),
+        ];
+        let b = vec![track("b1"), track("b2")];
+        let args = PatternArgs {
+            pattern: "AAB".to_owned(),
+        };
+        let res = Pattern::execute(&Client::default(), args, vec![a, b]).unwrap();
+
+        assert_eq!(ids(&res), ["a1", "a2", "b1", "a3", "a4", "b2", "a5"]);
+    }
+
+    #[test]
+    fn pattern_rejects_letters_without_an_input() {
+        let args = PatternArgs {
+            pattern: "ABC".to_owned(),
+        };
+        let prev = vec![vec![track("a")], vec![track("b")]];
+
+        assert!(Pattern::execute(&Client::default(), args, prev).is_err());
+    }
+
+    #[test]
+    fn pattern_normalizes_to_uppercase_letters() {
+        let mut args = PatternArgs {
+            pattern: " abab ".to_owned(),
+        };
+        Pattern::normalize(&mut args).unwrap();
+        assert_eq!(args.pattern, "ABAB");
+
+        let mut args = PatternArgs {
+            pattern: "A-B".to_owned(),
+        };
+        assert!(Pattern::normalize(&mut args).is_err());
+    }
+
+    #[test]
+    fn shuffle_union_dedups_then_shuffles_deterministically() {
+        let prev = vec![
+            vec![track("a"), track("b"), track("c")],
+            vec![track("b"), track("d"), track("a")],
+        ];
+        let shuffle = |seed| {
+            let args = ShuffleUnionArgs { seed: Some(seed) };
+            ids(&ShuffleUnion::execute(&Client::default(), args, prev.clone()).unwrap())
+        };
+
+        let res = shuffle(7);
+        let mut sorted = res.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["a", "b", "c", "d"]);
+        assert_eq!(res, shuffle(7));
+    }
+
+    #[test]
+    fn dovetail_merges_sorted_inputs() {
+        let by_popularity = |tracks: &[(&str, u32)]| -> TrackList {
+            tracks
+                .iter()
+                .map(|(id, popularity)| {
+                    let mut t = track(id);
+                    t.popularity = *popularity;
+                    t
+                })
+                .collect()
+        };
+        let prev = vec![
+            by_popularity(&[("a", 90), ("b", 60), ("c", 30)]),
+            by_popularity(&[("d", 80), ("e", 60), ("f", 10)]),
+        ];
+
+        let args = DovetailArgs {
+            by: "popularity".to_owned(),
+        };
+        let res = Dovetail::execute(&Client::default(), args, prev.clone()).unwrap();
+        assert_eq!(ids(&res), ["a", "d", "b", "e", "c", "f"]);
+
+        // Ascending inputs stay ascending
+        let prev: Vec<TrackList> = prev
+            .into_iter()
+            .map(|tracks| tracks.into_iter().rev().collect())
+            .collect();
+        let args = DovetailArgs {
+            by: "popularity".to_owned(),
+        };
+        let res = Dovetail::execute(&Client::default(), args, prev).unwrap();
+        assert_eq!(ids(&res), ["f", "c", "b", "e", "d", "a"]);
+    }
+
+    #[test]
+    fn union_weighted_never_repeats_and_follows_weights() {
+        let tracks =
+            |prefix: &'static str| (0..200).map(move |i| track(&format!("{}{}", prefix, i)));
+        // The second input ends with a copy of the first, so those picks must all be skipped
+        let prev = vec![
+            tracks("a").collect(),
+            tracks("b").chain(tracks("a")).collect(),
+        ];
+
+        let args = UnionWeightedArgs {
+            weights: vec![3.0, 1.0],
+            seed: Some(7),
+        };
+        let res = UnionWeighted::execute(&Client::default(), args, prev).unwrap();
+
+        let res = ids(&res);
+        let unique: HashSet<_> = res.iter().collect();
+        assert_eq!(res.len(), 400);
+        assert_eq!(unique.len(), 400);
+
+        // Roughly three of every four picks come from the first input while both have tracks
+        let from_first = res[..100].iter().filter(|id| id.starts_with('a')).count();
+        assert!((60..=90).contains(&from_first), "{}", from_first);
+    }
+
+    #[test]
+    fn union_weighted_rejects_non_positive_weights() {
+        let mut args = UnionWeightedArgs {
+            weights: vec![1.0, 0.0],
+            seed: None,
+        };
+
+        assert!(UnionWeighted::normalize(&mut args).is_err());
+    }
+}
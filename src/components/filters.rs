@@ -1,6 +1,15 @@
 ///! Filters do work on one source TrackList, returning it after filtering
+use rand::seq::SliceRandom;
+use rspotify::http::Query;
+use rspotify::model::{AlbumId, ArtistId, AudioFeatures, TrackId};
+use rspotify::prelude::*;
 use rspotify::AuthCodeSpotify as Client;
+
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
 
 use super::Result;
 use super::*;
@@ -17,6 +26,17 @@ pub struct Take;
 impl Executable for Take {
     type Args = TakeArgs;
 
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "limit": {"type": "integer", "minimum": 0},
+                "from": {"type": "string", "enum": ["start", "end"]},
+            },
+            "required": ["limit", "from"],
+        })
+    }
+
     fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
         let tracks = prev.first().unwrap().iter();
         if args.from.eq("end") {
@@ -27,7 +47,2239 @@ impl Executable for Take {
             Ok(tracks.take(args.limit as usize).cloned().collect())
         }
     }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if !matches!(args.from.as_str(), "start" | "end") {
+            return Err(ComponentError::invalid_argument(
+                "from",
+                format!("must be start or end, found {}", args.from),
+            ));
+        }
+        Ok(())
+    }
 }
 
-// pub struct TrackDedupFilter;
-// pub struct ArtistDedupFilter;
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CapTotalArgs {
+    pub max: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CapTotal;
+
+impl Executable for CapTotal {
+    type Args = CapTotalArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "max": {"type": "integer", "minimum": 0},
+            },
+            "required": ["max"],
+        })
+    }
+
+    // Keep at most the first X tracks
+    // Note: Lists already under the cap are returned unchanged
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = prev.into_iter().next().unwrap_or_default();
+        tracks.truncate(args.max);
+        Ok(tracks)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TotalDurationArgs {
+    pub max_minutes: u64,
+    /// Keep the first track even when it alone is longer than the cap.
+    #[serde(default)]
+    pub allow_oversized_first: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TotalDuration;
+
+impl Executable for TotalDuration {
+    type Args = TotalDurationArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "max_minutes": {"type": "integer", "minimum": 0},
+                "allow_oversized_first": {"type": "boolean", "default": false},
+            },
+            "required": ["max_minutes"],
+        })
+    }
+
+    // Keep the first tracks, until the next one would take the total duration over X minutes
+    // Note: A first track longer than the cap is only kept with `allow_oversized_first`
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let max = chrono::Duration::minutes(args.max_minutes as i64);
+
+        let mut total = chrono::Duration::zero();
+        let mut kept = TrackList::new();
+        for track in tracks {
+            let oversized_first = kept.is_empty() && args.allow_oversized_first;
+            if total + track.duration > max && !oversized_first {
+                break;
+            }
+            total = total + track.duration;
+            kept.push(track);
+        }
+        Ok(kept)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct HasPreviewArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HasPreview;
+
+impl Executable for HasPreview {
+    type Args = HasPreviewArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Keep only the tracks that have a 30 second preview clip
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| t.preview_url.is_some())
+            .collect())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PlayableArgs {
+    #[serde(default)]
+    pub require_preview: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Playable;
+
+impl Executable for Playable {
+    type Args = PlayableArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "require_preview": {"type": "boolean", "default": false},
+            },
+            "required": [],
+        })
+    }
+
+    // Keep only the tracks that can be played in the user's market
+    // Note: `is_playable` and `restrictions` are only set when the tracks were fetched for a market
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| t.is_playable != Some(false) && t.restrictions.is_none())
+            .filter(|t| !args.require_preview || t.preview_url.is_some())
+            .collect())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LocalArgs {
+    /// Drop local files when true (the default), otherwise keep only local files.
+    #[serde(default = "default_exclude_local")]
+    pub exclude_local: bool,
+}
+
+fn default_exclude_local() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Local;
+
+impl Executable for Local {
+    type Args = LocalArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "exclude_local": {"type": "boolean", "default": true},
+            },
+            "required": [],
+        })
+    }
+
+    // Drop local files, which can't be written to playlists by URI
+    // Note: With `exclude_local: false` this is inverted, keeping only the local files
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| t.is_local != args.exclude_local)
+            .collect())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BlockArtistsArgs {
+    pub artist_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BlockArtists;
+
+impl Executable for BlockArtists {
+    type Args = BlockArtistsArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "artist_ids": {"type": "array", "items": {"type": "string"}},
+            },
+            "required": ["artist_ids"],
+        })
+    }
+
+    // Remove every track featuring a blocked artist
+    // Note: This checks all the artists on a track, not just the primary artist
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        // Accept both bare IDs and spotify:artist:... URIs
+        let blocked = args
+            .artist_ids
+            .iter()
+            .map(|id| {
+                ArtistId::from_id_or_uri(id)
+                    .map(|id| id.id().to_owned())
+                    .map_err(|_| ComponentError::invalid_id("artist", id))
+            })
+            .collect::<Result<HashSet<_>>>()?;
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                !t.artists
+                    .iter()
+                    .filter_map(|a| a.id.as_ref())
+                    .any(|id| blocked.contains(id.id()))
+            })
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        for id in args.artist_ids.iter_mut() {
+            normalize_id("artist_ids", id, |id| {
+                ArtistId::from_id_or_uri(id).map(|id| id.uri())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OnlyAlbumsArgs {
+    pub album_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OnlyAlbums;
+
+impl Executable for OnlyAlbums {
+    type Args = OnlyAlbumsArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "album_ids": {"type": "array", "items": {"type": "string"}},
+            },
+            "required": ["album_ids"],
+        })
+    }
+
+    // Keep only the tracks from one of the allowed albums
+    // Note: Tracks without an album id (e.g. local files) are removed
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        // Accept both bare IDs and spotify:album:... URIs
+        let allowed = args
+            .album_ids
+            .iter()
+            .map(|id| {
+                AlbumId::from_id_or_uri(id)
+                    .map(|id| id.id().to_owned())
+                    .map_err(|_| ComponentError::invalid_id("album", id))
+            })
+            .collect::<Result<HashSet<_>>>()?;
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                t.album
+                    .id
+                    .as_ref()
+                    .is_some_and(|id| allowed.contains(id.id()))
+            })
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        for id in args.album_ids.iter_mut() {
+            normalize_id("album_ids", id, |id| {
+                AlbumId::from_id_or_uri(id).map(|id| id.uri())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MaxAlbumsArgs {
+    pub max: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MaxAlbums;
+
+impl Executable for MaxAlbums {
+    type Args = MaxAlbumsArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "max": {"type": "integer", "minimum": 0},
+            },
+            "required": ["max"],
+        })
+    }
+
+    // Keep tracks from the first X distinct albums, in order
+    // Note: Once the limit is reached, tracks from already seen albums are still accepted
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut albums = HashSet::new();
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                // n.b. Tracks without an album id (e.g. local files) are counted as one album
+                let album = t.album.id.as_ref().map(|id| id.id().to_owned());
+                if albums.contains(&album) {
+                    return true;
+                }
+                if albums.len() < args.max as usize {
+                    albums.insert(album);
+                    return true;
+                }
+                false
+            })
+            .collect())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PreferOlderArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PreferOlder;
+
+impl Executable for PreferOlder {
+    type Args = PreferOlderArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Order the tracks oldest saved first, surfacing long forgotten favourites
+    // Note: Tracks without a saved date (e.g. from an artist source) are kept, in order, at the end
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = prev.into_iter().next().unwrap_or_default();
+        tracks.sort_by_key(|t| (t.added_at.is_none(), t.added_at));
+        Ok(tracks)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SimilarToArgs {
+    pub seed_track_id: String,
+    pub radius: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SimilarTo;
+
+impl Executable for SimilarTo {
+    type Args = SimilarToArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "seed_track_id": {"type": "string"},
+                "radius": {"type": "number", "minimum": 0},
+            },
+            "required": ["seed_track_id", "radius"],
+        })
+    }
+
+    // Keep the tracks that sound like the seed track, i.e. whose audio features are within
+    // `radius` of the seed's - see `feature_vector` for the features compared
+    // Note: Tracks without audio features are dropped
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let seed_id = TrackId::from_id_or_uri(&args.seed_track_id)
+            .map_err(|_| ComponentError::invalid_id("track", &args.seed_track_id))?;
+        crate::metrics::global().record_spotify_call("track_features");
+        let seed = feature_vector(&client.track_features(seed_id)?);
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let features = audio_features(client, &tracks)?;
+
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                t.id.as_ref()
+                    .and_then(|id| features.get(id.id()))
+                    .is_some_and(|f| distance(&seed, &feature_vector(f)) <= args.radius)
+            })
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        normalize_id("seed_track_id", &mut args.seed_track_id, |id| {
+            TrackId::from_id_or_uri(id).map(|id| id.uri())
+        })
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MoodCurveArgs {
+    /// One of "rising", "falling" or "peak".
+    pub shape: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MoodCurve;
+
+impl Executable for MoodCurve {
+    type Args = MoodCurveArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "shape": {"type": "string", "enum": ["rising", "falling", "peak"]},
+            },
+            "required": ["shape"],
+        })
+    }
+
+    // Order the tracks by valence (musical positiveness) to follow an emotional arc -
+    // - rising: sad to happy
+    // - falling: happy to sad
+    // - peak: sad to happy, and back again
+    // Note: Tracks without audio features are kept, in order, at the end
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let features = audio_features(client, &tracks)?;
+
+        let mut known = Vec::new();
+        let mut unknown = TrackList::new();
+        for t in tracks {
+            match t.id.as_ref().and_then(|id| features.get(id.id())) {
+                Some(f) => known.push((f.valence, t)),
+                None => unknown.push(t),
+            }
+        }
+
+        known.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let sorted = known.into_iter().map(|(_, t)| t);
+
+        let mut curve: TrackList = match args.shape.as_str() {
+            "falling" => sorted.rev().collect(),
+            "peak" => {
+                // Alternate the ascending tracks between the rising and falling halves
+                let (rising, falling): (Vec<_>, Vec<_>) =
+                    sorted.enumerate().partition(|(i, _)| i % 2 == 0);
+                rising
+                    .into_iter()
+                    .chain(falling.into_iter().rev())
+                    .map(|(_, t)| t)
+                    .collect()
+            }
+            _ => sorted.collect(),
+        };
+
+        curve.extend(unknown);
+        Ok(curve)
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if !["rising", "falling", "peak"].contains(&args.shape.as_str()) {
+            return Err(ComponentError::invalid_argument(
+                "shape",
+                format!("expected rising, falling or peak, found {}", args.shape),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// --
+
+/// The audio features `filter:sort_by_feature` and `filter:top_by_feature` can order by.
+const SORTABLE_FEATURES: [&str; 9] = [
+    "acousticness",
+    "danceability",
+    "energy",
+    "instrumentalness",
+    "liveness",
+    "loudness",
+    "speechiness",
+    "tempo",
+    "valence",
+];
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SortByFeatureArgs {
+    /// One of SORTABLE_FEATURES, e.g. "energy".
+    pub feature: String,
+    /// One of "ascending" or "descending".
+    pub order: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SortByFeature;
+
+impl Executable for SortByFeature {
+    type Args = SortByFeatureArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "feature": {"type": "string", "enum": SORTABLE_FEATURES},
+                "order": {"type": "string", "enum": ["ascending", "descending"]},
+            },
+            "required": ["feature", "order"],
+        })
+    }
+
+    // Order the tracks by one of their audio features, e.g. ascending energy for a ramp-up
+    // Note: Tracks without audio features are kept, in order, at the end
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let features = audio_features(client, &tracks)?;
+
+        let mut known = Vec::new();
+        let mut unknown = TrackList::new();
+        for t in tracks {
+            let value =
+                t.id.as_ref()
+                    .and_then(|id| features.get(id.id()))
+                    .and_then(|f| feature_value(f, &args.feature));
+            match value {
+                Some(value) => known.push((value, t)),
+                None => unknown.push(t),
+            }
+        }
+
+        // n.b. `sort_by` is stable, so tracks with equal values keep their order
+        match args.order.as_str() {
+            "descending" => known.sort_by(|(a, _), (b, _)| b.total_cmp(a)),
+            _ => known.sort_by(|(a, _), (b, _)| a.total_cmp(b)),
+        }
+
+        let mut sorted: TrackList = known.into_iter().map(|(_, t)| t).collect();
+        sorted.extend(unknown);
+        Ok(sorted)
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        validate_feature(&args.feature)?;
+        if !matches!(args.order.as_str(), "ascending" | "descending") {
+            return Err(ComponentError::invalid_argument(
+                "order",
+                format!("must be ascending or descending, found {}", args.order),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Return ComponentError::InvalidArgument unless `feature` is one of SORTABLE_FEATURES.
+fn validate_feature(feature: &str) -> Result<()> {
+    if !SORTABLE_FEATURES.contains(&feature) {
+        return Err(ComponentError::invalid_argument(
+            "feature",
+            format!(
+                "expected one of {}, found {}",
+                SORTABLE_FEATURES.join(", "),
+                feature
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Look up an audio feature by name, see SORTABLE_FEATURES.
+fn feature_value(f: &AudioFeatures, name: &str) -> Option<f32> {
+    Some(match name {
+        "acousticness" => f.acousticness,
+        "danceability" => f.danceability,
+        "energy" => f.energy,
+        "instrumentalness" => f.instrumentalness,
+        "liveness" => f.liveness,
+        "loudness" => f.loudness,
+        "speechiness" => f.speechiness,
+        "tempo" => f.tempo,
+        "valence" => f.valence,
+        _ => return None,
+    })
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TopByFeatureArgs {
+    /// One of SORTABLE_FEATURES, e.g. "danceability".
+    pub feature: String,
+    pub count: u32,
+    /// Keep the highest scoring tracks, rather than the lowest.
+    pub descending: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TopByFeature;
+
+impl Executable for TopByFeature {
+    type Args = TopByFeatureArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "feature": {"type": "string", "enum": SORTABLE_FEATURES},
+                "count": {"type": "integer", "minimum": 0},
+                "descending": {"type": "boolean"},
+            },
+            "required": ["feature", "count", "descending"],
+        })
+    }
+
+    // Keep the `count` tracks with the highest (or lowest) score for one of their audio
+    // features, ordered by that score, e.g. the 20 most danceable tracks
+    // Note: Tracks without audio features are dropped, ties keep their original order
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        Self::normalize(&mut args.clone())?;
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let features = audio_features(client, &tracks)?;
+
+        let mut scored: Vec<(f32, Track)> = tracks
+            .into_iter()
+            .filter_map(|t| {
+                let value =
+                    t.id.as_ref()
+                        .and_then(|id| features.get(id.id()))
+                        .and_then(|f| feature_value(f, &args.feature))?;
+                Some((value, t))
+            })
+            .collect();
+
+        // n.b. `sort_by` is stable, so tracks with equal values keep their order
+        match args.descending {
+            true => scored.sort_by(|(a, _), (b, _)| b.total_cmp(a)),
+            false => scored.sort_by(|(a, _), (b, _)| a.total_cmp(b)),
+        }
+
+        Ok(scored
+            .into_iter()
+            .take(args.count as usize)
+            .map(|(_, t)| t)
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        validate_feature(&args.feature)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StratifiedSampleArgs {
+    pub total: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StratifiedSample;
+
+impl Executable for StratifiedSample {
+    type Args = StratifiedSampleArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "total": {"type": "integer", "minimum": 0},
+                "seed": {"type": "integer", "minimum": 0},
+            },
+            "required": ["total"],
+        })
+    }
+
+    // Randomly sample `total` tracks, taking roughly the same number from each primary artist
+    // Note: Places left over by the remainder, or by artists with too few tracks, are given to
+    // random artists that still have tracks. The sampled tracks keep their original order.
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let mut rng = rng(args.seed);
+
+        // Group the track indexes by primary artist, in order of first appearance
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut group_of: HashMap<Option<&ArtistId>, usize> = HashMap::new();
+        for (i, t) in tracks.iter().enumerate() {
+            let artist = t.artists.first().and_then(|a| a.id.as_ref());
+            let g = *group_of.entry(artist).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[g].push(i);
+        }
+
+        if groups.is_empty() {
+            return Ok(tracks);
+        }
+
+        let total = (args.total as usize).min(tracks.len());
+        let share = total / groups.len();
+        let mut quotas: Vec<usize> = groups.iter().map(|g| g.len().min(share)).collect();
+
+        // Hand out the remaining places one at a time, cycling through the artists in a random order
+        let mut left = total - quotas.iter().sum::<usize>();
+        let mut order: Vec<usize> = (0..groups.len()).collect();
+        order.shuffle(&mut rng);
+        while left > 0 {
+            for &g in &order {
+                if left > 0 && quotas[g] < groups[g].len() {
+                    quotas[g] += 1;
+                    left -= 1;
+                }
+            }
+        }
+
+        let mut keep = vec![false; tracks.len()];
+        for (group, quota) in groups.iter().zip(quotas) {
+            for &i in group.choose_multiple(&mut rng, quota) {
+                keep[i] = true;
+            }
+        }
+
+        Ok(tracks
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(t, keep)| keep.then_some(t))
+            .collect())
+    }
+
+    fn seed(args: &mut Self::Args) -> Option<&mut Option<u64>> {
+        Some(&mut args.seed)
+    }
+}
+
+// --
+
+/// The weight added to every track's popularity in `filter:weighted_sample`, so tracks with a
+/// popularity of 0 can still be picked.
+const WEIGHTED_SAMPLE_FLOOR: f64 = 1.0;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WeightedSampleArgs {
+    pub count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct WeightedSample;
+
+impl Executable for WeightedSample {
+    type Args = WeightedSampleArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "count": {"type": "integer", "minimum": 0},
+                "seed": {"type": "integer", "minimum": 0},
+            },
+            "required": ["count"],
+        })
+    }
+
+    // Randomly sample `count` tracks, where the chance of picking a track is proportional to its
+    // popularity (plus WEIGHTED_SAMPLE_FLOOR)
+    // Note: Tracks are sampled without replacement, and keep their original order
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let mut rng = rng(args.seed);
+
+        let indexes: Vec<usize> = (0..tracks.len()).collect();
+        let weight = |&i: &usize| tracks[i].popularity as f64 + WEIGHTED_SAMPLE_FLOOR;
+        let mut keep = vec![false; tracks.len()];
+        // n.b. Every weight is at least the floor, so this can't fail
+        for &i in indexes
+            .choose_multiple_weighted(&mut rng, args.count as usize, weight)
+            .expect("weights are positive")
+        {
+            keep[i] = true;
+        }
+
+        Ok(tracks
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(t, keep)| keep.then_some(t))
+            .collect())
+    }
+
+    fn seed(args: &mut Self::Args) -> Option<&mut Option<u64>> {
+        Some(&mut args.seed)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MinTrackNumberArgs {
+    pub min: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MinTrackNumber;
+
+impl Executable for MinTrackNumber {
+    type Args = MinTrackNumberArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "min": {"type": "integer", "minimum": 0},
+            },
+            "required": ["min"],
+        })
+    }
+
+    // Keep the tracks whose position on their album is at least X, e.g. min=2 skips album intros
+    // Note: Track numbers restart on each album (and disc), so this applies to every album
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| t.track_number >= args.min)
+            .collect())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AntiClumpArgs {
+    pub min_gap: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AntiClump;
+
+impl Executable for AntiClump {
+    type Args = AntiClumpArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "min_gap": {"type": "integer", "minimum": 0},
+            },
+            "required": ["min_gap"],
+        })
+    }
+
+    // Reorder so the same primary artist or album doesn't repeat within X tracks, greedily taking
+    // the earliest track that doesn't clash with the last X placed
+    // Note: When every remaining track clashes, the earliest is placed anyway
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut remaining = prev.into_iter().next().unwrap_or_default();
+        let mut tracks = TrackList::with_capacity(remaining.len());
+        let window = args.min_gap as usize;
+
+        while !remaining.is_empty() {
+            let recent = &tracks[tracks.len().saturating_sub(window)..];
+            let i = remaining
+                .iter()
+                .position(|t| !recent.iter().any(|r| clumps(r, t)))
+                .unwrap_or(0);
+            tracks.push(remaining.remove(i));
+        }
+
+        Ok(tracks)
+    }
+}
+
+/// Check whether two tracks share a primary artist or an album.
+/// n.b. Tracks without ids (e.g. local files) are compared by name
+fn clumps(a: &Track, b: &Track) -> bool {
+    let artist = |t: &Track| t.artists.first().map(|a| (a.id.clone(), a.name.clone()));
+    let album = |t: &Track| (t.album.id.clone(), t.album.name.clone());
+
+    artist(a) == artist(b) || album(a) == album(b)
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BalanceExplicitArgs {
+    /// The largest fraction of tracks that may be explicit, between 0 and 1.
+    pub max_explicit_ratio: f32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BalanceExplicit;
+
+impl Executable for BalanceExplicit {
+    type Args = BalanceExplicitArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "max_explicit_ratio": {"type": "number", "minimum": 0, "maximum": 1},
+            },
+            "required": ["max_explicit_ratio"],
+        })
+    }
+
+    // Drop explicit tracks, latest first, until at most X of the tracks are explicit
+    // Note: Clean tracks are always kept, and the order is otherwise unchanged
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+
+        let mut total = tracks.len();
+        let mut explicit = tracks.iter().filter(|t| t.explicit).count();
+        while explicit > 0 && explicit as f32 > args.max_explicit_ratio * total as f32 {
+            explicit -= 1;
+            total -= 1;
+        }
+
+        // Keep the first `explicit` explicit tracks
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                if !t.explicit {
+                    return true;
+                }
+                let keep = explicit > 0;
+                explicit = explicit.saturating_sub(1);
+                keep
+            })
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        if !(0.0..=1.0).contains(&args.max_explicit_ratio) {
+            return Err(ComponentError::invalid_argument(
+                "max_explicit_ratio",
+                "must be between 0 and 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeduplicateTrackArgs {
+    /// Deduplicate across every input, rather than only the first.
+    #[serde(default)]
+    pub across_inputs: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DeduplicateTrack;
+
+impl Executable for DeduplicateTrack {
+    type Args = DeduplicateTrackArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "across_inputs": {"type": "boolean", "default": false},
+            },
+            "required": [],
+        })
+    }
+
+    // Remove repeats of the same track, keeping the first
+    // Note: Only the first input is used, unless `across_inputs` is set to concatenate them all
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks: TrackList = match args.across_inputs {
+            true => prev.into_iter().flatten().collect(),
+            false => prev.into_iter().next().unwrap_or_default(),
+        };
+
+        // n.b. Tracks without an id can't be deduplicated, so they are always kept
+        Ok(util::dedup_tracks(tracks))
+    }
+}
+
+// pub struct ArtistDedupFilter;
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CanonicalizeArgs {}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Canonicalize;
+
+impl Executable for Canonicalize {
+    type Args = CanonicalizeArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+            "required": [],
+        })
+    }
+
+    // Replace the id of each relinked track with the id of the track that was requested, so the
+    // same song fetched for different markets has the same id, e.g. before `filter:dedup_track`
+    // Note: Tracks that weren't relinked (no `linked_from`) are left unchanged
+    fn execute(_: &Client, _: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let mut tracks = prev.into_iter().next().unwrap_or_default();
+        for t in tracks.iter_mut() {
+            if let Some(link) = &t.linked_from {
+                t.id = Some(link.id.clone());
+            }
+        }
+        Ok(tracks)
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NameMatchesArgs {
+    pub pattern: String,
+    /// Keep the matching tracks, rather than removing them.
+    #[serde(default = "default_keep")]
+    pub keep: bool,
+}
+
+fn default_keep() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NameMatches;
+
+impl Executable for NameMatches {
+    type Args = NameMatchesArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": {"type": "string", "format": "regex"},
+                "keep": {"type": "boolean", "default": true},
+            },
+            "required": ["pattern"],
+        })
+    }
+
+    // Keep (or remove, when `keep` is false) the tracks whose name matches the regex
+    // Note: Matching is case sensitive, unless the pattern starts with `(?i)`
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let regex = name_regex(&args.pattern)?;
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| regex.is_match(&t.name) == args.keep)
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        name_regex(&args.pattern).map(|_| ())
+    }
+}
+
+/// Compile the `pattern` argument, reporting a syntax error as an invalid argument.
+fn name_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|err| ComponentError::invalid_argument("pattern", err.to_string()))
+}
+
+// --
+
+/// The track name keywords `filter:drop_covers` removes by default.
+const COVER_NAME_KEYWORDS: [&str; 5] = [
+    "karaoke",
+    "tribute",
+    "made famous by",
+    "cover",
+    "in the style of",
+];
+
+/// The artist name keywords `filter:drop_covers` removes by default.
+const COVER_ARTIST_KEYWORDS: [&str; 3] = ["karaoke", "tribute", "cover"];
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DropCoversArgs {
+    #[serde(default = "default_cover_name_keywords")]
+    pub name_keywords: Vec<String>,
+    #[serde(default = "default_cover_artist_keywords")]
+    pub artist_keywords: Vec<String>,
+}
+
+fn default_cover_name_keywords() -> Vec<String> {
+    COVER_NAME_KEYWORDS.map(str::to_owned).to_vec()
+}
+
+fn default_cover_artist_keywords() -> Vec<String> {
+    COVER_ARTIST_KEYWORDS.map(str::to_owned).to_vec()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DropCovers;
+
+impl Executable for DropCovers {
+    type Args = DropCoversArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name_keywords": {"type": "array", "items": {"type": "string"}, "default": COVER_NAME_KEYWORDS},
+                "artist_keywords": {"type": "array", "items": {"type": "string"}, "default": COVER_ARTIST_KEYWORDS},
+            },
+            "required": [],
+        })
+    }
+
+    // Remove karaoke, cover and tribute tracks - those with a keyword in the track or artist name
+    // Note: Keywords match whole words, case insensitively, so "cover" doesn't match "Discover"
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let lowercase = |keywords: Vec<String>| -> Vec<String> {
+            keywords.iter().map(|k| k.to_lowercase()).collect()
+        };
+        let (names, artists) = (
+            lowercase(args.name_keywords),
+            lowercase(args.artist_keywords),
+        );
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                let name = t.name.to_lowercase();
+                let cover_name = names.iter().any(|k| contains_word(&name, k));
+                let cover_artist = t.artists.iter().any(|a| {
+                    let artist = a.name.to_lowercase();
+                    artists.iter().any(|k| contains_word(&artist, k))
+                });
+                !cover_name && !cover_artist
+            })
+            .collect())
+    }
+}
+
+/// Return whether `keyword` appears in `text` on word boundaries.
+fn contains_word(text: &str, keyword: &str) -> bool {
+    if keyword.is_empty() {
+        return false;
+    }
+    text.match_indices(keyword).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + keyword.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+// --
+
+/// The track name markers `filter:drop_speed_edits` removes by default.
+const SPEED_EDIT_MARKERS: [&str; 5] = ["sped up", "sped-up", "speed up", "slowed", "nightcore"];
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DropSpeedEditsArgs {
+    #[serde(default = "default_speed_edit_markers")]
+    pub markers: Vec<String>,
+}
+
+fn default_speed_edit_markers() -> Vec<String> {
+    SPEED_EDIT_MARKERS.map(str::to_owned).to_vec()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DropSpeedEdits;
+
+impl Executable for DropSpeedEdits {
+    type Args = DropSpeedEditsArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "markers": {"type": "array", "items": {"type": "string"}, "default": SPEED_EDIT_MARKERS},
+            },
+            "required": [],
+        })
+    }
+
+    // Remove "sped up", "slowed + reverb" and similar edits - those with a marker in the track name
+    // Note: Markers match whole words, case insensitively, see `filter:drop_covers`
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let markers: Vec<String> = args.markers.iter().map(|m| m.to_lowercase()).collect();
+
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                let name = t.name.to_lowercase();
+                !markers.iter().any(|m| contains_word(&name, m))
+            })
+            .collect())
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RequireArtistVarietyArgs {
+    pub min_artists: u32,
+    /// Return an empty list, rather than failing the flow, when there are too few artists.
+    #[serde(default)]
+    pub empty_on_fail: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RequireArtistVariety;
+
+impl Executable for RequireArtistVariety {
+    type Args = RequireArtistVarietyArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "min_artists": {"type": "integer", "minimum": 0},
+                "empty_on_fail": {"type": "boolean", "default": false},
+            },
+            "required": ["min_artists"],
+        })
+    }
+
+    // Pass the tracks through unchanged if they have at least `min_artists` distinct primary
+    // artists, otherwise fail (or return nothing) - a quality gate for sources that are too narrow
+    // Note: Artists are compared by id, tracks without a primary artist id aren't counted
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let artists: HashSet<&ArtistId> = tracks
+            .iter()
+            .filter_map(|t| t.artists.first().and_then(|a| a.id.as_ref()))
+            .collect();
+
+        if artists.len() >= args.min_artists as usize {
+            return Ok(tracks);
+        }
+        if args.empty_on_fail {
+            return Ok(TrackList::new());
+        }
+        Err(ComponentError::invalid_argument(
+            "min_artists",
+            format!(
+                "expected at least {} distinct artists, found {}",
+                args.min_artists,
+                artists.len()
+            ),
+        ))
+    }
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PadToArgs {
+    pub min_count: u32,
+    #[serde(default)]
+    pub allow_repeats: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PadTo;
+
+impl Executable for PadTo {
+    type Args = PadToArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "min_count": {"type": "integer", "minimum": 0},
+                "allow_repeats": {"type": "boolean", "default": false},
+            },
+            "required": ["min_count"],
+        })
+    }
+
+    // Repeat the tracks, in order, until there are at least X of them
+    // Note: Without `allow_repeats` (or with no tracks at all) the list is returned unchanged
+    fn execute(_: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = prev.into_iter().next().unwrap_or_default();
+        let min_count = args.min_count as usize;
+        if !args.allow_repeats || tracks.is_empty() || tracks.len() >= min_count {
+            return Ok(tracks);
+        }
+
+        Ok(tracks.iter().cycle().take(min_count).cloned().collect())
+    }
+}
+
+// --
+
+/// Spotify accepts at most 100 ids per audio features request.
+const AUDIO_FEATURES_CHUNK_SIZE: usize = 100;
+
+/// The most audio features kept in memory across flow runs.
+const AUDIO_FEATURES_CACHE_CAPACITY: usize = 50_000;
+
+/// The tempo (BPM) mapped to 1.0 in the feature vector, faster tracks are clamped.
+const FEATURE_MAX_TEMPO: f32 = 250.0;
+
+/// Fetch the audio features for the given tracks, keyed by track id.
+/// Note: Tracks without an id, or without audio features, are missing from the result
+fn audio_features(client: &Client, tracks: &TrackList) -> Result<HashMap<String, AudioFeatures>> {
+    let api = &client.config.api_base_url;
+    let mut features = HashMap::new();
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    {
+        let cache = audio_features_cache().lock().unwrap();
+        for id in tracks
+            .iter()
+            .filter_map(|t| t.id.as_ref().map(|id| id.id()))
+        {
+            match cache.get(&(api.clone(), id.to_owned())) {
+                Some(f) => {
+                    features.insert(id.to_owned(), f.clone());
+                }
+                // n.b. Each id is only requested once, even if the track is repeated
+                None if seen.insert(id) => missing.push(id),
+                None => {}
+            }
+        }
+    }
+
+    for chunk in missing.chunks(AUDIO_FEATURES_CHUNK_SIZE) {
+        // n.b. We can't use `tracks_features` here - Spotify returns null for tracks without
+        // features, which fails to deserialize.
+        #[derive(Deserialize)]
+        struct Payload {
+            audio_features: Vec<Option<AudioFeatures>>,
+        }
+
+        crate::metrics::global().record_spotify_call("audio_features");
+        let res = client.api_get(
+            &format!("audio-features/?ids={}", chunk.join(",")),
+            &Query::new(),
+        )?;
+        let payload: Payload = serde_json::from_str(&res).map_err(ClientError::from)?;
+
+        let mut cache = audio_features_cache().lock().unwrap();
+        if cache.len() >= AUDIO_FEATURES_CACHE_CAPACITY {
+            cache.clear();
+        }
+        for f in payload.audio_features.into_iter().flatten() {
+            let id = f.id.id().to_owned();
+            cache.insert((api.clone(), id.clone()), f.clone());
+            features.insert(id, f);
+        }
+    }
+    Ok(features)
+}
+
+/// Return the process-wide audio features cache, keyed by API base url and track id.
+/// n.b. A track's audio features never change, so entries don't expire - the cache is just
+/// cleared once it reaches AUDIO_FEATURES_CACHE_CAPACITY.
+fn audio_features_cache() -> &'static Mutex<HashMap<(String, String), AudioFeatures>> {
+    static CACHE: OnceLock<Mutex<HashMap<(String, String), AudioFeatures>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Map the audio features into a vector where each dimension is normalized to [0, 1], so
+/// every feature carries the same weight when comparing tracks.
+fn feature_vector(f: &AudioFeatures) -> [f32; 8] {
+    [
+        f.acousticness,
+        f.danceability,
+        f.energy,
+        f.instrumentalness,
+        f.liveness,
+        f.speechiness,
+        f.valence,
+        (f.tempo / FEATURE_MAX_TEMPO).clamp(0.0, 1.0),
+    ]
+}
+
+/// Euclidean distance between two feature vectors.
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{FlowLimits, UserDefinedFlow};
+    use crate::error::PublicError;
+    use crate::testing::{album, artist, features, ids, spotify::MockSpotify, track};
+    use chrono::{TimeZone, Utc};
+    use rspotify::model::{Restriction, RestrictionReason, TrackLink};
+
+    #[test]
+    fn cap_total_keeps_the_first_tracks() {
+        let args = CapTotalArgs { max: 2 };
+        let tracks = vec![track("a"), track("b"), track("c")];
+        let res = CapTotal::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["a", "b"]);
+    }
+
+    #[test]
+    fn cap_total_keeps_lists_under_the_cap() {
+        let args = CapTotalArgs { max: 5 };
+        let tracks = vec![track("a"), track("b")];
+        let res = CapTotal::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["a", "b"]);
+    }
+
+    #[test]
+    fn has_preview_keeps_tracks_with_preview_url() {
+        let mut a = track("a");
+        a.preview_url = Some("https://p.scdn.co/mp3-preview/a".to_owned());
+        let b = track("b");
+        let mut c = track("c");
+        c.preview_url = Some("https://p.scdn.co/mp3-preview/c".to_owned());
+
+        let res = HasPreview::execute(&Client::default(), HasPreviewArgs {}, vec![vec![a, b, c]])
+            .unwrap();
+
+        assert_eq!(ids(&res), ["a", "c"]);
+    }
+
+    #[test]
+    fn block_artists_removes_featured_artist_collaborations() {
+        let solo = track("solo");
+        let mut collab = track("collab");
+        collab.artists = vec![artist("primary"), artist("blocked")];
+        let mut other = track("other");
+        other.artists = vec![artist("someone")];
+
+        let args = BlockArtistsArgs {
+            artist_ids: vec!["spotify:artist:blocked".to_owned(), "artist".to_owned()],
+        };
+        let res = BlockArtists::execute(&Client::default(), args, vec![vec![solo, collab, other]])
+            .unwrap();
+
+        assert_eq!(ids(&res), ["other"]);
+    }
+
+    #[test]
+    fn only_albums_keeps_tracks_from_allowed_albums() {
+        let on = |id: &str, album_id: &str| {
+            let mut t = track(id);
+            t.album = album(album_id);
+            t
+        };
+        let tracks = vec![on("a1", "a"), on("b1", "b"), on("c1", "c"), on("a2", "a")];
+
+        let args = OnlyAlbumsArgs {
+            album_ids: vec!["spotify:album:a".to_owned(), "c".to_owned()],
+        };
+        let res = OnlyAlbums::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["a1", "c1", "a2"]);
+    }
+
+    #[test]
+    fn max_albums_keeps_tracks_from_seen_albums() {
+        let on = |id: &str, album_id: &str| {
+            let mut t = track(id);
+            t.album = album(album_id);
+            t
+        };
+        let tracks = vec![
+            on("a1", "a"),
+            on("b1", "b"),
+            on("c1", "c"),
+            on("a2", "a"),
+            on("d1", "d"),
+            on("b2", "b"),
+        ];
+
+        let res =
+            MaxAlbums::execute(&Client::default(), MaxAlbumsArgs { max: 2 }, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["a1", "b1", "a2", "b2"]);
+    }
+
+    #[test]
+    fn prefer_older_orders_by_saved_date() {
+        let saved = |id: &str, day: u32| {
+            let mut t = track(id);
+            t.added_at = Some(Utc.with_ymd_and_hms(2023, 1, day, 0, 0, 0).unwrap());
+            t
+        };
+        let tracks = vec![
+            saved("new", 20),
+            track("unsaved"),
+            saved("old", 1),
+            saved("mid", 10),
+        ];
+
+        let res =
+            PreferOlder::execute(&Client::default(), PreferOlderArgs {}, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["old", "mid", "new", "unsaved"]);
+    }
+
+    #[test]
+    fn can_compute_feature_distance() {
+        let seed = features("seed");
+        let mut other = features("other");
+        other.energy += 0.3;
+        other.valence -= 0.4;
+        other.tempo += 25.0; // 0.1 once normalized
+
+        let d = distance(&feature_vector(&seed), &feature_vector(&other));
+
+        assert!((d - 0.26_f32.sqrt()).abs() < 1e-6, "{}", d);
+        assert_eq!(
+            distance(&feature_vector(&seed), &feature_vector(&seed)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn similar_to_keeps_tracks_within_radius() {
+        let mut far = features("far");
+        far.energy = 1.0;
+        far.danceability = 0.0;
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/audio-features/seed", features("seed"))
+            .on(
+                "GET",
+                "/v1/audio-features/",
+                serde_json::json!({ "audio_features": [features("near"), null, far] }),
+            );
+
+        let args = SimilarToArgs {
+            seed_track_id: "spotify:track:seed".to_owned(),
+            radius: 0.25,
+        };
+        let tracks = vec![track("near"), track("missing"), track("far")];
+        let res = SimilarTo::execute(&spotify.client(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["near"]);
+        assert_eq!(
+            spotify.requests("GET", "/v1/audio-features/")[0].param("ids"),
+            Some("near,missing,far")
+        );
+    }
+
+    #[test]
+    fn playable_drops_unplayable_and_restricted_tracks() {
+        let unknown = track("unknown");
+        let mut playable = track("playable");
+        playable.is_playable = Some(true);
+        playable.preview_url = Some("https://p.scdn.co/mp3-preview/playable".to_owned());
+        let mut unplayable = track("unplayable");
+        unplayable.is_playable = Some(false);
+        let mut restricted = track("restricted");
+        restricted.restrictions = Some(Restriction {
+            reason: RestrictionReason::Market,
+        });
+        let tracks = vec![unknown, playable, unplayable, restricted];
+
+        let args = PlayableArgs {
+            require_preview: false,
+        };
+        let res = Playable::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+        assert_eq!(ids(&res), ["unknown", "playable"]);
+
+        let args = PlayableArgs {
+            require_preview: true,
+        };
+        let res = Playable::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["playable"]);
+    }
+
+    #[test]
+    fn local_drops_or_keeps_local_files() {
+        let mut local = track("local");
+        local.is_local = true;
+        let tracks = vec![track("a"), local, track("b")];
+
+        // Defaults to excluding local files
+        let args: LocalArgs = serde_json::from_str("{}").unwrap();
+        let res = Local::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+        assert_eq!(ids(&res), ["a", "b"]);
+
+        let args = LocalArgs {
+            exclude_local: false,
+        };
+        let res = Local::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["local"]);
+    }
+
+    fn mood_curve(shape: &str) -> Vec<String> {
+        let valences = [("a", 0.4), ("b", 0.1), ("c", 0.9), ("d", 0.6), ("e", 0.2)];
+        let features: Vec<_> = valences
+            .iter()
+            .map(|(id, valence)| {
+                let mut f = features(id);
+                f.valence = *valence;
+                Some(f)
+            })
+            .chain([None])
+            .collect();
+
+        let spotify = MockSpotify::start();
+        spotify.on(
+            "GET",
+            "/v1/audio-features/",
+            serde_json::json!({ "audio_features": features }),
+        );
+
+        let mut tracks: TrackList = valences.iter().map(|(id, _)| track(id)).collect();
+        tracks.push(track("missing"));
+
+        let args = MoodCurveArgs {
+            shape: shape.to_owned(),
+        };
+        ids(&MoodCurve::execute(&spotify.client(), args, vec![tracks]).unwrap())
+    }
+
+    #[test]
+    fn mood_curve_orders_by_shape() {
+        assert_eq!(mood_curve("rising"), ["b", "e", "a", "d", "c", "missing"]);
+        assert_eq!(mood_curve("falling"), ["c", "d", "a", "e", "b", "missing"]);
+        assert_eq!(mood_curve("peak"), ["b", "a", "c", "d", "e", "missing"]);
+    }
+
+    #[test]
+    fn mood_curve_rejects_unknown_shape() {
+        let args = MoodCurveArgs {
+            shape: "zigzag".to_owned(),
+        };
+        let err = MoodCurve::execute(&Client::default(), args, vec![]).unwrap_err();
+
+        assert!(
+            matches!(err, ComponentError::InvalidArgument { name: "shape", .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn mood_curve_shape_is_checked_before_running() {
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: filter:mood_curve
+        parameters:
+            shape: sideways
+edges: []
+"#,
+        )
+        .unwrap();
+
+        let Err(PublicError::Invalid { errors }) = flow.check(&FlowLimits::default()) else {
+            panic!("expected the flow to be invalid");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field.as_deref(), Some("parameters.shape"));
+    }
+
+    /// Build `n` tracks by the given (primary) artist.
+    fn tracks_by(artist_id: &str, n: usize) -> TrackList {
+        (0..n)
+            .map(|i| {
+                let mut t = track(&format!("{artist_id}{i}"));
+                t.artists = vec![artist(artist_id)];
+                t
+            })
+            .collect()
+    }
+
+    fn count_by_artist(tracks: &TrackList) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for t in tracks {
+            *counts.entry(t.artists[0].name.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn stratified_sample_balances_artists() {
+        let tracks = [tracks_by("a", 10), tracks_by("b", 10), tracks_by("c", 10)].concat();
+
+        for seed in 0..10 {
+            let args = StratifiedSampleArgs {
+                total: 8,
+                seed: Some(seed),
+            };
+            let res =
+                StratifiedSample::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+
+            // 8 / 3 = 2, with the remainder of 2 given to two different artists
+            let mut counts: Vec<usize> = count_by_artist(&res).into_values().collect();
+            counts.sort();
+            assert_eq!(counts, [2, 3, 3]);
+        }
+    }
+
+    #[test]
+    fn stratified_sample_gives_unused_places_to_other_artists() {
+        let tracks = [tracks_by("a", 10), tracks_by("b", 1), tracks_by("c", 10)].concat();
+        let args = StratifiedSampleArgs {
+            total: 9,
+            seed: Some(7),
+        };
+        let res = StratifiedSample::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        let counts = count_by_artist(&res);
+        assert_eq!(res.len(), 9);
+        assert_eq!(counts["Artist b"], 1);
+        assert!((3..=5).contains(&counts["Artist a"]));
+        assert!((3..=5).contains(&counts["Artist c"]));
+    }
+
+    #[test]
+    fn stratified_sample_is_repeatable_with_seed() {
+        let tracks = [tracks_by("a", 10), tracks_by("b", 10)].concat();
+        let run = || {
+            let args = StratifiedSampleArgs {
+                total: 5,
+                seed: Some(42),
+            };
+            StratifiedSample::execute(&Client::default(), args, vec![tracks.clone()]).unwrap()
+        };
+
+        let res = run();
+        assert_eq!(ids(&res), ids(&run()));
+
+        // The sampled tracks keep their original order
+        let mut sorted = ids(&res);
+        sorted.sort();
+        assert_eq!(ids(&res), sorted);
+    }
+
+    #[test]
+    fn min_track_number_skips_the_start_of_each_album() {
+        let numbered = |id: &str, album_id: &str, track_number: u32| {
+            let mut t = track(id);
+            t.album = album(album_id);
+            t.track_number = track_number;
+            t
+        };
+
+        let tracks = vec![
+            numbered("a1", "a", 1),
+            numbered("a2", "a", 2),
+            numbered("a3", "a", 3),
+            numbered("b1", "b", 1),
+            numbered("b2", "b", 2),
+        ];
+        let args = MinTrackNumberArgs { min: 2 };
+        let res = MinTrackNumber::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["a2", "a3", "b2"]);
+    }
+
+    #[test]
+    fn anti_clump_spaces_out_artists_and_albums() {
+        let by = |id: &str, artist_id: &str, album_id: &str| {
+            let mut t = track(id);
+            t.artists = vec![artist(artist_id)];
+            t.album = album(album_id);
+            t
+        };
+
+        // x2 shares x1's artist, y2 shares y1's album
+        let tracks = vec![
+            by("x1", "x", "x"),
+            by("x2", "x", "x2"),
+            by("y1", "y", "y"),
+            by("y2", "y2", "y"),
+            by("z1", "z", "z"),
+            by("w1", "w", "w"),
+        ];
+        let args = AntiClumpArgs { min_gap: 2 };
+        let res = AntiClump::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        assert_eq!(ids(&res), ["x1", "y1", "z1", "x2", "y2", "w1"]);
+        for (i, t) in res.iter().enumerate() {
+            for prev in &res[i.saturating_sub(2)..i] {
+                assert!(!clumps(prev, t), "{:?} clumps", ids(&res[i - 1..=i]));
+            }
+        }
+    }
+
+    #[test]
+    fn anti_clump_places_unavoidable_clumps_in_order() {
+        let tracks = vec![track("a"), track("b"), track("c")];
+        let args = AntiClumpArgs { min_gap: 3 };
+        let res = AntiClump::execute(&Client::default(), args, vec![tracks]).unwrap();
+
+        // Every fixture track shares an artist and album
+        assert_eq!(ids(&res), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn balance_explicit_drops_the_latest_explicit_tracks() {
+        let rated = |id: &str, explicit: bool| {
+            let mut t = track(id);
+            t.explicit = explicit;
+            t
+        };
+
+        let tracks = vec![
+            rated("e1", true),
+            rated("c1", false),
+            rated("e2", true),
+            rated("e3", true),
+            rated("c2", false),
+            rated("e4", true),
+        ];
+        let run = |max_explicit_ratio| {
+            let args = BalanceExplicitArgs { max_explicit_ratio };
+            ids(&BalanceExplicit::execute(&Client::default(), args, vec![tracks.clone()]).unwrap())
+        };
+
+        // 2 explicit of 4 is the most allowed at 0.5
+        assert_eq!(run(0.5), ["e1", "c1", "e2", "c2"]);
+        assert_eq!(run(0.0), ["c1", "c2"]);
+        // Under the cap, nothing is dropped
+        assert_eq!(run(0.7), ["e1", "c1", "e2", "e3", "c2", "e4"]);
+    }
+
+    #[test]
+    fn balance_explicit_rejects_ratios_outside_0_to_1() {
+        let mut args = BalanceExplicitArgs {
+            max_explicit_ratio: 1.5,
+        };
+        assert!(BalanceExplicit::normalize(&mut args).is_err());
+    }
+
+    #[test]
+    fn dedup_track_can_compare_across_inputs() {
+        let first = vec![track("a"), track("b"), track("a")];
+        let second = vec![track("b"), track("c")];
+        let run = |across_inputs| {
+            let args = DeduplicateTrackArgs { across_inputs };
+            let prev = vec![first.clone(), second.clone()];
+            ids(&DeduplicateTrack::execute(&Client::default(), args, prev).unwrap())
+        };
+
+        assert_eq!(run(false), ["a", "b"]);
+        assert_eq!(run(true), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn canonicalize_lets_dedup_collapse_relinked_tracks() {
+        let mut relinked = track("b");
+        relinked.linked_from = Some(TrackLink {
+            external_urls: HashMap::new(),
+            href: "https://api.spotify.com/v1/tracks/a".to_owned(),
+            id: TrackId::from_id("a").unwrap(),
+        });
+        let tracks = vec![track("a"), relinked, track("c")];
+
+        let res =
+            Canonicalize::execute(&Client::default(), CanonicalizeArgs {}, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["a", "a", "c"]);
+
+        let args = DeduplicateTrackArgs::default();
+        let res = DeduplicateTrack::execute(&Client::default(), args, vec![res]).unwrap();
+        assert_eq!(ids(&res), ["a", "c"]);
+    }
+
+    #[test]
+    fn pad_to_repeats_tracks_when_allowed() {
+        let tracks = vec![track("a"), track("b")];
+        let run = |min_count, allow_repeats| {
+            let args = PadToArgs {
+                min_count,
+                allow_repeats,
+            };
+            ids(&PadTo::execute(&Client::default(), args, vec![tracks.clone()]).unwrap())
+        };
+
+        assert_eq!(run(5, true), ["a", "b", "a", "b", "a"]);
+        assert_eq!(run(5, false), ["a", "b"]);
+        assert_eq!(run(1, true), ["a", "b"]);
+    }
+
+    #[test]
+    fn total_duration_stops_at_the_cap() {
+        let long = |id: &str, minutes: i64| {
+            let mut t = track(id);
+            t.duration = chrono::Duration::minutes(minutes);
+            t
+        };
+        let tracks = vec![long("a", 4), long("b", 3), long("c", 5), long("d", 1)];
+        let run = |max_minutes, allow_oversized_first| {
+            let args = TotalDurationArgs {
+                max_minutes,
+                allow_oversized_first,
+            };
+            ids(&TotalDuration::execute(&Client::default(), args, vec![tracks.clone()]).unwrap())
+        };
+
+        // Stops at the first track over the cap, rather than skipping it for a shorter one
+        assert_eq!(run(10, false), ["a", "b"]);
+        assert_eq!(run(12, false), ["a", "b", "c"]);
+        assert_eq!(run(60, false), ["a", "b", "c", "d"]);
+
+        assert_eq!(run(3, false), Vec::<String>::new());
+        assert_eq!(run(3, true), ["a"]);
+    }
+
+    #[test]
+    fn name_matches_can_keep_or_remove_matches() {
+        let named = |id: &str, name: &str| {
+            let mut t = track(id);
+            t.name = name.to_owned();
+            t
+        };
+        let tracks = vec![
+            named("a", "Song - Live"),
+            named("b", "Song"),
+            named("c", "Other (Live at Wembley)"),
+        ];
+        let run = |keep| {
+            let args = NameMatchesArgs {
+                pattern: r"(?i)\blive\b".to_owned(),
+                keep,
+            };
+            ids(&NameMatches::execute(&Client::default(), args, vec![tracks.clone()]).unwrap())
+        };
+
+        assert_eq!(run(true), ["a", "c"]);
+        assert_eq!(run(false), ["b"]);
+    }
+
+    #[test]
+    fn name_matches_rejects_invalid_pattern() {
+        let mut args = NameMatchesArgs {
+            pattern: "(unclosed".to_owned(),
+            keep: true,
+        };
+        let err = NameMatches::normalize(&mut args).unwrap_err();
+
+        assert!(
+            matches!(
+                err,
+                ComponentError::InvalidArgument {
+                    name: "pattern",
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert!(matches!(
+            PublicError::from(err),
+            PublicError::BadRequest { .. }
+        ));
+    }
+
+    #[test]
+    fn drop_covers_removes_obvious_covers() {
+        let named = |id: &str, name: &str, artist_name: &str| {
+            let mut t = track(id);
+            t.name = name.to_owned();
+            t.artists[0].name = artist_name.to_owned();
+            t
+        };
+        let tracks = vec![
+            named("original", "Cover Me Softly", "The Band"),
+            named("karaoke", "Song (Karaoke Version)", "The Band"),
+            named("famous", "Song (Made Famous by The Band)", "Studio Singers"),
+            named("tribute", "Song", "Tribute To The Band"),
+            named("discover", "Discover", "The Band"),
+        ];
+        let args: DropCoversArgs = serde_json::from_value(json!({})).unwrap();
+        let res = DropCovers::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+
+        // "Cover Me Softly" is dropped too - the defaults trade some false positives for coverage
+        assert_eq!(ids(&res), ["discover"]);
+
+        let args = DropCoversArgs {
+            name_keywords: vec!["KARAOKE".to_owned()],
+            artist_keywords: vec![],
+        };
+        let res = DropCovers::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["original", "famous", "tribute", "discover"]);
+    }
+
+    #[test]
+    fn drop_speed_edits_keeps_originals() {
+        let named = |id: &str, name: &str| {
+            let mut t = track(id);
+            t.name = name.to_owned();
+            t
+        };
+        let tracks = vec![
+            named("original", "Song"),
+            named("sped", "Song (Sped Up)"),
+            named("slowed", "Song - SLOWED + REVERB"),
+            named("nightcore", "Song (Nightcore Remix)"),
+            named("speedway", "Speedway Upbeat"),
+        ];
+        let args: DropSpeedEditsArgs = serde_json::from_value(json!({})).unwrap();
+        let res = DropSpeedEdits::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+        assert_eq!(ids(&res), ["original", "speedway"]);
+
+        let args = DropSpeedEditsArgs {
+            markers: vec!["Nightcore".to_owned()],
+        };
+        let res = DropSpeedEdits::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["original", "sped", "slowed", "speedway"]);
+    }
+
+    #[test]
+    fn sort_by_feature_orders_by_energy() {
+        let energies = [("a", 0.5), ("b", 0.9), ("c", 0.1)];
+        let features: Vec<_> = energies
+            .iter()
+            .map(|(id, energy)| {
+                let mut f = features(id);
+                f.energy = *energy;
+                Some(f)
+            })
+            .chain([None])
+            .collect();
+
+        let spotify = MockSpotify::start();
+        spotify.on(
+            "GET",
+            "/v1/audio-features/",
+            serde_json::json!({ "audio_features": features }),
+        );
+
+        let mut tracks: TrackList = energies.iter().map(|(id, _)| track(id)).collect();
+        tracks.push(track("missing"));
+
+        let sort = |order: &str| {
+            let args = SortByFeatureArgs {
+                feature: "energy".to_owned(),
+                order: order.to_owned(),
+            };
+            ids(&SortByFeature::execute(&spotify.client(), args, vec![tracks.clone()]).unwrap())
+        };
+        assert_eq!(sort("ascending"), ["c", "a", "b", "missing"]);
+        assert_eq!(sort("descending"), ["b", "a", "c", "missing"]);
+
+        // The second run only asks for the track without features - the rest are cached
+        let requests = spotify.requests("GET", "/v1/audio-features/");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].param("ids"), Some("missing"));
+    }
+
+    #[test]
+    fn sort_by_feature_rejects_unknown_feature() {
+        let mut args = SortByFeatureArgs {
+            feature: "loudest".to_owned(),
+            order: "ascending".to_owned(),
+        };
+        let err = SortByFeature::normalize(&mut args).unwrap_err();
+        assert!(err.to_string().contains("Invalid feature"), "{}", err);
+    }
+
+    #[test]
+    fn top_by_feature_keeps_highest_scoring_tracks() {
+        let danceability = [("a", 0.5), ("b", 0.9), ("c", 0.1), ("d", 0.7)];
+        let features: Vec<_> = danceability
+            .iter()
+            .map(|(id, value)| {
+                let mut f = features(id);
+                f.danceability = *value;
+                Some(f)
+            })
+            .chain([None])
+            .collect();
+
+        let spotify = MockSpotify::start();
+        spotify.on(
+            "GET",
+            "/v1/audio-features/",
+            serde_json::json!({ "audio_features": features }),
+        );
+
+        let mut tracks: TrackList = danceability.iter().map(|(id, _)| track(id)).collect();
+        tracks.push(track("missing"));
+
+        let top = |count: u32, descending: bool| {
+            let args = TopByFeatureArgs {
+                feature: "danceability".to_owned(),
+                count,
+                descending,
+            };
+            ids(&TopByFeature::execute(&spotify.client(), args, vec![tracks.clone()]).unwrap())
+        };
+        assert_eq!(top(2, true), ["b", "d"]);
+        assert_eq!(top(2, false), ["c", "a"]);
+        // Tracks without features are dropped, even when there's room for them
+        assert_eq!(top(10, true), ["b", "d", "a", "c"]);
+
+        let mut args = TopByFeatureArgs {
+            feature: "groove".to_owned(),
+            count: 2,
+            descending: true,
+        };
+        assert!(TopByFeature::normalize(&mut args).is_err());
+    }
+
+    #[test]
+    fn weighted_sample_prefers_popular_tracks() {
+        let tracks: TrackList = [("hot", 100), ("warm", 50), ("cold", 0)]
+            .iter()
+            .map(|(id, popularity)| {
+                let mut t = track(id);
+                t.popularity = *popularity;
+                t
+            })
+            .collect();
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for seed in 0..200 {
+            let args = WeightedSampleArgs {
+                count: 1,
+                seed: Some(seed),
+            };
+            let res =
+                WeightedSample::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+            assert_eq!(res.len(), 1);
+            *counts.entry(ids(&res).remove(0)).or_default() += 1;
+        }
+
+        let count = |id: &str| counts.get(id).copied().unwrap_or_default();
+        assert!(count("hot") > count("warm"), "{:?}", counts);
+        assert!(count("warm") > count("cold"), "{:?}", counts);
+
+        // Asking for more tracks than there are returns them all, in order
+        let args = WeightedSampleArgs {
+            count: 5,
+            seed: Some(0),
+        };
+        let res = WeightedSample::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert_eq!(ids(&res), ["hot", "warm", "cold"]);
+    }
+
+    #[test]
+    fn require_artist_variety_passes_varied_tracks() {
+        let tracks = [tracks_by("a", 2), tracks_by("b", 1), tracks_by("c", 1)].concat();
+        let args = RequireArtistVarietyArgs {
+            min_artists: 3,
+            empty_on_fail: false,
+        };
+        let res =
+            RequireArtistVariety::execute(&Client::default(), args, vec![tracks.clone()]).unwrap();
+
+        assert_eq!(res, tracks);
+    }
+
+    #[test]
+    fn require_artist_variety_rejects_narrow_tracks() {
+        let tracks = [tracks_by("a", 5), tracks_by("b", 5)].concat();
+        let args = RequireArtistVarietyArgs {
+            min_artists: 3,
+            empty_on_fail: false,
+        };
+        let err = RequireArtistVariety::execute(&Client::default(), args, vec![tracks.clone()])
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ComponentError::InvalidArgument {
+                    name: "min_artists",
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+
+        let args = RequireArtistVarietyArgs {
+            min_artists: 3,
+            empty_on_fail: true,
+        };
+        let res = RequireArtistVariety::execute(&Client::default(), args, vec![tracks]).unwrap();
+        assert!(res.is_empty());
+    }
+}
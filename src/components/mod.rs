@@ -1,21 +1,170 @@
-/// TrackList is a collection of FullTracks. It is used as a return type for source components.
-pub type TrackList = Vec<rspotify::model::FullTrack>;
+/// TrackList is a collection of Tracks. It is used as a return type for source components.
+pub type TrackList = Vec<Track>;
 
 pub mod combiners;
 pub mod conditinals;
 pub mod filters;
+pub mod outputs;
 pub mod sources;
+pub mod util;
 
-use rspotify::AuthCodeSpotify as Client;
+use chrono::{DateTime, Utc};
+use derive_more::{Display, Error};
+use rand::{rngs::StdRng, SeedableRng};
+use rspotify::{
+    http::HttpError,
+    model::{FullTrack, IdError},
+    AuthCodeSpotify as Client, ClientError,
+};
 use serde::{Deserialize, Serialize};
+use std::{
+    ops::{Deref, DerefMut},
+    time::Instant,
+};
 
+use self::combiners::*;
 use self::filters::*;
+use self::outputs::*;
 use self::sources::*;
-use crate::error::Result;
+
+pub type Result<T> = std::result::Result<T, ComponentError>;
+
+/// Track wraps a FullTrack with the metadata Spotify only returns alongside it, e.g. when the
+/// track was saved. Sources fill in what they know, and it is carried through the flow as-is.
+///
+/// Track derefs to the inner FullTrack, so components can read the track fields directly.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Track {
+    #[serde(flatten)]
+    pub track: FullTrack,
+    /// When the track was added to the user's library (or playlist), if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<DateTime<Utc>>,
+}
+
+impl From<FullTrack> for Track {
+    fn from(track: FullTrack) -> Self {
+        Track {
+            track,
+            added_at: None,
+        }
+    }
+}
+
+impl Track {
+    /// Return a key identifying the song, regardless of which release the track is from.
+    ///
+    /// The key is the lowercased track name and primary artist name, ignoring anything in
+    /// brackets or after a " - ", e.g. "Song (Radio Edit)" and "Song - Remastered 2011" both
+    /// match "Song". Punctuation and repeated whitespace are also ignored.
+    pub fn fuzzy_key(&self) -> String {
+        let mut name = String::new();
+        let mut depth = 0;
+        for c in self.name.split(" - ").next().unwrap_or_default().chars() {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth = (depth - 1).max(0),
+                c if depth == 0 && (c.is_alphanumeric() || c.is_whitespace()) => {
+                    name.extend(c.to_lowercase())
+                }
+                _ => {}
+            }
+        }
+
+        let artist = self.artists.first().map(|a| a.name.to_lowercase());
+        format!(
+            "{}|{}",
+            name.split_whitespace().collect::<Vec<_>>().join(" "),
+            artist.unwrap_or_default()
+        )
+    }
+}
+
+impl Deref for Track {
+    type Target = FullTrack;
+
+    fn deref(&self) -> &Self::Target {
+        &self.track
+    }
+}
+
+impl DerefMut for Track {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.track
+    }
+}
+
+/// ComponentError describes why a component failed to execute.
+///
+/// These are mapped to a [`PublicError`](crate::error::PublicError) at the handler boundary, so
+/// user mistakes (e.g. a malformed artist id) can be told apart from Spotify being unavailable.
+#[derive(Debug, Display, Error)]
+pub enum ComponentError {
+    #[display(fmt = "Invalid {} id: {}", kind, id)]
+    InvalidId { kind: &'static str, id: String },
+    #[display(fmt = "Invalid {}: {}", name, message)]
+    InvalidArgument { name: &'static str, message: String },
+    #[display(fmt = "Spotify could not find the requested resource")]
+    NotFound,
+    #[display(fmt = "Not supported: {}", message)]
+    Unsupported { message: String },
+    #[display(fmt = "Spotify request failed: {}", _0)]
+    Upstream(ClientError),
+}
+
+impl ComponentError {
+    /// Build a ComponentError::InvalidId for the given kind of id, e.g. "artist".
+    pub fn invalid_id(kind: &'static str, id: impl Into<String>) -> Self {
+        ComponentError::InvalidId {
+            kind,
+            id: id.into(),
+        }
+    }
+}
+
+impl ComponentError {
+    /// Build a ComponentError::InvalidArgument for the named component argument.
+    pub fn invalid_argument(name: &'static str, message: impl Into<String>) -> Self {
+        ComponentError::InvalidArgument {
+            name,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ClientError> for ComponentError {
+    fn from(err: ClientError) -> Self {
+        // Spotify responds with a 404 when e.g. an artist with a valid id doesn't exist
+        if let ClientError::Http(http) = &err {
+            if let HttpError::StatusCode(res) = http.as_ref() {
+                if res.status() == 404 {
+                    return ComponentError::NotFound;
+                }
+            }
+        }
+
+        ComponentError::Upstream(err)
+    }
+}
+
+/// Build the random number generator used by components that sample or shuffle.
+///
+/// Components take an optional `seed` argument, so a flow can be made to give the same result on
+/// every run. Without one the generator is seeded from the OS.
+pub fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
 
 /// NonExhaustive is a helper enum to allow us to Deserialze unknown components.
 /// Required as a workaround due to `#[serde(other)]` not working with tuple variants.
 ///
+/// Unknown components are kept as-is and serialized back unchanged, so flows written for a newer
+/// build survive an import/export round trip. n.b. This relies on serde_json's `preserve_order`
+/// feature to keep the original key order.
+///
 /// Ref: <https://github.com/serde-rs/serde/issues/1701#issuecomment-584677088>
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(untagged)]
@@ -33,6 +182,32 @@ impl<T> NonExhaustive<T> {
     }
 }
 
+/// ComponentKind is the role a component plays in a flow, taken from its name prefix.
+/// E.g. `output:overwrite` is an Output.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentKind {
+    Source,
+    Filter,
+    Combiner,
+    Conditional,
+    Output,
+}
+
+impl ComponentKind {
+    /// Map a component name to its kind, returning None for an unrecognised prefix.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.split(':').next()? {
+            "source" => Some(ComponentKind::Source),
+            "filter" => Some(ComponentKind::Filter),
+            "combiner" => Some(ComponentKind::Combiner),
+            "conditional" => Some(ComponentKind::Conditional),
+            "output" => Some(ComponentKind::Output),
+            _ => None,
+        }
+    }
+}
+
 /// The Executable Trait should be implemented by all components.
 ///
 /// Each Executable component should take an arguments object, as well as a list of previous
@@ -40,7 +215,58 @@ impl<T> NonExhaustive<T> {
 pub trait Executable {
     type Args;
 
+    /// Return the JSON schema of the component `Args`, used to build forms and type definitions.
+    fn schema() -> serde_json::Value;
+
     fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList>;
+
+    /// Validate and canonicalize the arguments before the flow runs, e.g. see [`normalize_id`].
+    fn normalize(_: &mut Self::Args) -> Result<()> {
+        Ok(())
+    }
+
+    /// Return the Spotify OAuth scopes the user must have granted for the component to run.
+    fn required_scopes() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Return the seed argument of a randomized component, so a flow-level seed can fill it in.
+    fn seed(_: &mut Self::Args) -> Option<&mut Option<u64>> {
+        None
+    }
+}
+
+/// Parse an id argument with `parse`, replacing it with the canonical spotify URI.
+///
+/// Accepts both bare ids and URIs, returning ComponentError::InvalidArgument naming the argument
+/// if the id is malformed. E.g.
+///
+/// ```rust
+/// normalize_id("id", &mut args.id, |id| ArtistId::from_id_or_uri(id).map(|id| id.uri()))?;
+/// ```
+pub fn normalize_id(
+    name: &'static str,
+    value: &mut String,
+    parse: impl FnOnce(&str) -> std::result::Result<String, IdError>,
+) -> Result<()> {
+    *value = parse(value).map_err(|_| {
+        ComponentError::invalid_argument(name, format!("{} is not a valid id or URI", value))
+    })?;
+    Ok(())
+}
+
+/// A TrackSink accepts tracks one at a time, as they are produced.
+///
+/// This is the interface for streaming outputs, which write to Spotify incrementally rather than
+/// waiting for the whole [`TrackList`].
+/// n.b. The controller doesn't feed sinks yet - outputs still receive their whole input, and push
+/// it through the sink to write it in chunks
+pub trait TrackSink {
+    /// Accept the next track, the sink may write any buffered tracks before returning.
+    fn push(&mut self, track: Track) -> Result<()>;
+
+    /// Write any remaining buffered tracks, returning how many tracks were written.
+    fn finish(self) -> Result<usize>;
 }
 
 // --
@@ -83,11 +309,129 @@ macro_rules! components {
                 }
             }
 
+            /// Build the named component from its parameters, e.g. to construct a flow in code.
+            ///
+            /// Returns ComponentError::Unsupported for unknown component names, or
+            /// ComponentError::InvalidArgument if the parameters don't match the component `Args`.
+            pub fn from_parts(name: &str, parameters: serde_json::Value) -> Result<Self> {
+                let invalid = |err: serde_json::Error| {
+                    ComponentError::invalid_argument("parameters", err.to_string())
+                };
+                match name {
+                    $($a => serde_json::from_value(parameters).map(Component::$b).map_err(invalid),)*
+                    _ => Err(ComponentError::Unsupported {
+                        message: format!("unknown component {}", name),
+                    }),
+                }
+            }
+
+            /// Return the name of every registered component, in registration order.
+            pub fn names() -> &'static [&'static str] {
+                &[$($a),*]
+            }
+
+            /// Return the kind of the component.
+            pub fn kind(&self) -> ComponentKind {
+                // n.b. Every registered component name has a known prefix
+                ComponentKind::from_name(self.name()).unwrap()
+            }
+
+            /// Return the JSON schema of the named component's `Args`, titled after the type, e.g.
+            /// `TakeArgs`. Returns None for unknown component names.
+            pub fn args_schema(name: &str) -> Option<serde_json::Value> {
+                match name {
+                    $($a => {
+                        let mut parameters = <$b>::schema();
+                        parameters["title"] = concat!(stringify!($b), "Args").into();
+                        Some(parameters)
+                    },)*
+                    _ => None,
+                }
+            }
+
+            /// Return the fields of the named component's `Args`, as serde reads them. Returns
+            /// None for unknown component names.
+            #[cfg(test)]
+            pub fn args_fields(name: &str) -> Option<&'static [&'static str]> {
+                match name {
+                    $($a => Some(crate::testing::serde_fields::<<$b as Executable>::Args>()),)*
+                    _ => None,
+                }
+            }
+
+            /// Return the Spotify OAuth scopes the component needs, see
+            /// [`Executable::required_scopes`].
+            pub fn required_scopes(&self) -> &'static [&'static str] {
+                match self {
+                    $(Component::$b(_) => <$b>::required_scopes(),)*
+                }
+            }
+
+            /// Return the Spotify OAuth scopes the named component needs, see
+            /// [`Executable::required_scopes`]. Returns None for unknown component names.
+            pub fn scopes_of(name: &str) -> Option<&'static [&'static str]> {
+                match name {
+                    $($a => Some(<$b>::required_scopes()),)*
+                    _ => None,
+                }
+            }
+
+            /// Return the JSON schema of every component, as a `oneOf` over their tagged representations.
+            ///
+            /// Each `parameters` schema is the component's [`Component::args_schema`].
+            pub fn json_schema() -> serde_json::Value {
+                let variants: Vec<serde_json::Value> = vec![
+                    $({
+                        let parameters = Self::args_schema($a).unwrap();
+                        serde_json::json!({
+                            "type": "object",
+                            "properties": {
+                                "component": { "const": $a },
+                                "parameters": parameters,
+                            },
+                            "required": ["component", "parameters"],
+                        })
+                    },)*
+                ];
+
+                serde_json::json!({
+                    "$schema": "http://json-schema.org/draft-07/schema#",
+                    "title": "Component",
+                    "oneOf": variants,
+                })
+            }
+
+            /// Set the seed of a randomized component, unless it already has one, see
+            /// [`Executable::seed`]. Components without a seed are unchanged.
+            pub fn seed_with(&mut self, seed: u64) {
+                match self {
+                    $(Component::$b(args) => {
+                        if let Some(own) = <$b>::seed(args) {
+                            own.get_or_insert(seed);
+                        }
+                    },)*
+                }
+            }
+
+            /// Validate and canonicalize the component arguments, see [`Executable::normalize`].
+            pub fn normalize(&mut self) -> Result<()> {
+                match self {
+                    $(Component::$b(args) => <$b>::normalize(args),)*
+                }
+            }
+
             /// Execute the component with the given arguments and previous component results.
+            /// n.b. Every execution is recorded in the global metrics registry.
             pub fn execute(self, client: &Client, prev: Vec<TrackList>) -> Result<TrackList> {
-                match self {
+                let name = self.name();
+                let started = Instant::now();
+                let res = match self {
                     $(Component::$b(args) => <$b>::execute(client, args, prev),)*
-                }
+                };
+
+                let tracks = res.as_ref().map(Vec::len).ok();
+                crate::metrics::global().record_component(name, started.elapsed(), tracks);
+                res
             }
         }
     };
@@ -99,7 +443,162 @@ components![
     ("source:artist_top_tracks", ArtistTopTracks),
     ("source:album", Album),
     ("source:user_liked_tracks", UserLikedTracks),
+    ("source:liked_since_last_run", LikedSinceLastRun),
+    ("source:my_playlists_matching", MyPlaylistsMatching),
+    ("source:saved_episodes", SavedEpisodes),
 
     // Filters
-    ("filter:take", Take)
+    ("filter:take", Take),
+    ("filter:cap_total", CapTotal),
+    ("filter:total_duration", TotalDuration),
+    ("filter:has_preview", HasPreview),
+    ("filter:playable", Playable),
+    ("filter:local", Local),
+    ("filter:block_artists", BlockArtists),
+    ("filter:only_albums", OnlyAlbums),
+    ("filter:max_albums", MaxAlbums),
+    ("filter:prefer_older", PreferOlder),
+    ("filter:similar_to", SimilarTo),
+    ("filter:mood_curve", MoodCurve),
+    ("filter:sort_by_feature", SortByFeature),
+    ("filter:top_by_feature", TopByFeature),
+    ("filter:stratified_sample", StratifiedSample),
+    ("filter:weighted_sample", WeightedSample),
+    ("filter:min_track_number", MinTrackNumber),
+    ("filter:anti_clump", AntiClump),
+    ("filter:balance_explicit", BalanceExplicit),
+    ("filter:dedup_track", DeduplicateTrack),
+    ("filter:canonicalize", Canonicalize),
+    ("filter:pad_to", PadTo),
+    ("filter:name_matches", NameMatches),
+    ("filter:drop_covers", DropCovers),
+    ("filter:drop_speed_edits", DropSpeedEdits),
+    ("filter:require_artist_variety", RequireArtistVariety),
+
+    // Combiners
+    ("combiner:first_non_empty", FirstNonEmpty),
+    ("combiner:merge_by_popularity", MergeByPopularity),
+    ("combiner:concat_limited", ConcatLimited),
+    ("combiner:union_fuzzy", UnionFuzzy),
+    ("combiner:round_robin", RoundRobin),
+    ("combiner:zip_n", ZipN),
+    ("combiner:pattern", Pattern),
+    ("combiner:shuffle_union", ShuffleUnion),
+    ("combiner:dovetail", Dovetail),
+    ("combiner:union_weighted", UnionWeighted),
+
+    // Outputs
+    ("output:overwrite", Overwrite),
+    ("output:append", Append)
 ];
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(json: &str) -> String {
+        let component: NonExhaustive<Component> = serde_json::from_str(json).unwrap();
+        serde_json::to_string(&component).unwrap()
+    }
+
+    #[test]
+    fn unknown_components_round_trip_unchanged() {
+        let json = r#"{"component":"source:future_thing","parameters":{"zeta":1,"alpha":[true,null,2.5],"nested":{"b":"x","a":{}}}}"#;
+
+        let component: NonExhaustive<Component> = serde_json::from_str(json).unwrap();
+        assert!(matches!(component, NonExhaustive::Unknown(_)));
+
+        assert_eq!(round_trip(json), json);
+    }
+
+    #[test]
+    fn unknown_components_without_parameters_round_trip_unchanged() {
+        let json = r#"{"component":"combiner:zip"}"#;
+
+        assert_eq!(round_trip(json), json);
+    }
+
+    #[test]
+    fn schemas_match_args() {
+        // n.b. serde lists aliases among the fields, but they are left out of the schemas
+        const ALIASES: &[(&str, &str)] = &[("source:user_liked_tracks", "max")];
+
+        for name in Component::names() {
+            let schema = Component::args_schema(name).unwrap();
+            let mut properties: Vec<&str> = schema["properties"]
+                .as_object()
+                .map(|p| p.keys().map(String::as_str).collect())
+                .unwrap_or_default();
+            properties.sort();
+            let mut fields: Vec<&str> = Component::args_fields(name)
+                .unwrap()
+                .iter()
+                .copied()
+                .filter(|field| !ALIASES.contains(&(name, field)))
+                .collect();
+            fields.sort();
+            assert_eq!(properties, fields, "{}", name);
+
+            // Only the required properties may be left out
+            let required: Vec<&str> = schema["required"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|r| r.as_str().unwrap())
+                .collect();
+            let without_any = Component::from_parts(name, serde_json::json!({}));
+            assert_eq!(required.is_empty(), without_any.is_ok(), "{}", name);
+            for field in required {
+                assert!(fields.contains(&field), "{}: {}", name, field);
+            }
+        }
+    }
+
+    #[test]
+    fn can_build_component_from_parts() {
+        let params = serde_json::json!({ "limit": 2, "from": "start" });
+        let component = Component::from_parts("filter:take", params).unwrap();
+        assert_eq!(component.name(), "filter:take");
+
+        let tracks = ["a", "b", "c"].map(crate::testing::track).to_vec();
+        let res = component.execute(&Client::default(), vec![tracks]).unwrap();
+        assert_eq!(crate::testing::ids(&res), ["a", "b"]);
+
+        let err = Component::from_parts("filter:nope", serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, ComponentError::Unsupported { .. }), "{}", err);
+
+        let err = Component::from_parts("filter:take", serde_json::json!({})).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ComponentError::InvalidArgument {
+                    name: "parameters",
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn every_component_has_a_kind() {
+        let json = r#"{"component":"output:overwrite","parameters":{"by_name":"test playlist"}}"#;
+        let component: Component = serde_json::from_str(json).unwrap();
+
+        assert_eq!(component.kind(), ComponentKind::Output);
+        assert_eq!(ComponentKind::from_name("unknown:thing"), None);
+    }
+
+    #[test]
+    fn known_components_round_trip_unchanged() {
+        let json = r#"{"component":"output:overwrite","parameters":{"by_name":"test playlist"}}"#;
+
+        let component: NonExhaustive<Component> = serde_json::from_str(json).unwrap();
+        assert!(matches!(component, NonExhaustive::Known(_)));
+
+        assert_eq!(round_trip(json), json);
+    }
+}
@@ -0,0 +1,693 @@
+//! Outputs take one TrackList and write it to the user's Spotify account, returning the tracks written
+use chrono::{DateTime, Utc};
+use rspotify::model::*;
+use rspotify::prelude::*;
+use rspotify::AuthCodeSpotify as Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::Result;
+use super::*;
+
+/// Spotify accepts at most 100 items per add/replace request.
+const PLAYLIST_WRITE_CHUNK_SIZE: usize = 100;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OverwriteArgs {
+    pub by_name: String,
+    /// Optional playlist description, see [`render_description`] for the supported placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Create the playlist as public, rather than private, if it doesn't exist yet.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Overwrite;
+
+impl Executable for Overwrite {
+    type Args = OverwriteArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "by_name": {"type": "string"},
+                "description": {"type": "string"},
+                "public": {"type": "boolean", "default": false},
+            },
+            "required": ["by_name"],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &[
+            "playlist-read-private",
+            "playlist-modify-private",
+            "playlist-modify-public",
+        ]
+    }
+
+    // Replace the contents of the users playlist with the given name
+    // Note: The playlist is created if it doesn't exist yet
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let tracks = writable(prev.into_iter().next().unwrap_or_default());
+        let (playlist_id, _) = find_or_create_playlist(client, &args.by_name, args.public)?;
+
+        let ids: Vec<&TrackId> = tracks.iter().filter_map(|t| t.id.as_ref()).collect();
+
+        // Replace the playlist with the first chunk of tracks, then append the rest.
+        // n.b. Replacing with an empty chunk clears the playlist
+        let mut chunks = ids.chunks(PLAYLIST_WRITE_CHUNK_SIZE);
+        let first = chunks.next().unwrap_or_default();
+        crate::metrics::global().record_spotify_call("playlist_replace_items");
+        client.playlist_replace_items(playlist_id.as_ref(), playable(first))?;
+        for chunk in chunks {
+            crate::metrics::global().record_spotify_call("playlist_add_items");
+            client.playlist_add_items(playlist_id.as_ref(), playable(chunk), None)?;
+        }
+
+        if let Some(template) = &args.description {
+            let description = render_description(template, Utc::now(), ids.len());
+            crate::metrics::global().record_spotify_call("playlist_change_detail");
+            client.playlist_change_detail(
+                playlist_id.as_ref(),
+                None,
+                None,
+                Some(&description),
+                None,
+            )?;
+        }
+
+        Ok(tracks)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AppendArgs {
+    pub by_name: String,
+    /// Insert the tracks at this index, e.g. 0 for the top, rather than at the end.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<u32>,
+    /// Create the playlist as public, rather than private, if it doesn't exist yet.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Append;
+
+impl Executable for Append {
+    type Args = AppendArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "by_name": {"type": "string"},
+                "position": {"type": "integer", "minimum": 0},
+                "public": {"type": "boolean", "default": false},
+            },
+            "required": ["by_name"],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &[
+            "playlist-read-private",
+            "playlist-modify-private",
+            "playlist-modify-public",
+        ]
+    }
+
+    // Append the tracks to the end of the users playlist with the given name, or insert them at
+    // `position` when set
+    // Note: Tracks are pushed through a PlaylistSink, which writes them in chunks
+    fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        // n.b. The position is checked before creating a missing playlist, which starts empty
+        let me = current_user_id(client)?;
+        let found = find_playlist(client, &me, &args.by_name)?;
+        let total = found.as_ref().map_or(0, |(_, total)| *total);
+        if let Some(position) = args.position.filter(|p| *p > total) {
+            return Err(ComponentError::invalid_argument(
+                "position",
+                format!(
+                    "{} is past the end of the playlist, which has {} tracks",
+                    position, total
+                ),
+            ));
+        }
+
+        let playlist_id = match found {
+            Some((playlist_id, _)) => playlist_id,
+            None => create_playlist(client, &me, &args.by_name, args.public)?,
+        };
+
+        let tracks = writable(prev.into_iter().next().unwrap_or_default());
+        let mut sink = PlaylistSink::new(client, playlist_id).with_position(args.position);
+        for track in tracks.iter() {
+            sink.push(track.clone())?;
+        }
+        sink.finish()?;
+        Ok(tracks)
+    }
+}
+
+/// PlaylistSink appends the tracks pushed into it to a playlist.
+///
+/// Track ids are buffered until a full chunk is available, then written in a single request.
+/// Tracks that can't be written (see [`writable`]) are skipped.
+pub struct PlaylistSink<'a> {
+    client: &'a Client,
+    playlist_id: PlaylistId<'static>,
+    /// Where the next chunk is inserted, or None to append to the end.
+    position: Option<u32>,
+    buffer: Vec<TrackId<'static>>,
+    written: usize,
+}
+
+impl<'a> PlaylistSink<'a> {
+    pub fn new(client: &'a Client, playlist_id: PlaylistId<'static>) -> Self {
+        PlaylistSink {
+            client,
+            playlist_id,
+            position: None,
+            buffer: Vec::with_capacity(PLAYLIST_WRITE_CHUNK_SIZE),
+            written: 0,
+        }
+    }
+
+    /// Insert the tracks starting at the given index, rather than appending them.
+    /// n.b. Each chunk is inserted after the last, so the tracks keep their order
+    pub fn with_position(mut self, position: Option<u32>) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Write the buffered tracks to the playlist.
+    fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<&TrackId> = self.buffer.iter().collect();
+        // n.b. rspotify types the position as a Duration, sending its milliseconds as the index
+        let position = self
+            .position
+            .map(|p| chrono::Duration::milliseconds(p.into()));
+        self.client
+            .playlist_add_items(self.playlist_id.as_ref(), playable(&ids), position)?;
+
+        if let Some(position) = self.position.as_mut() {
+            *position += ids.len() as u32;
+        }
+        self.written += ids.len();
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl TrackSink for PlaylistSink<'_> {
+    fn push(&mut self, track: Track) -> Result<()> {
+        if !is_writable(&track) {
+            return Ok(());
+        }
+
+        // n.b. Only the id is kept, the rest of the track is dropped once pushed
+        self.buffer.extend(track.track.id);
+        if self.buffer.len() >= PLAYLIST_WRITE_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<usize> {
+        self.flush()?;
+        Ok(self.written)
+    }
+}
+
+// --
+
+/// Remove the tracks that can't be written to a playlist.
+///
+/// Local files and tracks without an id have no track URI. These are skipped, rather than failing
+/// the whole write - the controller reports how many were skipped.
+fn writable(tracks: TrackList) -> TrackList {
+    tracks.into_iter().filter(is_writable).collect()
+}
+
+fn is_writable(track: &Track) -> bool {
+    !track.is_local && track.id.is_some()
+}
+
+/// Find the current users playlist with the given name, creating a new (private, unless
+/// `public` is set) playlist if it doesn't exist. Returns the playlist id, along with how many tracks are in the playlist.
+fn find_or_create_playlist(
+    client: &Client,
+    name: &str,
+    public: bool,
+) -> Result<(PlaylistId<'static>, u32)> {
+    let me = current_user_id(client)?;
+    match find_playlist(client, &me, name)? {
+        Some(found) => Ok(found),
+        None => Ok((create_playlist(client, &me, name, public)?, 0)),
+    }
+}
+
+/// Look up the id of the user the client is authenticated as.
+fn current_user_id(client: &Client) -> Result<UserId<'static>> {
+    crate::metrics::global().record_spotify_call("me");
+    Ok(client.me()?.id)
+}
+
+/// Find the playlist with the given name owned by `me`, returning its id and how many tracks
+/// are in it.
+fn find_playlist(
+    client: &Client,
+    me: &UserId,
+    name: &str,
+) -> Result<Option<(PlaylistId<'static>, u32)>> {
+    crate::metrics::global().record_spotify_call("current_user_playlists");
+    for playlist in client.current_user_playlists() {
+        let playlist = playlist?;
+        if playlist.name == name && playlist.owner.id == *me {
+            return Ok(Some((playlist.id, playlist.tracks.total)));
+        }
+    }
+    Ok(None)
+}
+
+/// Create a new (private, unless `public` is set) playlist for `me` with the given name.
+fn create_playlist(
+    client: &Client,
+    me: &UserId,
+    name: &str,
+    public: bool,
+) -> Result<PlaylistId<'static>> {
+    crate::metrics::global().record_spotify_call("user_playlist_create");
+    let playlist = client.user_playlist_create(me.as_ref(), name, Some(public), None, None)?;
+    Ok(playlist.id)
+}
+
+/// Map a chunk of track ids into the PlayableIds accepted by the playlist endpoints.
+fn playable<'a>(ids: &'a [&TrackId]) -> impl Iterator<Item = PlayableId<'a>> + Send + 'a {
+    ids.iter().map(|id| PlayableId::Track(id.as_ref()))
+}
+
+/// Render a playlist description template.
+///
+/// Supported placeholders:
+/// - `{date}` - the current date, formatted as YYYY-MM-DD
+/// - `{count}` - the number of tracks written to the playlist
+fn render_description(template: &str, now: DateTime<Utc>, count: usize) -> String {
+    template
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{count}", &count.to_string())
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{full_playlist, ids, me, page, playlist, spotify::MockSpotify, track};
+    use chrono::TimeZone;
+
+    #[test]
+    fn can_render_description_template() {
+        let now = Utc.with_ymd_and_hms(2023, 2, 18, 21, 53, 18).unwrap();
+        let res = render_description(
+            "Generated by SmarterPlaylists on {date}. {count} tracks",
+            now,
+            25,
+        );
+
+        assert_eq!(
+            res,
+            "Generated by SmarterPlaylists on 2023-02-18. 25 tracks"
+        );
+    }
+
+    #[test]
+    fn overwrite_sets_description_on_existing_playlist() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![
+                    playlist("other", "test playlist", "someone"),
+                    playlist("existing", "test playlist", "owner"),
+                ]),
+            )
+            .on("PUT", "/v1/playlists/existing/tracks", ())
+            .on("PUT", "/v1/playlists/existing", ());
+
+        let args = OverwriteArgs {
+            by_name: "test playlist".to_owned(),
+            description: Some("Generated by SmarterPlaylists on {date}.".to_owned()),
+            public: false,
+        };
+        Overwrite::execute(&spotify.client(), args, vec![vec![track("a"), track("b")]]).unwrap();
+
+        let replaced = spotify.requests("PUT", "/v1/playlists/existing/tracks");
+        assert_eq!(
+            replaced[0].json()["uris"],
+            serde_json::json!(["spotify:track:a", "spotify:track:b"])
+        );
+
+        let details = spotify.requests("PUT", "/v1/playlists/existing");
+        assert_eq!(details.len(), 1);
+        assert_eq!(
+            details[0].json()["description"],
+            format!(
+                "Generated by SmarterPlaylists on {}.",
+                Utc::now().format("%Y-%m-%d")
+            )
+        );
+    }
+
+    #[test]
+    fn overwrite_sets_description_on_created_playlist() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "POST",
+                "/v1/users/owner/playlists",
+                full_playlist("created", "test playlist", "owner"),
+            )
+            .on("PUT", "/v1/playlists/created/tracks", ())
+            .on("PUT", "/v1/playlists/created", ());
+
+        let args = OverwriteArgs {
+            by_name: "test playlist".to_owned(),
+            description: Some("{count} tracks".to_owned()),
+            public: false,
+        };
+        Overwrite::execute(&spotify.client(), args, vec![vec![track("a")]]).unwrap();
+
+        assert_eq!(
+            spotify.requests("POST", "/v1/users/owner/playlists").len(),
+            1
+        );
+
+        let details = spotify.requests("PUT", "/v1/playlists/created");
+        assert_eq!(details[0].json()["description"], "1 tracks");
+    }
+
+    #[test]
+    fn outputs_forward_public_flag_when_creating_playlist() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "POST",
+                "/v1/users/owner/playlists",
+                full_playlist("created", "test playlist", "owner"),
+            )
+            .on("PUT", "/v1/playlists/created/tracks", ())
+            .on(
+                "POST",
+                "/v1/playlists/created/tracks",
+                serde_json::json!({ "snapshot_id": "snapshot" }),
+            );
+
+        let args = OverwriteArgs {
+            by_name: "test playlist".to_owned(),
+            description: None,
+            public: true,
+        };
+        Overwrite::execute(&spotify.client(), args, vec![vec![track("a")]]).unwrap();
+
+        let args = AppendArgs {
+            by_name: "test playlist".to_owned(),
+            position: None,
+            public: false,
+        };
+        Append::execute(&spotify.client(), args, vec![vec![track("a")]]).unwrap();
+
+        let created = spotify.requests("POST", "/v1/users/owner/playlists");
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0].json()["public"], true);
+        assert_eq!(created[1].json()["public"], false);
+
+        // Playlists are private unless asked otherwise
+        let args: OverwriteArgs =
+            serde_json::from_value(serde_json::json!({ "by_name": "x" })).unwrap();
+        assert!(!args.public);
+    }
+
+    #[test]
+    fn overwrite_skips_tracks_without_uri() {
+        let mut local = track("local");
+        local.is_local = true;
+        let mut missing = track("missing");
+        missing.id = None;
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("existing", "test playlist", "owner")]),
+            )
+            .on("PUT", "/v1/playlists/existing/tracks", ());
+
+        let args = OverwriteArgs {
+            by_name: "test playlist".to_owned(),
+            description: None,
+            public: false,
+        };
+        let tracks = vec![track("a"), local, missing, track("b")];
+        let res = Overwrite::execute(&spotify.client(), args, vec![tracks]).unwrap();
+
+        // Only the written tracks are returned
+        assert_eq!(ids(&res), ["a", "b"]);
+
+        let replaced = spotify.requests("PUT", "/v1/playlists/existing/tracks");
+        assert_eq!(
+            replaced[0].json()["uris"],
+            serde_json::json!(["spotify:track:a", "spotify:track:b"])
+        );
+    }
+
+    #[test]
+    fn overwrite_skips_description_when_unset() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("existing", "test playlist", "owner")]),
+            )
+            .on("PUT", "/v1/playlists/existing/tracks", ());
+
+        let args = OverwriteArgs {
+            by_name: "test playlist".to_owned(),
+            description: None,
+            public: false,
+        };
+        Overwrite::execute(&spotify.client(), args, vec![vec![track("a")]]).unwrap();
+
+        assert!(spotify.requests("PUT", "/v1/playlists/existing").is_empty());
+    }
+
+    fn existing_playlist() -> MockSpotify {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("existing", "test playlist", "owner")]),
+            )
+            .on(
+                "POST",
+                "/v1/playlists/existing/tracks",
+                serde_json::json!({ "snapshot_id": "snapshot" }),
+            );
+        spotify
+    }
+
+    #[test]
+    fn playlist_sink_flushes_full_chunks_as_tracks_arrive() {
+        let spotify = existing_playlist();
+        let client = spotify.client();
+        let mut sink = PlaylistSink::new(&client, PlaylistId::from_id("existing").unwrap());
+
+        for i in 0..PLAYLIST_WRITE_CHUNK_SIZE {
+            sink.push(track(&format!("t{i}"))).unwrap();
+            let flushed = spotify.requests("POST", "/v1/playlists/existing/tracks");
+            // Nothing is written until a full chunk is buffered
+            assert_eq!(
+                flushed.len(),
+                usize::from(i == PLAYLIST_WRITE_CHUNK_SIZE - 1)
+            );
+        }
+
+        sink.push(track("last")).unwrap();
+        assert_eq!(sink.finish().unwrap(), PLAYLIST_WRITE_CHUNK_SIZE + 1);
+
+        let flushed = spotify.requests("POST", "/v1/playlists/existing/tracks");
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(
+            flushed[1].json()["uris"],
+            serde_json::json!(["spotify:track:last"])
+        );
+    }
+
+    #[test]
+    fn append_writes_in_chunks_and_skips_tracks_without_uri() {
+        let mut local = track("local");
+        local.is_local = true;
+
+        let mut tracks: TrackList = (0..250).map(|i| track(&format!("t{i}"))).collect();
+        tracks.insert(10, local);
+
+        let spotify = existing_playlist();
+        let args = AppendArgs {
+            by_name: "test playlist".to_owned(),
+            position: None,
+            public: false,
+        };
+        let res = Append::execute(&spotify.client(), args, vec![tracks]).unwrap();
+        assert_eq!(res.len(), 250);
+
+        let sizes: Vec<usize> = spotify
+            .requests("POST", "/v1/playlists/existing/tracks")
+            .iter()
+            .map(|r| r.json()["uris"].as_array().unwrap().len())
+            .collect();
+        assert_eq!(sizes, [100, 100, 50]);
+    }
+
+    #[test]
+    fn append_inserts_at_position() {
+        let mut existing = playlist("existing", "test playlist", "owner");
+        existing.tracks.total = 5;
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on("GET", "/v1/me/playlists?offset=0", page(vec![existing]))
+            .on(
+                "POST",
+                "/v1/playlists/existing/tracks",
+                serde_json::json!({ "snapshot_id": "snapshot" }),
+            );
+
+        let tracks: TrackList = (0..150).map(|i| track(&format!("t{i}"))).collect();
+        let args = AppendArgs {
+            by_name: "test playlist".to_owned(),
+            position: Some(2),
+            public: false,
+        };
+        Append::execute(&spotify.client(), args, vec![tracks]).unwrap();
+
+        // Each chunk is inserted after the last
+        let positions: Vec<serde_json::Value> = spotify
+            .requests("POST", "/v1/playlists/existing/tracks")
+            .iter()
+            .map(|r| r.json()["position"].clone())
+            .collect();
+        assert_eq!(positions, [2, 102]);
+
+        let args = AppendArgs {
+            by_name: "test playlist".to_owned(),
+            position: Some(6),
+            public: false,
+        };
+        let err = Append::execute(&spotify.client(), args, vec![]).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ComponentError::InvalidArgument {
+                    name: "position",
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn append_checks_position_before_creating_playlist() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "POST",
+                "/v1/users/owner/playlists",
+                full_playlist("created", "test playlist", "owner"),
+            );
+
+        // A missing playlist would be created empty, so only position 0 fits
+        let args = AppendArgs {
+            by_name: "test playlist".to_owned(),
+            position: Some(1),
+            public: false,
+        };
+        let err = Append::execute(&spotify.client(), args, vec![]).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ComponentError::InvalidArgument {
+                    name: "position",
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+        assert!(spotify
+            .requests("POST", "/v1/users/owner/playlists")
+            .is_empty());
+    }
+}
@@ -1,9 +1,12 @@
 ///! Sources take user-defined arguments and return TrackLists
+use rspotify::http::Query;
 use rspotify::model::*;
 use rspotify::prelude::*;
 use rspotify::AuthCodeSpotify as Client;
 
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use super::Result;
 use super::*;
@@ -18,14 +21,41 @@ pub struct Album;
 impl Executable for Album {
     type Args = AlbumArgs;
 
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+            },
+            "required": ["id"],
+        })
+    }
+
     // Fetch the list of tracks in the album, then
     // request the FullTrack object
     fn execute(client: &Client, args: Self::Args, _: Vec<TrackList>) -> Result<TrackList> {
         let mut ids = Vec::new(); // Temp track id vector
-        for t in client.album_track(AlbumId::from_id_or_uri(&args.id).unwrap()) {
-            ids.push(t.unwrap().id.unwrap())
+        let id = AlbumId::from_id_or_uri(&args.id)
+            .map_err(|_| ComponentError::invalid_id("album", &args.id))?;
+        crate::metrics::global().record_spotify_call("album_tracks");
+        for t in client.album_track(id) {
+            // Skip tracks without an id, we can't fetch them
+            if let Some(id) = t?.id {
+                ids.push(id)
+            }
+        }
+        if ids.is_empty() {
+            return Ok(TrackList::new());
         }
-        client.tracks(ids, None).map_err(|e| e.into())
+        crate::metrics::global().record_spotify_call("tracks");
+        let tracks = client.tracks(ids, None)?;
+        Ok(tracks.into_iter().map(Track::from).collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        normalize_id("id", &mut args.id, |id| {
+            AlbumId::from_id_or_uri(id).map(|id| id.uri())
+        })
     }
 }
 
@@ -34,6 +64,8 @@ impl Executable for Album {
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ArtistTopTracksArgs {
     pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<Country>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -42,15 +74,32 @@ pub struct ArtistTopTracks;
 impl Executable for ArtistTopTracks {
     type Args = ArtistTopTracksArgs;
 
-    // Fetch top tracks for a given artist
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "market": {"type": "string", "minLength": 2, "maxLength": 2},
+            },
+            "required": ["id"],
+        })
+    }
+
+    // Fetch top tracks for a given artist, in the given market
     // Note: This selects the artists top tracks, not all of them
     fn execute(client: &Client, args: Self::Args, _: Vec<TrackList>) -> Result<TrackList> {
-        client
-            .artist_top_tracks(
-                ArtistId::from_id_or_uri(&args.id).unwrap(),
-                Market::FromToken,
-            )
-            .map_err(|e| e.into())
+        let id = ArtistId::from_id_or_uri(&args.id)
+            .map_err(|_| ComponentError::invalid_id("artist", &args.id))?;
+        crate::metrics::global().record_spotify_call("artist_top_tracks");
+        let market = args.market.map_or(Market::FromToken, Market::Country);
+        let tracks = client.artist_top_tracks(id, market)?;
+        Ok(tracks.into_iter().map(Track::from).collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        normalize_id("id", &mut args.id, |id| {
+            ArtistId::from_id_or_uri(id).map(|id| id.uri())
+        })
     }
 }
 
@@ -58,7 +107,14 @@ impl Executable for ArtistTopTracks {
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct UserLikedTracksArgs {
-    pub limit: u32,
+    /// The most liked songs to fetch, or the whole library when unset.
+    /// n.b. Also accepted as `max`, like [`LikedSinceLastRunArgs::max`]
+    #[serde(default, alias = "max", skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_before: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -67,22 +123,899 @@ pub struct UserLikedTracks;
 impl Executable for UserLikedTracks {
     type Args = UserLikedTracksArgs;
 
-    // Fetch users liked songs
-    // Note: Limited by most recent [1-999]
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "limit": {"type": "integer", "minimum": 0},
+                "added_after": {"type": "string", "format": "date"},
+                "added_before": {"type": "string", "format": "date"},
+            },
+            "required": [],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &["user-library-read"]
+    }
+
+    // Fetch users liked songs, optionally only those saved within [added_after, added_before)
+    // Note: Limited by the most recent `limit` songs, if given, and always by $SPL_LIKED_TRACKS_CAP
     fn execute(client: &Client, args: Self::Args, prev: Vec<TrackList>) -> Result<TrackList> {
+        let after = parse_date("added_after", args.added_after.as_deref())?;
+        let before = parse_date("added_before", args.added_before.as_deref())?;
+
+        let max = [args.limit, Some(liked_tracks_cap())]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or_default();
+        let tracks = saved_tracks(client, crate::spotify::page_size(), max, after)?;
+        Ok(tracks
+            .into_iter()
+            .filter(|t| {
+                let Some(added_at) = t.added_at else {
+                    return false;
+                };
+                after.is_none_or(|after| added_at >= after)
+                    && before.is_none_or(|before| added_at < before)
+            })
+            .collect())
+    }
+
+    fn normalize(args: &mut Self::Args) -> Result<()> {
+        let after = parse_date("added_after", args.added_after.as_deref())?;
+        let before = parse_date("added_before", args.added_before.as_deref())?;
+        if let (Some(after), Some(before)) = (after, before) {
+            if after >= before {
+                return Err(ComponentError::invalid_argument(
+                    "added_before",
+                    "must be after added_after",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an optional `YYYY-MM-DD` argument as the start of that day, in UTC.
+fn parse_date(name: &'static str, value: Option<&str>) -> Result<Option<DateTime<Utc>>> {
+    value
+        .map(|value| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()))
+                .map_err(|_| {
+                    ComponentError::invalid_argument(
+                        name,
+                        format!("{value} is not a YYYY-MM-DD date"),
+                    )
+                })
+        })
+        .transpose()
+}
+
+/// The most liked songs a single source will fetch, however large the library.
+const DEFAULT_LIKED_TRACKS_CAP: u32 = 10_000;
+
+/// Return the safety cap on liked songs fetched, to avoid runaway paging through huge libraries.
+///
+/// Defaults to [`DEFAULT_LIKED_TRACKS_CAP`], and can be changed with $SPL_LIKED_TRACKS_CAP.
+fn liked_tracks_cap() -> u32 {
+    std::env::var("SPL_LIKED_TRACKS_CAP")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_LIKED_TRACKS_CAP)
+}
+
+/// Fetch up to `max` of the users liked songs, `page_size` tracks at a time.
+///
+/// The songs come most recently saved first, so paging stops early once it passes `since`.
+fn saved_tracks(
+    client: &Client,
+    page_size: u32,
+    max: u32,
+    since: Option<DateTime<Utc>>,
+) -> Result<TrackList> {
+    let mut tracks = TrackList::new();
+    let mut offset = 0;
+    while offset < max {
+        let limit = page_size.min(max - offset);
+        crate::metrics::global().record_spotify_call("saved_tracks");
+        let page = client.current_user_saved_tracks_manual(None, Some(limit), Some(offset))?;
+        if page.items.is_empty() {
+            break;
+        }
+        offset += page.items.len() as u32;
+        let passed = matches!((since, page.items.last()), (Some(since), Some(last)) if last.added_at < since);
+        tracks.extend(page.items.into_iter().map(|st| Track {
+            track: st.track,
+            added_at: Some(st.added_at),
+        }));
+        if passed {
+            break;
+        }
+    }
+    Ok(tracks)
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LikedSinceLastRunArgs {
+    /// The most liked songs to fetch on the first run, or the whole library when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u32>,
+    /// When the flow last ran successfully, filled in from the saved flow before each run.
+    /// n.b. Never read from the flow's parameters, but still serialized so the node's hash
+    /// changes from one run to the next
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub last_run_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LikedSinceLastRun;
+
+impl Executable for LikedSinceLastRun {
+    type Args = LikedSinceLastRunArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "max": {"type": "integer", "minimum": 0},
+            },
+            "required": [],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &["user-library-read"]
+    }
+
+    // Fetch the users liked songs saved since the flow last ran successfully
+    // Note: The first run, or any unsaved flow, has no last run so returns the most recent `max`
+    fn execute(client: &Client, args: Self::Args, _: Vec<TrackList>) -> Result<TrackList> {
+        let max = match args.last_run_at {
+            Some(_) => liked_tracks_cap(),
+            None => args.max.unwrap_or(u32::MAX).min(liked_tracks_cap()),
+        };
+        let tracks = saved_tracks(client, crate::spotify::page_size(), max, args.last_run_at)?;
+        Ok(liked_since(tracks, args.last_run_at))
+    }
+}
+
+/// Keep the tracks saved strictly after `cursor`, or every track when there is no cursor.
+fn liked_since(tracks: TrackList, cursor: Option<DateTime<Utc>>) -> TrackList {
+    let Some(cursor) = cursor else {
+        return tracks;
+    };
+    tracks
+        .into_iter()
+        .filter(|t| t.added_at.is_some_and(|added_at| added_at > cursor))
+        .collect()
+}
+
+// --
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MyPlaylistsMatchingArgs {
+    pub pattern: String,
+    #[serde(default)]
+    pub combine: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MyPlaylistsMatching;
+
+impl Executable for MyPlaylistsMatching {
+    type Args = MyPlaylistsMatchingArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": {"type": "string"},
+                "combine": {"type": "boolean", "default": false},
+            },
+            "required": ["pattern"],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &["playlist-read-private"]
+    }
+
+    // Fetch the tracks from the playlists the user owns whose name contains the pattern
+    // (case-insensitive)
+    // Note: Only the first matching playlist is used, unless `combine` is set
+    fn execute(client: &Client, args: Self::Args, _: Vec<TrackList>) -> Result<TrackList> {
+        let pattern = args.pattern.to_lowercase();
+
+        crate::metrics::global().record_spotify_call("me");
+        let me = client.me()?;
+
         let mut tracks = TrackList::new();
-        let mut offset = 0;
-        loop {
-            let page = client.current_user_saved_tracks_manual(None, Some(50), Some(offset))?;
-            if offset >= 949 || page.items.is_empty() {
+        crate::metrics::global().record_spotify_call("current_user_playlists");
+        for playlist in client.current_user_playlists() {
+            let playlist = playlist?;
+            // n.b. The user's playlists include those they follow, which aren't theirs
+            if playlist.owner.id != me.id || !playlist.name.to_lowercase().contains(&pattern) {
+                continue;
+            }
+
+            tracks.extend(playlist_tracks(client, playlist.id.as_ref())?);
+            if !args.combine {
                 break;
             }
-            offset += page.items.len() as u32;
-            tracks.extend(page.items.iter().map(|st| st.track.clone()));
         }
         Ok(tracks)
     }
 }
 
+// --
+
+/// SavedEpisode is a podcast episode in the user's library.
+/// n.b. rspotify doesn't model the saved episodes endpoint yet
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SavedEpisode {
+    pub added_at: DateTime<Utc>,
+    pub episode: FullEpisode,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SavedEpisodesArgs {
+    pub limit: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SavedEpisodes;
+
+impl Executable for SavedEpisodes {
+    type Args = SavedEpisodesArgs;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "limit": {"type": "integer", "minimum": 0},
+            },
+            "required": ["limit"],
+        })
+    }
+
+    fn required_scopes() -> &'static [&'static str] {
+        &["user-library-read"]
+    }
+
+    // Fetch the users most recently saved podcast episodes
+    // Note: Flows can only carry tracks so far, so this fails unless the user has no episodes
+    fn execute(client: &Client, args: Self::Args, _: Vec<TrackList>) -> Result<TrackList> {
+        let episodes = saved_episodes(client, args.limit, crate::spotify::page_size())?;
+        if episodes.is_empty() {
+            return Ok(TrackList::new());
+        }
+
+        Err(ComponentError::Unsupported {
+            message: format!(
+                "found {} saved episodes, but flows can only contain tracks",
+                episodes.len()
+            ),
+        })
+    }
+}
+
+/// Fetch up to `limit` of the users saved episodes, `page_size` at a time.
+fn saved_episodes(client: &Client, limit: u32, page_size: u32) -> Result<Vec<SavedEpisode>> {
+    let mut episodes = Vec::new();
+    while episodes.len() < limit as usize {
+        let (size, offset) = (page_size.to_string(), episodes.len().to_string());
+        let query = Query::from([("limit", size.as_str()), ("offset", offset.as_str())]);
+
+        crate::metrics::global().record_spotify_call("saved_episodes");
+        let res = client.api_get("me/episodes", &query)?;
+        let page: Page<SavedEpisode> = serde_json::from_str(&res).map_err(ClientError::from)?;
+        if page.items.is_empty() {
+            break;
+        }
+        episodes.extend(page.items);
+    }
+
+    episodes.truncate(limit as usize);
+    Ok(episodes)
+}
+
+// --
+
+/// Fetch all the tracks in a playlist.
+/// Note: Episodes and unavailable items are skipped, as are items that fail to parse - e.g. the
+/// null items collaborative playlists can return - rather than failing the whole fetch.
+fn playlist_tracks(client: &Client, id: PlaylistId<'_>) -> Result<TrackList> {
+    // n.b. We can't use `playlist_items` here - one bad item fails to deserialize the whole page
+    #[derive(Deserialize)]
+    struct Payload {
+        items: Vec<Value>,
+        next: Option<String>,
+    }
+
+    let page_size = crate::spotify::page_size().to_string();
+    let mut tracks = TrackList::new();
+    let mut offset = 0;
+    loop {
+        let offset_str = offset.to_string();
+        let query = Query::from([
+            ("limit", page_size.as_str()),
+            ("offset", offset_str.as_str()),
+        ]);
+
+        crate::metrics::global().record_spotify_call("playlist_items");
+        let res = client.api_get(&format!("playlists/{}/tracks", id.id()), &query)?;
+        let page: Payload = serde_json::from_str(&res).map_err(ClientError::from)?;
+        if page.items.is_empty() {
+            break;
+        }
+        offset += page.items.len();
+
+        for item in page.items {
+            let item: PlaylistItem = match serde_json::from_value(item) {
+                Ok(item) => item,
+                Err(err) => {
+                    log::warn!("skipping unreadable item in playlist {}: {}", id.id(), err);
+                    continue;
+                }
+            };
+            if let Some(PlayableItem::Track(track)) = item.track {
+                tracks.push(Track {
+                    track,
+                    added_at: item.added_at,
+                });
+            }
+        }
+
+        if page.next.is_none() {
+            break;
+        }
+    }
+    Ok(tracks)
+}
+
 // pub struct SpotifyPlaylist;
 // pub struct PrivatePlaylist;
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PublicError;
+    use crate::testing::{
+        ids, me, page, playlist, playlist_item, simplified_track, spotify::MockSpotify, track,
+    };
+
+    fn mock_playlists() -> MockSpotify {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![
+                    // Followed, but owned by someone else
+                    playlist("followed", "Chill Classics", "someone"),
+                    playlist("chill1", "Chill Vibes", "owner"),
+                    playlist("workout", "Workout", "owner"),
+                    playlist("chill2", "sunday chill", "owner"),
+                ]),
+            );
+
+        for (id, tracks) in [
+            ("followed", ["x", "y"]),
+            ("chill1", ["a", "b"]),
+            ("workout", ["c", "d"]),
+            ("chill2", ["e", "f"]),
+        ] {
+            let path = format!("/v1/playlists/{}/tracks", id);
+            let items = tracks.iter().map(|t| playlist_item(track(t))).collect();
+            spotify.on("GET", &path, page::<PlaylistItem>(vec![])).on(
+                "GET",
+                &format!("{}?offset=0", path),
+                page(items),
+            );
+        }
+
+        spotify
+    }
+
+    #[test]
+    fn my_playlists_matching_returns_first_match() {
+        let spotify = mock_playlists();
+        let args = MyPlaylistsMatchingArgs {
+            pattern: "CHILL".to_owned(),
+            combine: false,
+        };
+        let res = MyPlaylistsMatching::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["a", "b"]);
+        assert!(spotify
+            .requests("GET", "/v1/playlists/chill2/tracks")
+            .is_empty());
+    }
+
+    #[test]
+    fn my_playlists_matching_skips_followed_playlists() {
+        let spotify = mock_playlists();
+        let args = MyPlaylistsMatchingArgs {
+            pattern: "classics".to_owned(),
+            combine: true,
+        };
+        let res = MyPlaylistsMatching::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert!(res.is_empty());
+        assert!(spotify
+            .requests("GET", "/v1/playlists/followed/tracks")
+            .is_empty());
+    }
+
+    #[test]
+    fn my_playlists_matching_can_combine_matches() {
+        let spotify = mock_playlists();
+        let args = MyPlaylistsMatchingArgs {
+            pattern: "chill".to_owned(),
+            combine: true,
+        };
+        let res = MyPlaylistsMatching::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["a", "b", "e", "f"]);
+    }
+
+    #[test]
+    fn my_playlists_matching_without_match_is_empty() {
+        let spotify = mock_playlists();
+        let args = MyPlaylistsMatchingArgs {
+            pattern: "jazz".to_owned(),
+            combine: true,
+        };
+        let res = MyPlaylistsMatching::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn playlist_tracks_skips_unreadable_items() {
+        let spotify = MockSpotify::start();
+        let mut items = page(vec![
+            playlist_item(track("a")),
+            playlist_item(track("b")),
+            playlist_item(track("c")),
+        ]);
+        items.items[1].track = None;
+        let mut items = serde_json::to_value(items).unwrap();
+        items["items"]
+            .as_array_mut()
+            .unwrap()
+            .splice(1..1, [Value::Null, json!({ "track": { "unknown": true } })]);
+        spotify.on("GET", "/v1/playlists/collab/tracks", items);
+
+        let id = PlaylistId::from_id("collab").unwrap();
+        let res = playlist_tracks(&spotify.client(), id).unwrap();
+
+        assert_eq!(ids(&res), ["a", "c"]);
+    }
+
+    #[test]
+    fn album_rejects_invalid_id() {
+        let args = AlbumArgs {
+            id: "spotify:album:not-an-id".to_owned(),
+        };
+        let err = Album::execute(&Client::default(), args, vec![]).unwrap_err();
+
+        assert!(
+            matches!(err, ComponentError::InvalidId { kind: "album", .. }),
+            "{:?}",
+            err
+        );
+        assert_bad_request(err, "Invalid album id: spotify:album:not-an-id");
+    }
+
+    #[test]
+    fn artist_top_tracks_rejects_invalid_id() {
+        // An album URI isn't a valid artist id
+        let args = ArtistTopTracksArgs {
+            id: "spotify:album:6qqNVTkY8uBg9cP3Jd7DAH".to_owned(),
+            market: None,
+        };
+        let err = ArtistTopTracks::execute(&Client::default(), args, vec![]).unwrap_err();
+
+        assert!(
+            matches!(err, ComponentError::InvalidId { kind: "artist", .. }),
+            "{:?}",
+            err
+        );
+        assert_bad_request(
+            err,
+            "Invalid artist id: spotify:album:6qqNVTkY8uBg9cP3Jd7DAH",
+        );
+    }
+
+    /// Assert the error is shown to the user as a BadRequest with the given message.
+    fn assert_bad_request(err: ComponentError, expected: &str) {
+        match PublicError::from(err) {
+            PublicError::BadRequest { message } => assert_eq!(message, expected),
+            err => panic!("expected BadRequest, found {:?}", err),
+        }
+    }
+
+    #[test]
+    fn artist_top_tracks_maps_missing_artist_to_not_found() {
+        let spotify = MockSpotify::start();
+        spotify.on_status(
+            "GET",
+            "/v1/artists/6qqNVTkY8uBg9cP3Jd7DAH/top-tracks",
+            404,
+            serde_json::json!({"error": {"status": 404, "message": "Not found."}}),
+        );
+
+        let args = ArtistTopTracksArgs {
+            id: "spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH".to_owned(),
+            market: None,
+        };
+        let err = ArtistTopTracks::execute(&spotify.client(), args, vec![]).unwrap_err();
+
+        assert!(matches!(err, ComponentError::NotFound), "{:?}", err);
+    }
+
+    #[test]
+    fn artist_top_tracks_requests_given_market() {
+        let spotify = MockSpotify::start();
+        spotify.on(
+            "GET",
+            "/v1/artists/6qqNVTkY8uBg9cP3Jd7DAH/top-tracks?market=SE",
+            serde_json::json!({"tracks": [track("a")]}),
+        );
+
+        let args = ArtistTopTracksArgs {
+            id: "spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH".to_owned(),
+            market: Some(Country::Sweden),
+        };
+        let tracks = ArtistTopTracks::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&tracks), ["a"]);
+    }
+
+    #[test]
+    fn album_skips_tracks_without_id() {
+        let mut local = simplified_track(track("local"));
+        local.id = None;
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on(
+                "GET",
+                "/v1/albums/album/tracks",
+                page::<SimplifiedTrack>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/albums/album/tracks?offset=0",
+                page(vec![simplified_track(track("a")), local]),
+            )
+            .on(
+                "GET",
+                "/v1/tracks/",
+                serde_json::json!({ "tracks": [track("a")] }),
+            );
+
+        let args = AlbumArgs {
+            id: "spotify:album:album".to_owned(),
+        };
+        let res = Album::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["a"]);
+        assert_eq!(
+            spotify.requests("GET", "/v1/tracks/")[0].param("ids"),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn album_returns_page_errors() {
+        let spotify = MockSpotify::start();
+        spotify.on_status(
+            "GET",
+            "/v1/albums/album/tracks",
+            500,
+            serde_json::json!({"error": {"status": 500, "message": "Server error"}}),
+        );
+
+        let args = AlbumArgs {
+            id: "spotify:album:album".to_owned(),
+        };
+        let err = Album::execute(&spotify.client(), args, vec![]).unwrap_err();
+
+        assert!(matches!(err, ComponentError::Upstream(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn user_liked_tracks_keeps_saved_date() {
+        let saved = |id: &str, added_at: &str| serde_json::json!({ "added_at": added_at, "track": track(id) });
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/tracks", page::<SavedTrack>(vec![]))
+            .on(
+                "GET",
+                "/v1/me/tracks?offset=0",
+                page(vec![
+                    saved("a", "2023-02-01T00:00:00Z"),
+                    saved("b", "2021-06-15T12:30:00Z"),
+                ]),
+            );
+
+        let args = UserLikedTracksArgs {
+            limit: Some(50),
+            added_after: None,
+            added_before: None,
+        };
+        let res = UserLikedTracks::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["a", "b"]);
+        assert_eq!(
+            res[1].added_at.unwrap().to_rfc3339(),
+            "2021-06-15T12:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn user_liked_tracks_accepts_max_as_limit() {
+        let args: UserLikedTracksArgs =
+            serde_json::from_value(serde_json::json!({ "max": 10 })).unwrap();
+        assert_eq!(args.limit, Some(10));
+
+        // Written back out under the one name
+        assert_eq!(
+            serde_json::to_value(&args).unwrap(),
+            serde_json::json!({ "limit": 10 })
+        );
+    }
+
+    #[test]
+    fn user_liked_tracks_fetches_pages_of_the_given_size() {
+        let saved = |i: usize| serde_json::json!({ "added_at": "2023-02-01T00:00:00Z", "track": track(&format!("t{i}")) });
+        let spotify = MockSpotify::start();
+        spotify.on("GET", "/v1/me/tracks", page::<SavedTrack>(vec![]));
+        for offset in [0, 10, 20] {
+            let items = (offset..25.min(offset + 10)).map(saved).collect();
+            spotify.on(
+                "GET",
+                &format!("/v1/me/tracks?limit=10&offset={offset}"),
+                page::<serde_json::Value>(items),
+            );
+        }
+
+        let res = saved_tracks(&spotify.client(), 10, DEFAULT_LIKED_TRACKS_CAP, None).unwrap();
+        assert_eq!(res.len(), 25);
+
+        // Three pages of tracks, then an empty page to finish
+        assert_eq!(spotify.requests("GET", "/v1/me/tracks").len(), 4);
+    }
+
+    #[test]
+    fn user_liked_tracks_fetches_the_full_library() {
+        let saved = |i: usize| serde_json::json!({ "added_at": "2023-02-01T00:00:00Z", "track": track(&format!("t{i}")) });
+        let spotify = MockSpotify::start();
+        spotify.on("GET", "/v1/me/tracks", page::<SavedTrack>(vec![]));
+        for offset in (0..2000).step_by(50) {
+            let items = (offset..offset + 50).map(saved).collect();
+            spotify.on(
+                "GET",
+                &format!("/v1/me/tracks?limit=50&offset={offset}"),
+                page::<serde_json::Value>(items),
+            );
+        }
+
+        let res = saved_tracks(&spotify.client(), 50, DEFAULT_LIKED_TRACKS_CAP, None).unwrap();
+        assert_eq!(res.len(), 2000);
+        assert_eq!(spotify.requests("GET", "/v1/me/tracks").len(), 41);
+
+        // An explicit max stops paging part way through a page
+        spotify.on(
+            "GET",
+            "/v1/me/tracks?limit=20&offset=100",
+            page::<serde_json::Value>((100..120).map(saved).collect()),
+        );
+        let res = saved_tracks(&spotify.client(), 50, 120, None).unwrap();
+        assert_eq!(res.len(), 120);
+        let last = spotify.requests("GET", "/v1/me/tracks").pop().unwrap();
+        assert_eq!(last.param("limit"), Some("20"));
+    }
+
+    #[test]
+    fn user_liked_tracks_filters_by_date_range() {
+        let saved = |id: &str, added_at: &str| serde_json::json!({ "added_at": added_at, "track": track(id) });
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/tracks", page::<SavedTrack>(vec![]))
+            .on(
+                "GET",
+                "/v1/me/tracks?offset=0",
+                page(vec![
+                    saved("newer", "2024-01-01T00:00:00Z"),
+                    saved("late", "2023-12-31T23:59:59Z"),
+                    saved("early", "2023-01-01T00:00:00Z"),
+                    saved("older", "2022-12-31T23:59:59Z"),
+                ]),
+            );
+
+        let mut args = UserLikedTracksArgs {
+            limit: Some(50),
+            added_after: Some("2023-01-01".to_owned()),
+            added_before: Some("2024-01-01".to_owned()),
+        };
+        UserLikedTracks::normalize(&mut args).unwrap();
+        let res = UserLikedTracks::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["late", "early"]);
+
+        // The last page is older than added_after, so there's no need to fetch any more
+        assert_eq!(spotify.requests("GET", "/v1/me/tracks").len(), 1);
+    }
+
+    #[test]
+    fn user_liked_tracks_rejects_invalid_date_range() {
+        let mut args = UserLikedTracksArgs {
+            limit: Some(50),
+            added_after: Some("2023-01-01".to_owned()),
+            added_before: Some("2023-01-01".to_owned()),
+        };
+        let err = UserLikedTracks::normalize(&mut args).unwrap_err();
+        assert_bad_request(err, "Invalid added_before: must be after added_after");
+
+        args.added_before = Some("01/01/2024".to_owned());
+        let err = UserLikedTracks::normalize(&mut args).unwrap_err();
+        assert_bad_request(
+            err,
+            "Invalid added_before: 01/01/2024 is not a YYYY-MM-DD date",
+        );
+    }
+
+    #[test]
+    fn liked_since_compares_against_the_cursor() {
+        let saved = |id: &str, added_at: &str| {
+            let mut t = track(id);
+            t.added_at = Some(added_at.parse().unwrap());
+            t
+        };
+        let tracks = vec![
+            saved("newer", "2023-07-01T12:00:01Z"),
+            saved("exact", "2023-07-01T12:00:00Z"),
+            saved("older", "2023-06-30T00:00:00Z"),
+        ];
+
+        let cursor = "2023-07-01T12:00:00Z".parse().ok();
+        assert_eq!(ids(&liked_since(tracks.clone(), cursor)), ["newer"]);
+        assert_eq!(ids(&liked_since(tracks, None)).len(), 3);
+    }
+
+    #[test]
+    fn liked_since_last_run_stops_paging_at_the_cursor() {
+        let saved = |id: &str, added_at: &str| serde_json::json!({ "added_at": added_at, "track": track(id) });
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/tracks", page::<SavedTrack>(vec![]))
+            .on(
+                "GET",
+                "/v1/me/tracks?offset=0",
+                page(vec![
+                    saved("newer", "2023-07-02T00:00:00Z"),
+                    saved("older", "2023-06-30T00:00:00Z"),
+                ]),
+            );
+
+        let args = LikedSinceLastRunArgs {
+            max: None,
+            last_run_at: "2023-07-01T00:00:00Z".parse().ok(),
+        };
+        let res = LikedSinceLastRun::execute(&spotify.client(), args, vec![]).unwrap();
+
+        assert_eq!(ids(&res), ["newer"]);
+        assert_eq!(spotify.requests("GET", "/v1/me/tracks").len(), 1);
+    }
+
+    fn saved_episode(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "added_at": "2023-06-01T00:00:00Z",
+            "episode": {
+                "audio_preview_url": null,
+                "description": "",
+                "duration_ms": 1800000,
+                "explicit": false,
+                "external_urls": {},
+                "href": "",
+                "id": id,
+                "images": [],
+                "is_externally_hosted": false,
+                "is_playable": true,
+                "language": "en",
+                "languages": ["en"],
+                "name": format!("Episode {id}"),
+                "release_date": "2023-06-01",
+                "release_date_precision": "day",
+                "resume_point": null,
+                "show": {
+                    "available_markets": [],
+                    "copyrights": [],
+                    "description": "",
+                    "explicit": false,
+                    "external_urls": {},
+                    "href": "",
+                    "id": "show",
+                    "images": [],
+                    "is_externally_hosted": null,
+                    "languages": ["en"],
+                    "media_type": "audio",
+                    "name": "Show",
+                    "publisher": "Publisher",
+                },
+            },
+        })
+    }
+
+    fn mock_episodes() -> MockSpotify {
+        let spotify = MockSpotify::start();
+        spotify
+            .on(
+                "GET",
+                "/v1/me/episodes?offset=0",
+                page(vec![saved_episode("e1"), saved_episode("e2")]),
+            )
+            .on(
+                "GET",
+                "/v1/me/episodes?offset=2",
+                page(vec![saved_episode("e3"), saved_episode("e4")]),
+            )
+            .on(
+                "GET",
+                "/v1/me/episodes?offset=4",
+                page::<serde_json::Value>(vec![]),
+            );
+        spotify
+    }
+
+    #[test]
+    fn saved_episodes_pages_until_the_limit() {
+        let spotify = mock_episodes();
+        let episodes = saved_episodes(&spotify.client(), 3, 2).unwrap();
+
+        let names: Vec<&str> = episodes.iter().map(|e| e.episode.name.as_str()).collect();
+        assert_eq!(names, ["Episode e1", "Episode e2", "Episode e3"]);
+        assert_eq!(spotify.requests("GET", "/v1/me/episodes").len(), 2);
+    }
+
+    #[test]
+    fn saved_episodes_stops_at_the_last_page() {
+        let spotify = mock_episodes();
+        let episodes = saved_episodes(&spotify.client(), 10, 2).unwrap();
+
+        assert_eq!(episodes.len(), 4);
+        assert_eq!(spotify.requests("GET", "/v1/me/episodes").len(), 3);
+    }
+
+    #[test]
+    fn saved_episodes_cannot_be_mixed_with_tracks() {
+        let spotify = mock_episodes();
+        let args = SavedEpisodesArgs { limit: 2 };
+        let err = SavedEpisodes::execute(&spotify.client(), args, vec![]).unwrap_err();
+
+        assert!(
+            matches!(err, ComponentError::Unsupported { .. }),
+            "{:?}",
+            err
+        );
+    }
+}
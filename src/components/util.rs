@@ -0,0 +1,56 @@
+//! Helpers shared by the filters and combiners
+use rspotify::prelude::*;
+use std::{collections::HashSet, hash::Hash};
+
+use super::{Track, TrackList};
+
+/// Remove the items whose key has already been seen, keeping the first occurrence of each.
+///
+/// The result is always in first-occurrence order - the set is only used for membership, never
+/// iterated. Items without a key (`None`) can't be compared, so are always kept.
+pub fn dedup_by_key<T, K, F>(items: impl IntoIterator<Item = T>, mut key: F) -> Vec<T>
+where
+    K: Eq + Hash,
+    F: FnMut(&T) -> Option<K>,
+{
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter(|item| match key(item) {
+            Some(key) => seen.insert(key),
+            None => true,
+        })
+        .collect()
+}
+
+/// Remove repeats of the same track by id, see [`dedup_by_key`].
+/// Note: Tracks without an id are always kept
+pub fn dedup_tracks(tracks: impl IntoIterator<Item = Track>) -> TrackList {
+    dedup_by_key(tracks, |t| t.id.as_ref().map(|id| id.id().to_owned()))
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ids, track};
+
+    #[test]
+    fn dedup_keeps_first_occurrence_order() {
+        let mut local = track("local");
+        local.id = None;
+        let tracks = ["c", "a", "c", "b", "a", "d", "b"]
+            .iter()
+            .map(|id| track(id))
+            .chain([local.clone(), local])
+            .collect::<TrackList>();
+
+        let res = dedup_tracks(tracks);
+        // n.b. Tracks without an id are listed as ""
+        assert_eq!(ids(&res), ["c", "a", "b", "d", "", ""]);
+
+        let res = dedup_by_key(["bb", "a", "cc", "d", "eee"], |s| Some(s.len()));
+        assert_eq!(res, ["bb", "a", "eee"]);
+    }
+}
@@ -1,15 +1,26 @@
-///! The Controller takes the flow definetion as JSON, parses it, and runs the flow
+//! The Controller takes the flow definetion as JSON, parses it, and runs the flow
+use chrono::{DateTime, TimeZone, Utc};
+use rspotify::{
+    http::Query,
+    model::{FullTrack, Market},
+    prelude::*,
+    AuthCodeSpotify as Client, ClientError,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet},
+    env,
+    hash::{Hash, Hasher},
     sync::{Arc, RwLock},
     thread,
+    time::Instant,
 };
 use uuid::Uuid;
 
 use crate::{
-    components::{Component, NonExhaustive, TrackList},
-    error::Result,
+    components::{Component, ComponentError, ComponentKind, NonExhaustive, Track, TrackList},
+    error::{PublicError, Result},
+    metrics,
 };
 
 //
@@ -29,14 +40,184 @@ struct Constraint<T> {
 
 //
 
-pub type Cache = Arc<RwLock<HashMap<Uuid, TrackList>>>;
+pub type Cache = Arc<RwLock<Snapshots>>;
 pub type Batch = Vec<Uuid>;
 pub type Schedule = Vec<Batch>;
+/// CancelCheck is polled while a flow runs, returning true once the run should stop.
+pub type CancelCheck<'a> = dyn Fn() -> bool + Sync + 'a;
+/// Snapshots holds the output of each node in a run, keyed by node id.
+pub type Snapshots = HashMap<Uuid, NodeSnapshot>;
+
+/// NodeSnapshot is the output of a node, along with the hash of the node and its inputs.
+///
+/// The hash covers the node's component and arguments, and the hashes of its upstream nodes -
+/// so a change to any node changes the hash of every node downstream of it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NodeSnapshot {
+    pub hash: u64,
+    pub tracks: TrackList,
+}
+
+/// StoredSnapshots holds the compact form of each node's snapshot, kept between incremental runs.
+pub type StoredSnapshots = HashMap<Uuid, StoredSnapshot>;
+
+/// StoredSnapshot is a [`NodeSnapshot`] reduced to its hash and track ids, so even a large flow's
+/// snapshots fit in a single cache value. The tracks are fetched again when the node is reused.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StoredSnapshot {
+    pub hash: u64,
+    pub tracks: Vec<StoredTrack>,
+}
+
+/// StoredTrack is the id of a track in a [`StoredSnapshot`], along with when it was added, which
+/// isn't part of the track fetched from Spotify.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredTrack {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<DateTime<Utc>>,
+}
+
+/// Reduce the snapshots of a run to the form stored for the next incremental run.
+/// n.b. Nodes with a track that can't be fetched again (i.e. a local file) are left out, so run again
+pub fn store_snapshots(snapshots: &Snapshots) -> StoredSnapshots {
+    snapshots
+        .iter()
+        .filter_map(|(node_id, snapshot)| {
+            let tracks = snapshot
+                .tracks
+                .iter()
+                .map(|t| {
+                    Some(StoredTrack {
+                        id: t.id.as_ref()?.id().to_owned(),
+                        added_at: t.added_at,
+                    })
+                })
+                .collect::<Option<_>>()?;
+            Some((
+                *node_id,
+                StoredSnapshot {
+                    hash: snapshot.hash,
+                    tracks,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// The most tracks fetched per request when a stored snapshot is reused.
+const SNAPSHOT_TRACKS_CHUNK_SIZE: usize = 50;
+
+/// RunOptions control how a flow is run.
+#[derive(Clone, Copy)]
+pub struct RunOptions<'a> {
+    /// Checked before each batch and after each node, a running node isn't interrupted.
+    pub cancelled: &'a CancelCheck<'a>,
+    /// The stored snapshots from a previous run. Nodes whose hash is unchanged reuse their
+    /// previous output rather than running again.
+    pub previous: Option<&'a StoredSnapshots>,
+    /// Record which source node each track came from, see [`ExecutionReport::provenance`].
+    pub explain: bool,
+    /// The flow-level seed, see [`UserDefinedFlow::with_seed`].
+    pub seed: Option<u64>,
+}
+
+impl Default for RunOptions<'_> {
+    fn default() -> Self {
+        RunOptions {
+            cancelled: &never,
+            previous: None,
+            explain: false,
+            seed: None,
+        }
+    }
+}
+
+fn never() -> bool {
+    false
+}
+
+//
+
+const FLOW_MAX_NODES: usize = 100;
+const FLOW_MAX_EDGES: usize = 400;
+const FLOW_MAX_BATCH_WIDTH: usize = 16;
+
+/// FlowLimits bounds the size of a user-defined flow.
+///
+/// These are checked before (and after) building the schedule so a malicious or buggy client
+/// can't exhaust the server by submitting a flow with thousands of nodes/edges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlowLimits {
+    pub max_nodes: usize,
+    pub max_edges: usize,
+    /// The maximum number of nodes that may run in parallel within a single batch.
+    pub max_batch_width: usize,
+}
+
+impl Default for FlowLimits {
+    fn default() -> Self {
+        FlowLimits {
+            max_nodes: FLOW_MAX_NODES,
+            max_edges: FLOW_MAX_EDGES,
+            max_batch_width: FLOW_MAX_BATCH_WIDTH,
+        }
+    }
+}
+
+impl FlowLimits {
+    /// Build the limits from the `$SPL_FLOW_MAX_*` environment variables,
+    /// falling back to the defaults when they are unset or invalid.
+    pub fn from_env() -> Self {
+        let var = |key: &str, default: usize| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        FlowLimits {
+            max_nodes: var("SPL_FLOW_MAX_NODES", FLOW_MAX_NODES),
+            max_edges: var("SPL_FLOW_MAX_EDGES", FLOW_MAX_EDGES),
+            max_batch_width: var("SPL_FLOW_MAX_BATCH_WIDTH", FLOW_MAX_BATCH_WIDTH),
+        }
+    }
+}
 
 //
 
 pub type Edge = (uuid::Uuid, uuid::Uuid);
 
+/// ValidationError locates a problem in a flow definition, so the UI can highlight it.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ValidationError {
+    /// The offending node, or None for problems with the flow as a whole, e.g. its edges.
+    pub node_id: Option<Uuid>,
+    /// The path of the offending field within the node, e.g. `parameters.limit`.
+    pub field: Option<String>,
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn from_component(node_id: Uuid, err: &ComponentError) -> Self {
+        let (field, kind) = match err {
+            ComponentError::InvalidArgument { name, .. } => {
+                (Some(format!("parameters.{}", name)), "invalid_argument")
+            }
+            ComponentError::InvalidId { .. } => (None, "invalid_id"),
+            _ => (None, "invalid"),
+        };
+
+        ValidationError {
+            node_id: Some(node_id),
+            field,
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserDefinedFlow {
     pub nodes: HashMap<uuid::Uuid, NonExhaustive<Component>>,
@@ -48,7 +229,274 @@ impl UserDefinedFlow {
         todo!()
     }
 
-    fn build_schedule(&self) -> Result<Schedule> {
+    /// Verify the flow is within the given node/edge limits.
+    pub fn check_limits(&self, limits: &FlowLimits) -> Result<()> {
+        if self.nodes.len() > limits.max_nodes {
+            return Err(PublicError::bad_request(format!(
+                "Flow has {} nodes, the maximum allowed is {}",
+                self.nodes.len(),
+                limits.max_nodes
+            )));
+        }
+
+        if self.edges.len() > limits.max_edges {
+            return Err(PublicError::bad_request(format!(
+                "Flow has {} edges, the maximum allowed is {}",
+                self.edges.len(),
+                limits.max_edges
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Return the nodes whose component isn't known to this build, along with the component name.
+    ///
+    /// These are allowed when saving a flow, but will fail when the flow is executed.
+    pub fn unknown_components(&self) -> Vec<(Uuid, String)> {
+        let mut unknown: Vec<(Uuid, String)> = self
+            .nodes
+            .iter()
+            .filter_map(|(id, node)| match node {
+                NonExhaustive::Known(_) => None,
+                NonExhaustive::Unknown(value) => {
+                    let name = value["component"].as_str().unwrap_or("<missing>");
+                    Some((*id, name.to_owned()))
+                }
+            })
+            .collect();
+
+        // Sort to keep the output stable across HashMap iteration orders
+        unknown.sort();
+        unknown
+    }
+
+    /// Return the number of incoming and outgoing edges of every node.
+    /// n.b. Edges to nodes that aren't in the flow are ignored, `validate` rejects those
+    fn degrees(&self) -> HashMap<Uuid, (usize, usize)> {
+        let mut degrees: HashMap<Uuid, (usize, usize)> =
+            self.nodes.keys().map(|id| (*id, (0, 0))).collect();
+        for (lhs, rhs) in self.edges.iter() {
+            if let Some((_, outgoing)) = degrees.get_mut(lhs) {
+                *outgoing += 1;
+            }
+            if let Some((incoming, _)) = degrees.get_mut(rhs) {
+                *incoming += 1;
+            }
+        }
+        degrees
+    }
+
+    /// Return the nodes matching `filter` on their (incoming, outgoing) degree, sorted.
+    fn nodes_by_degree(&self, filter: impl Fn(usize, usize) -> bool) -> Vec<Uuid> {
+        let mut nodes: Vec<Uuid> = self
+            .degrees()
+            .into_iter()
+            .filter(|(_, (incoming, outgoing))| filter(*incoming, *outgoing))
+            .map(|(id, _)| id)
+            .collect();
+
+        // Sort to keep the output stable across HashMap iteration orders
+        nodes.sort();
+        nodes
+    }
+
+    /// Return the nodes that don't read from any other node, sorted.
+    pub fn sources(&self) -> Vec<Uuid> {
+        self.nodes_by_degree(|incoming, _| incoming == 0)
+    }
+
+    /// Return the nodes that no other node reads from, sorted.
+    pub fn sinks(&self) -> Vec<Uuid> {
+        self.nodes_by_degree(|_, outgoing| outgoing == 0)
+    }
+
+    /// Return the nodes where the flow's results land, sorted - every output node, along with
+    /// any other node that nothing reads from, see [`UserDefinedFlow::sinks`].
+    /// Note: Unknown components are still recognised as outputs by their name prefix
+    pub fn output_nodes(&self) -> Vec<Uuid> {
+        let sinks = self.sinks();
+        let mut outputs: Vec<Uuid> = self
+            .nodes
+            .iter()
+            .filter(|(id, node)| {
+                node_kind(node) == Some(ComponentKind::Output) || sinks.contains(id)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        outputs.sort();
+        outputs
+    }
+
+    /// Return the Spotify OAuth scopes needed to run every node of the flow, sorted.
+    /// Note: Unknown components can't declare any scopes, so add none
+    pub fn required_scopes(&self) -> BTreeSet<&'static str> {
+        self.nodes
+            .values()
+            .filter_map(|node| match node {
+                NonExhaustive::Known(c) => Some(c.required_scopes()),
+                NonExhaustive::Unknown(_) => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Copy the flow, replacing every node UUID with a new one, e.g. to clone or import a flow.
+    /// n.b. Every edge is rewritten with the same old -> new mapping, so the graph is unchanged
+    pub fn with_fresh_ids(&self) -> UserDefinedFlow {
+        // Edges to unknown nodes are rejected by `validate`, but may still be present here - their
+        // endpoints are mapped too, so every occurrence of an id gets the same new one
+        let endpoints = self.edges.iter().flat_map(|(lhs, rhs)| [lhs, rhs]);
+        let mut mapping: HashMap<Uuid, Uuid> = HashMap::new();
+        for id in self.nodes.keys().chain(endpoints) {
+            mapping.entry(*id).or_insert_with(Uuid::new_v4);
+        }
+        let remap = |id: &Uuid| mapping[id];
+
+        UserDefinedFlow {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(id, node)| (remap(id), node.clone()))
+                .collect(),
+            edges: self
+                .edges
+                .iter()
+                .map(|(lhs, rhs)| (remap(lhs), remap(rhs)))
+                .collect(),
+        }
+    }
+
+    /// Set the market of every node that takes one, but doesn't specify it.
+    /// Note: `Market::FromToken` is what the nodes fall back to anyway, so is a no-op
+    pub fn with_default_market(mut self, market: Market) -> Self {
+        let Market::Country(country) = market else {
+            return self;
+        };
+
+        for node in self.nodes.values_mut() {
+            if let NonExhaustive::Known(Component::ArtistTopTracks(args)) = node {
+                args.market.get_or_insert(country);
+            }
+        }
+        self
+    }
+
+    /// Remove every output node, along with its edges, so the flow can run without writing to
+    /// Spotify. The nodes that fed the outputs are left as the last nodes of the flow.
+    pub fn without_outputs(mut self) -> Self {
+        self.nodes
+            .retain(|_, node| node_kind(node) != Some(ComponentKind::Output));
+        let nodes = &self.nodes;
+        self.edges
+            .retain(|(lhs, rhs)| nodes.contains_key(lhs) && nodes.contains_key(rhs));
+        self
+    }
+
+    /// Set the time the flow last ran successfully on every node that only returns newer tracks.
+    pub fn with_last_run(mut self, last_run_at: Option<i64>) -> Self {
+        let last_run_at = last_run_at.and_then(|t| Utc.timestamp_opt(t, 0).single());
+        for node in self.nodes.values_mut() {
+            if let NonExhaustive::Known(Component::LikedSinceLastRun(args)) = node {
+                args.last_run_at = last_run_at;
+            }
+        }
+        self
+    }
+
+    /// Seed every randomized node that doesn't specify its own seed, so the same flow seed
+    /// always produces the same playlist.
+    /// Note: Each node's seed is derived from the flow seed and its id, so nodes don't share a seed
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        let Some(seed) = seed else {
+            return self;
+        };
+
+        for (id, node) in self.nodes.iter_mut() {
+            if let NonExhaustive::Known(component) = node {
+                let (hi, lo) = id.as_u64_pair();
+                component.seed_with(seed ^ hi ^ lo);
+            }
+        }
+        self
+    }
+
+    /// Validate and canonicalize the arguments of every known node, e.g. ids become spotify URIs.
+    ///
+    /// Returns PublicError::BadRequest naming the node and argument of the first invalid value,
+    /// rather than failing part way through a run.
+    pub fn normalize(&mut self) -> Result<()> {
+        // Sort to report the same node first across HashMap iteration orders
+        let mut nodes: Vec<(&Uuid, &mut NonExhaustive<Component>)> =
+            self.nodes.iter_mut().collect();
+        nodes.sort_by_key(|(id, _)| **id);
+
+        for (id, node) in nodes {
+            if let NonExhaustive::Known(component) = node {
+                component
+                    .normalize()
+                    .map_err(|err| PublicError::bad_request(format!("Node {}: {}", id, err)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check every node's arguments, and the flow as a whole, collecting all of the problems.
+    ///
+    /// Unlike [`Self::normalize`] and [`Self::validate`], this doesn't stop at the first problem,
+    /// returning PublicError::Invalid listing each of them instead.
+    pub fn check(&self, limits: &FlowLimits) -> Result<Schedule> {
+        let mut errors = Vec::new();
+        for (id, node) in self.nodes.iter() {
+            if let NonExhaustive::Known(component) = node {
+                if let Err(err) = component.clone().normalize() {
+                    errors.push(ValidationError::from_component(*id, &err));
+                }
+            }
+        }
+        // Sort to keep the output stable across HashMap iteration orders
+        errors.sort_by_key(|err| err.node_id);
+
+        match self.validate(limits) {
+            Ok(schedule) if errors.is_empty() => Ok(schedule),
+            Ok(_) => Err(PublicError::Invalid { errors }),
+            Err(err) => {
+                errors.push(ValidationError {
+                    node_id: None,
+                    field: None,
+                    kind: "invalid_flow",
+                    message: err.to_string(),
+                });
+                Err(PublicError::Invalid { errors })
+            }
+        }
+    }
+
+    /// Verify the flow is well-formed, returning the schedule it would run with.
+    pub fn validate(&self, limits: &FlowLimits) -> Result<Schedule> {
+        // Every edge must connect two known nodes -
+        // The scheduler assumes this holds.
+        for (lhs, rhs) in self.edges.iter() {
+            for id in [lhs, rhs] {
+                if !self.nodes.contains_key(id) {
+                    return Err(PublicError::bad_request(format!(
+                        "Edge [{}, {}] references unknown node:{}",
+                        lhs, rhs, id
+                    )));
+                }
+            }
+        }
+
+        self.build_schedule(limits)
+    }
+
+    fn build_schedule(&self, limits: &FlowLimits) -> Result<Schedule> {
+        // Refuse to allocate anything for oversized flows
+        self.check_limits(limits)?;
+
         let mut constraints = Vec::<Constraint<&Uuid>>::new();
         let mut domains = HashMap::<&Uuid, Vec<usize>>::new();
 
@@ -152,56 +600,390 @@ impl UserDefinedFlow {
 
         schedule.retain(|b| !b.is_empty());
 
+        // Each node in a batch is run on its own thread -
+        // Make sure we don't spawn more threads than allowed.
+        if let Some(batch) = schedule.iter().find(|b| b.len() > limits.max_batch_width) {
+            return Err(PublicError::bad_request(format!(
+                "Flow has {} nodes running in parallel, the maximum allowed is {}",
+                batch.len(),
+                limits.max_batch_width
+            )));
+        }
+
         Ok(schedule)
     }
 
     // --
 
-    pub fn execute(&self) -> Result<()> {
+    /// Run the flow, returning a report of the tracks produced by each node.
+    pub fn execute(&self, client: &Client) -> Result<ExecutionReport> {
+        let (report, _) = self.execute_with(client, RunOptions::default())?;
+        Ok(report)
+    }
+
+    /// Run the flow with the given options, returning a report of the tracks produced by each
+    /// node, along with the node snapshots to pass to the next incremental run.
+    pub fn execute_with(
+        &self,
+        client: &Client,
+        options: RunOptions,
+    ) -> Result<(ExecutionReport, Snapshots)> {
+        let snapshots = self.run_with(client, options)?;
+
+        let mut report = ExecutionReport::default();
+        let outputs = self.output_nodes();
+        for (id, snapshot) in snapshots.iter() {
+            let tracks = &snapshot.tracks;
+            report.tracks.insert(*id, tracks.len());
+
+            if let Some(previous) = options.previous.and_then(|p| p.get(id)) {
+                if previous.hash == snapshot.hash {
+                    report.reused.insert(*id);
+                }
+            }
+
+            if outputs.contains(id) {
+                report.outputs.insert(*id, tracks.len());
+            }
+
+            // Outputs return the tracks they wrote, anything else from their input was skipped
+            if let Some(NonExhaustive::Known(c)) = self.nodes.get(id) {
+                if c.kind() == ComponentKind::Output {
+                    let input = self
+                        .edges
+                        .iter()
+                        .find(|(_, rhs)| rhs == id)
+                        .and_then(|(lhs, _)| snapshots.get(lhs))
+                        .map_or(0, |s| s.tracks.len());
+                    report
+                        .skipped
+                        .insert(*id, input.saturating_sub(tracks.len()));
+                }
+            }
+        }
+
+        if options.explain {
+            let schedule = self.build_schedule(&FlowLimits::from_env())?;
+            report.provenance = self.provenance(&schedule, &snapshots);
+        }
+
+        Ok((report, snapshots))
+    }
+
+    /// Trace each node's output tracks back to the source nodes they came from.
+    ///
+    /// A source node (see [`UserDefinedFlow::sources`]) is the origin of all of its tracks, any
+    /// other node's tracks come from whichever of its inputs returned the same track. Tracks are matched by id, so those
+    /// without one (or whose id a node changed) have no recorded origin.
+    fn provenance(&self, schedule: &Schedule, snapshots: &Snapshots) -> Provenance {
+        let mut provenance = Provenance::new();
+        let sources = self.sources();
+        for node_id in schedule.iter().flatten() {
+            let Some(snapshot) = snapshots.get(node_id) else {
+                continue;
+            };
+            let inputs: Vec<&Uuid> = self
+                .edges
+                .iter()
+                .filter(|(_, rhs)| rhs == node_id)
+                .map(|(lhs, _)| lhs)
+                .collect();
+
+            let mut origins: HashMap<String, Vec<Uuid>> = HashMap::new();
+            for id in snapshot.tracks.iter().filter_map(|t| t.id.as_ref()) {
+                let id = id.id();
+                if origins.contains_key(id) {
+                    continue;
+                }
+
+                let origin = match sources.contains(node_id) {
+                    true => vec![*node_id],
+                    false => {
+                        let mut origin = Vec::new();
+                        for input in &inputs {
+                            let upstream = provenance.get(*input).and_then(|p| p.get(id));
+                            for source in upstream.into_iter().flatten() {
+                                if !origin.contains(source) {
+                                    origin.push(*source);
+                                }
+                            }
+                        }
+                        origin
+                    }
+                };
+                if !origin.is_empty() {
+                    origins.insert(id.to_owned(), origin);
+                }
+            }
+            provenance.insert(*node_id, origins);
+        }
+        provenance
+    }
+
+    /// Run the flow, returning the tracks produced by each node.
+    pub fn run(&self, client: &Client) -> Result<HashMap<Uuid, TrackList>> {
+        let snapshots = self.run_with(client, RunOptions::default())?;
+        Ok(snapshots
+            .into_iter()
+            .map(|(id, snapshot)| (id, snapshot.tracks))
+            .collect())
+    }
+
+    fn run_with(&self, client: &Client, options: RunOptions) -> Result<Snapshots> {
+        let started = Instant::now();
+        let res = self.run_batches(client, options);
+        metrics::global().record_flow_execution(res.is_ok(), started.elapsed());
+        res
+    }
+
+    fn run_batches(&self, client: &Client, options: RunOptions) -> Result<Snapshots> {
+        let schedule = self.validate(&FlowLimits::from_env())?;
+
+        // Catch bad arguments up front, before any node has run
+        let mut flow = self.clone().with_seed(options.seed);
+        flow.normalize()?;
+
         let cache = Cache::new(RwLock::new(HashMap::new()));
-        for batch in self.build_schedule()?.iter() {
-            self.execute_batch(batch, &cache)?;
+        for batch in schedule.iter() {
+            if (options.cancelled)() {
+                return Err(PublicError::Cancelled);
+            }
+            flow.execute_batch(client, batch, &cache, options)?;
         }
-        Ok(())
+
+        let results = std::mem::take(&mut *cache.write().unwrap());
+        Ok(results)
+    }
+
+    /// Run the flow, returning the tracks produced by its last node.
+    ///
+    /// Returns PublicError::BadRequest unless exactly one node has no outgoing edges.
+    pub fn run_to_end(&self, client: &Client) -> Result<TrackList> {
+        let ends = self.sinks();
+        let [end] = ends[..] else {
+            return Err(PublicError::bad_request(format!(
+                "Flow must end in exactly one node, found {}",
+                ends.len()
+            )));
+        };
+
+        let mut results = self.run(client)?;
+        Ok(results.remove(&end).unwrap_or_default())
     }
 
-    pub fn execute_batch(&self, batch: &Batch, cache: &Cache) -> Result<()> {
+    pub fn execute_batch(
+        &self,
+        client: &Client,
+        batch: &Batch,
+        cache: &Cache,
+        options: RunOptions,
+    ) -> Result<()> {
         thread::scope(|s| {
             let mut handles = Vec::new();
+            let mut reused = Vec::new();
 
             // Run each node in batch
             for node_id in batch.iter() {
                 let node = self.nodes.get(node_id).unwrap();
-                let result_cache = Arc::clone(&cache);
 
-                let h = s.spawn(move || {
-                    // Do some work 1..2..3..
-                    thread::sleep(std::time::Duration::from_millis(500));
-                    println!("{}", node.clone().unwrap().name());
+                // Collect the inputs from the upstream nodes, in edge order
+                // n.b. The schedule guarantees these have already run
+                let (hash, prev) = {
+                    let results = cache.read().unwrap();
+                    let upstream: Vec<Option<&NodeSnapshot>> = self
+                        .edges
+                        .iter()
+                        .filter(|(_, rhs)| rhs == node_id)
+                        .map(|(lhs, _)| results.get(lhs))
+                        .collect();
+
+                    let hash = node_hash(node, upstream.iter().map(|u| u.map_or(0, |u| u.hash)));
+                    let prev: Vec<TrackList> = upstream
+                        .into_iter()
+                        .map(|u| u.map(|u| u.tracks.clone()).unwrap_or_default())
+                        .collect();
+                    (hash, prev)
+                };
 
-                    // Push results to the cache
-                    result_cache.write().unwrap().insert(*node_id, Vec::new());
+                // Reuse the previous output when neither the node nor its inputs have changed
+                let previous = options.previous.and_then(|p| p.get(node_id));
+                // n.b. Nodes in a batch don't depend on each other, so this can't be seen early
+                if let Some(previous) = previous.filter(|p| p.hash == hash) {
+                    reused.push((*node_id, previous));
+                    continue;
+                }
+
+                let h = s.spawn(move || match node.clone() {
+                    NonExhaustive::Known(component) => {
+                        component.execute(client, prev).map_err(PublicError::from)
+                    }
+                    NonExhaustive::Unknown(value) => Err(PublicError::bad_request(format!(
+                        "Unknown component: {}",
+                        value["component"].as_str().unwrap_or("<missing>")
+                    ))),
                 });
 
-                handles.push(h);
+                handles.push((*node_id, hash, h));
             }
 
-            // Wait for all nodes in batch to complete
-            for h in handles {
-                h.join().unwrap();
+            // n.b. Fetched while the rest of the batch runs
+            rehydrate(client, cache, reused)?;
+
+            // Wait for all nodes in batch to complete, then push results to the cache
+            for (node_id, hash, h) in handles {
+                let tracks = h.join().map_err(|_| "Component panicked")??;
+                cache
+                    .write()
+                    .unwrap()
+                    .insert(node_id, NodeSnapshot { hash, tracks });
+
+                // n.b. Returning early still waits for the rest of the batch, when the scope ends
+                if (options.cancelled)() {
+                    return Err(PublicError::Cancelled);
+                }
             }
-        });
 
-        Ok(())
+            Ok(())
+        })
+    }
+}
+
+/// Rebuild the output of each reused node from its stored snapshot, adding it to the cache.
+///
+/// Tracks already in the cache (i.e. output by an upstream node) are copied, the rest are fetched.
+/// n.b. Tracks Spotify no longer returns are dropped, as they would be if the node ran again
+fn rehydrate(client: &Client, cache: &Cache, reused: Vec<(Uuid, &StoredSnapshot)>) -> Result<()> {
+    if reused.is_empty() {
+        return Ok(());
+    }
+
+    let mut known: HashMap<String, FullTrack> = HashMap::new();
+    for snapshot in cache.read().unwrap().values() {
+        for t in snapshot.tracks.iter() {
+            if let Some(id) = &t.id {
+                known.insert(id.id().to_owned(), t.track.clone());
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let missing: Vec<&str> = reused
+        .iter()
+        .flat_map(|(_, snapshot)| snapshot.tracks.iter())
+        .map(|t| t.id.as_str())
+        .filter(|id| !known.contains_key(*id) && seen.insert(*id))
+        .collect();
+
+    for chunk in missing.chunks(SNAPSHOT_TRACKS_CHUNK_SIZE) {
+        // n.b. We can't use `tracks` here - Spotify returns null for unknown ids, which fails to
+        // deserialize.
+        #[derive(Deserialize)]
+        struct Payload {
+            tracks: Vec<Option<FullTrack>>,
+        }
+
+        metrics::global().record_spotify_call("tracks");
+        let res = client
+            .api_get(&format!("tracks/?ids={}", chunk.join(",")), &Query::new())
+            .map_err(ComponentError::from)?;
+        let payload: Payload = serde_json::from_str(&res)
+            .map_err(|err| ComponentError::from(ClientError::from(err)))?;
+        for track in payload.tracks.into_iter().flatten() {
+            if let Some(id) = &track.id {
+                known.insert(id.id().to_owned(), track);
+            }
+        }
+    }
+
+    let mut cache = cache.write().unwrap();
+    for (node_id, snapshot) in reused {
+        let tracks = snapshot
+            .tracks
+            .iter()
+            .filter_map(|t| {
+                known.get(&t.id).map(|track| Track {
+                    track: track.clone(),
+                    added_at: t.added_at,
+                })
+            })
+            .collect();
+        cache.insert(
+            node_id,
+            NodeSnapshot {
+                hash: snapshot.hash,
+                tracks,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Hash a node's component and arguments, along with the hashes of its upstream nodes.
+/// n.b. The hash is only stable for a given build, so after an upgrade the stored snapshots
+/// stop matching and every node runs again (the stale snapshots expire with their TTL)
+fn node_hash(node: &NonExhaustive<Component>, upstream: impl Iterator<Item = u64>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(node)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    for hash in upstream {
+        hash.hash(&mut hasher);
     }
+    hasher.finish()
+}
+
+/// Return the kind of a node's component.
+/// Note: Unknown components are still recognised by their name prefix
+fn node_kind(node: &NonExhaustive<Component>) -> Option<ComponentKind> {
+    match node {
+        NonExhaustive::Known(c) => Some(c.kind()),
+        NonExhaustive::Unknown(value) => {
+            ComponentKind::from_name(value["component"].as_str().unwrap_or_default())
+        }
+    }
+}
+
+/// Provenance maps each node to the source nodes each of its tracks came from, by track id.
+pub type Provenance = HashMap<Uuid, HashMap<String, Vec<Uuid>>>;
+
+/// ExecutionReport summarises a completed flow run.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExecutionReport {
+    /// The number of tracks returned by each node.
+    pub tracks: HashMap<Uuid, usize>,
+    /// The number of tracks returned by every node where the flow's results land, a flow may have
+    /// several, see [`UserDefinedFlow::output_nodes`].
+    #[serde(default)]
+    pub outputs: HashMap<Uuid, usize>,
+    /// The number of tracks each output node couldn't write, e.g. local files.
+    pub skipped: HashMap<Uuid, usize>,
+    /// The nodes whose output was reused from the previous run, rather than run again.
+    #[serde(default)]
+    pub reused: HashSet<Uuid>,
+    /// Where each node's tracks came from, only recorded when run with `RunOptions::explain`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub provenance: Provenance,
 }
 
 // --
 
 #[cfg(test)]
 mod tests {
-    use super::{Schedule, UserDefinedFlow};
-    use std::{collections::HashSet, str::FromStr};
+    use super::{
+        store_snapshots, FlowLimits, RunOptions, Schedule, StoredSnapshots, UserDefinedFlow,
+    };
+    use crate::{
+        cache::CACHE_MAX_VALUE_BYTES,
+        components::NonExhaustive,
+        error::PublicError,
+        metrics,
+        testing::{ids, me, page, playlist, playlist_item, spotify::MockSpotify, track},
+    };
+    use rspotify::model::{Country, Market, PlaylistItem, SimplifiedPlaylist};
+    use std::{
+        collections::{HashMap, HashSet},
+        str::FromStr,
+    };
     use uuid::Uuid;
 
     const TEST_YAML: &str = r#"
@@ -242,6 +1024,21 @@ edges:
     - [5d83eaac-546e-41f8-b584-9558c037a90c, f0cb5d21-abad-4d11-9dbf-12855a01c463]
 "#;
 
+    #[test]
+    fn can_find_output_nodes() {
+        let mut flow: UserDefinedFlow = serde_yaml::from_str(TEST_YAML).unwrap();
+        let id = |s: &str| Uuid::from_str(s).unwrap();
+        let output = id("f0cb5d21-abad-4d11-9dbf-12855a01c463");
+        assert_eq!(flow.output_nodes(), [output]);
+
+        // Disconnecting the output leaves the node before it as a sink too
+        flow.edges.retain(|(_, rhs)| *rhs != output);
+        let dedup = id("5d83eaac-546e-41f8-b584-9558c037a90c");
+        let mut expected = vec![output, dedup];
+        expected.sort();
+        assert_eq!(flow.output_nodes(), expected);
+    }
+
     #[test]
     fn can_parse_user_defined_flow() {
         let flow: UserDefinedFlow = serde_yaml::from_str(&TEST_YAML).unwrap();
@@ -252,7 +1049,7 @@ edges:
     #[test]
     fn can_build_valid_schedule() {
         let flow: UserDefinedFlow = serde_yaml::from_str(&TEST_YAML).unwrap();
-        let schedule = flow.build_schedule().unwrap();
+        let schedule = flow.build_schedule(&FlowLimits::default()).unwrap();
 
         assert_batches(
             schedule,
@@ -266,8 +1063,877 @@ edges:
         );
     }
 
+    #[test]
+    fn can_enforce_node_limit() {
+        let limits = FlowLimits {
+            max_nodes: 3,
+            ..Default::default()
+        };
+
+        assert!(chain(3).build_schedule(&limits).is_ok());
+        assert_bad_request(chain(4).build_schedule(&limits).unwrap_err());
+    }
+
+    #[test]
+    fn can_enforce_edge_limit() {
+        let limits = FlowLimits {
+            max_edges: 3,
+            ..Default::default()
+        };
+
+        // A chain of N nodes has N-1 edges
+        assert!(chain(4).build_schedule(&limits).is_ok());
+        assert_bad_request(chain(5).build_schedule(&limits).unwrap_err());
+    }
+
+    #[test]
+    fn can_enforce_batch_width_limit() {
+        let limits = FlowLimits {
+            max_batch_width: 3,
+            ..Default::default()
+        };
+
+        // Nodes without edges all run in the same batch
+        assert!(parallel(3).build_schedule(&limits).is_ok());
+        assert_bad_request(parallel(4).build_schedule(&limits).unwrap_err());
+    }
+
     //
 
+    /// Build a flow of `n` nodes with no edges between them.
+    fn parallel(n: usize) -> UserDefinedFlow {
+        let nodes: HashMap<_, _> = (0..n)
+            .map(|_| {
+                let component = serde_json::json!({ "component": "filter:unknown" });
+                (Uuid::new_v4(), NonExhaustive::Unknown(component))
+            })
+            .collect();
+
+        UserDefinedFlow {
+            nodes,
+            edges: Vec::new(),
+        }
+    }
+
+    /// Build a flow of `n` nodes, each connected to the next.
+    fn chain(n: usize) -> UserDefinedFlow {
+        let mut flow = parallel(n);
+        let ids: Vec<Uuid> = flow.nodes.keys().cloned().collect();
+        flow.edges = ids.windows(2).map(|w| (w[0], w[1])).collect();
+        flow
+    }
+
+    /// Build a flow where one node feeds two others, which both feed a fourth.
+    /// Returns the flow, along with its [top, left, right, bottom] node ids.
+    fn diamond() -> (UserDefinedFlow, [Uuid; 4]) {
+        let mut flow = parallel(4);
+        let ids: Vec<Uuid> = flow.nodes.keys().cloned().collect();
+        let [top, left, right, bottom] = ids[..] else {
+            unreachable!()
+        };
+        flow.edges = vec![(top, left), (top, right), (left, bottom), (right, bottom)];
+        (flow, [top, left, right, bottom])
+    }
+
+    /// Build a flow of a two node chain, alongside a node connected to nothing.
+    /// Returns the flow, along with its [head, tail, lone] node ids.
+    fn disconnected() -> (UserDefinedFlow, [Uuid; 3]) {
+        let mut flow = parallel(3);
+        let ids: Vec<Uuid> = flow.nodes.keys().cloned().collect();
+        let [head, tail, lone] = ids[..] else {
+            unreachable!()
+        };
+        flow.edges = vec![(head, tail)];
+        (flow, [head, tail, lone])
+    }
+
+    #[test]
+    fn can_find_sources_and_sinks() {
+        let sorted = |mut ids: Vec<Uuid>| {
+            ids.sort();
+            ids
+        };
+
+        let (flow, [top, _, _, bottom]) = diamond();
+        assert_eq!(flow.sources(), [top]);
+        assert_eq!(flow.sinks(), [bottom]);
+
+        // A node connected to nothing is both a source and a sink
+        let (flow, [head, tail, lone]) = disconnected();
+        assert_eq!(flow.sources(), sorted(vec![head, lone]));
+        assert_eq!(flow.sinks(), sorted(vec![tail, lone]));
+    }
+
+    #[test]
+    fn can_collect_required_scopes() {
+        let flow: UserDefinedFlow = serde_yaml::from_str(TEST_YAML).unwrap();
+
+        assert_eq!(
+            flow.required_scopes().into_iter().collect::<Vec<_>>(),
+            [
+                "playlist-modify-private",
+                "playlist-modify-public",
+                "playlist-read-private",
+                "user-library-read",
+            ]
+        );
+    }
+
+    #[test]
+    fn can_list_unknown_components() {
+        let flow: UserDefinedFlow = serde_yaml::from_str(TEST_YAML).unwrap();
+        let mut names: Vec<String> = flow
+            .unknown_components()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        names.sort();
+
+        assert_eq!(names, ["combiner:zip", "filter:track_deduplication"]);
+    }
+
+    #[test]
+    fn can_reject_edges_to_unknown_nodes() {
+        let mut flow = chain(2);
+        flow.edges
+            .push((*flow.nodes.keys().next().unwrap(), Uuid::new_v4()));
+
+        assert_bad_request(flow.validate(&FlowLimits::default()).unwrap_err());
+    }
+
+    const RUN_YAML: &str = r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:my_playlists_matching
+        parameters:
+            pattern: mix
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 1
+            from: end
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#;
+
+    #[test]
+    fn can_execute_flow() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("p1", "Weekly Mix", "owner")]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks",
+                page::<PlaylistItem>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks?offset=0",
+                page(vec![playlist_item(track("a")), playlist_item(track("b"))]),
+            );
+
+        let executions = |name: &str| {
+            let snapshot = metrics::global().snapshot();
+            snapshot.components.get(name).map_or(0, |m| m.executions)
+        };
+        let before = [
+            executions("source:my_playlists_matching"),
+            executions("filter:take"),
+        ];
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(RUN_YAML).unwrap();
+        let report = flow.execute(&spotify.client()).unwrap();
+
+        let source = Uuid::from_str("da0e029b-7a25-424e-b031-fc1271e38069").unwrap();
+        let take = Uuid::from_str("587d87da-0b5b-4b89-a41b-63414b93235c").unwrap();
+        assert_eq!(report.tracks, HashMap::from([(source, 2), (take, 1)]));
+        // Without an output node, the flow's results land in its last node
+        assert_eq!(report.outputs, HashMap::from([(take, 1)]));
+
+        // Each component execution is counted
+        // n.b. The registry is shared with tests running in parallel, so only a lower bound holds
+        assert!(executions("source:my_playlists_matching") > before[0]);
+        assert!(executions("filter:take") > before[1]);
+    }
+
+    #[test]
+    fn can_report_every_output() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on(
+                "GET",
+                "/v1/artists/artistA/top-tracks",
+                serde_json::json!({ "tracks": [track("a1"), track("a2")] }),
+            )
+            .on(
+                "GET",
+                "/v1/artists/artistB/top-tracks",
+                serde_json::json!({ "tracks": [track("b1")] }),
+            )
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![
+                    playlist("out1", "First", "owner"),
+                    playlist("out2", "Second", "owner"),
+                ]),
+            )
+            .on("PUT", "/v1/playlists/out1/tracks", ())
+            .on("PUT", "/v1/playlists/out2/tracks", ());
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistA
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistB
+    377033c8-c36c-4f04-a716-5e1736f4dfdc:
+        component: combiner:round_robin
+        parameters: {}
+    f0cb5d21-abad-4d11-9dbf-12855a01c463:
+        component: output:overwrite
+        parameters:
+            by_name: First
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: output:overwrite
+        parameters:
+            by_name: Second
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+    - [b38547f9-22cc-47ab-94bb-da695ee3ac4b, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+    - [377033c8-c36c-4f04-a716-5e1736f4dfdc, f0cb5d21-abad-4d11-9dbf-12855a01c463]
+    - [377033c8-c36c-4f04-a716-5e1736f4dfdc, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#,
+        )
+        .unwrap();
+        let report = flow.execute(&spotify.client()).unwrap();
+
+        let id = |s: &str| Uuid::from_str(s).unwrap();
+        assert_eq!(
+            report.outputs,
+            HashMap::from([
+                (id("f0cb5d21-abad-4d11-9dbf-12855a01c463"), 3),
+                (id("587d87da-0b5b-4b89-a41b-63414b93235c"), 3),
+            ])
+        );
+        for playlist in ["out1", "out2"] {
+            let written = spotify.requests("PUT", &format!("/v1/playlists/{playlist}/tracks"));
+            assert_eq!(written.len(), 1, "{}", playlist);
+        }
+    }
+
+    #[test]
+    fn can_report_tracks_skipped_by_outputs() {
+        let mut local = track("local");
+        local.is_local = true;
+        let mut missing = track("missing");
+        missing.id = None;
+
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![
+                    playlist("p1", "Weekly Mix", "owner"),
+                    playlist("out", "Output", "owner"),
+                ]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks",
+                page::<PlaylistItem>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks?offset=0",
+                page(vec![
+                    playlist_item(track("a")),
+                    playlist_item(local),
+                    playlist_item(missing),
+                    playlist_item(track("b")),
+                ]),
+            )
+            .on("PUT", "/v1/playlists/out/tracks", ());
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:my_playlists_matching
+        parameters:
+            pattern: mix
+    f0cb5d21-abad-4d11-9dbf-12855a01c463:
+        component: output:overwrite
+        parameters:
+            by_name: Output
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, f0cb5d21-abad-4d11-9dbf-12855a01c463]
+"#,
+        )
+        .unwrap();
+        let report = flow.execute(&spotify.client()).unwrap();
+
+        let output = Uuid::from_str("f0cb5d21-abad-4d11-9dbf-12855a01c463").unwrap();
+        assert_eq!(report.tracks[&output], 2);
+        assert_eq!(report.skipped, HashMap::from([(output, 2)]));
+
+        let written = spotify.requests("PUT", "/v1/playlists/out/tracks");
+        assert_eq!(
+            written[0].json()["uris"],
+            serde_json::json!(["spotify:track:a", "spotify:track:b"])
+        );
+    }
+
+    #[test]
+    fn cancelling_stops_later_batches() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("p1", "Weekly Mix", "owner")]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks",
+                page::<PlaylistItem>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks?offset=0",
+                page(vec![playlist_item(track("a"))]),
+            );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:my_playlists_matching
+        parameters:
+            pattern: mix
+    f0cb5d21-abad-4d11-9dbf-12855a01c463:
+        component: output:overwrite
+        parameters:
+            by_name: Output
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, f0cb5d21-abad-4d11-9dbf-12855a01c463]
+"#,
+        )
+        .unwrap();
+
+        // Cancel as soon as the source has fetched its tracks
+        let cancelled = || {
+            !spotify
+                .requests("GET", "/v1/playlists/p1/tracks")
+                .is_empty()
+        };
+        let options = RunOptions {
+            cancelled: &cancelled,
+            ..Default::default()
+        };
+        let res = flow.execute_with(&spotify.client(), options);
+
+        assert!(matches!(res, Err(PublicError::Cancelled)));
+        // The output never ran, so never looked up its playlist (only the source looked up the user)
+        assert_eq!(spotify.requests("GET", "/v1/me/").len(), 1);
+    }
+
+    #[test]
+    fn incremental_run_only_reruns_changed_nodes() {
+        let spotify = MockSpotify::start();
+        for id in ["artistA", "artistB", "artistC"] {
+            spotify.on(
+                "GET",
+                &format!("/v1/artists/{id}/top-tracks"),
+                serde_json::json!({ "tracks": [track(&format!("{id}1"))] }),
+            );
+        }
+        // Reused sources fetch their stored tracks again
+        spotify.on(
+            "GET",
+            "/v1/tracks/",
+            serde_json::json!({ "tracks": [track("artistA1"), track("artistB1")] }),
+        );
+        let fetches = |id: &str| {
+            spotify
+                .requests("GET", &format!("/v1/artists/{id}/top-tracks"))
+                .len()
+        };
+
+        let yaml = |artist: &str| {
+            format!(
+                r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: {artist}
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistB
+    377033c8-c36c-4f04-a716-5e1736f4dfdc:
+        component: combiner:round_robin
+        parameters: {{}}
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 1
+            from: start
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+    - [b38547f9-22cc-47ab-94bb-da695ee3ac4b, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+    - [377033c8-c36c-4f04-a716-5e1736f4dfdc, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#
+            )
+        };
+        let run = |artist: &str, previous: &StoredSnapshots| {
+            let flow: UserDefinedFlow = serde_yaml::from_str(&yaml(artist)).unwrap();
+            let options = RunOptions {
+                previous: Some(previous),
+                ..Default::default()
+            };
+            let (report, snapshots) = flow.execute_with(&spotify.client(), options).unwrap();
+            (report, store_snapshots(&snapshots), snapshots)
+        };
+        let id = |s: &str| Uuid::from_str(s).unwrap();
+        let (source_a, source_b) = (
+            id("da0e029b-7a25-424e-b031-fc1271e38069"),
+            id("b38547f9-22cc-47ab-94bb-da695ee3ac4b"),
+        );
+
+        // The first run has nothing to reuse
+        let (report, stored, _) = run("artistA", &StoredSnapshots::new());
+        assert!(report.reused.is_empty());
+        assert_eq!((fetches("artistA"), fetches("artistB")), (1, 1));
+
+        // An unchanged flow reuses every node, only fetching the source tracks once
+        let (report, stored, snapshots) = run("artistA", &stored);
+        assert_eq!(report.reused.len(), 4);
+        assert_eq!((fetches("artistA"), fetches("artistB")), (1, 1));
+        assert_eq!(spotify.requests("GET", "/v1/tracks/").len(), 1);
+        assert_eq!(ids(&snapshots[&source_b].tracks), ["artistB1"]);
+
+        // Changing one source reruns it and everything downstream of it, but not its sibling
+        let (report, _, snapshots) = run("artistC", &stored);
+        assert_eq!(report.reused, HashSet::from([source_b]));
+        assert_eq!((fetches("artistC"), fetches("artistB")), (1, 1));
+        assert_eq!(ids(&snapshots[&source_a].tracks), ["artistC1"]);
+        assert_eq!(ids(&snapshots[&source_b].tracks), ["artistB1"]);
+    }
+
+    #[test]
+    fn incremental_run_reuses_snapshots_over_the_cache_limit() {
+        let spotify = MockSpotify::start();
+        let tracks: Vec<_> = (0..2000).map(|i| track(&format!("t{i}"))).collect();
+        spotify
+            .on(
+                "GET",
+                "/v1/artists/artistA/top-tracks",
+                serde_json::json!({ "tracks": tracks }),
+            )
+            .on(
+                "GET",
+                "/v1/tracks/",
+                serde_json::json!({ "tracks": tracks }),
+            );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistA
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 1000
+            from: start
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#,
+        )
+        .unwrap();
+
+        let (_, snapshots) = flow
+            .execute_with(&spotify.client(), RunOptions::default())
+            .unwrap();
+        let stored = store_snapshots(&snapshots);
+        assert!(serde_json::to_string(&snapshots).unwrap().len() > CACHE_MAX_VALUE_BYTES);
+        assert!(serde_json::to_string(&stored).unwrap().len() < CACHE_MAX_VALUE_BYTES);
+
+        let options = RunOptions {
+            previous: Some(&stored),
+            ..Default::default()
+        };
+        let (report, reused) = flow.execute_with(&spotify.client(), options).unwrap();
+        assert_eq!(report.reused.len(), 2);
+        assert_eq!(
+            spotify
+                .requests("GET", "/v1/artists/artistA/top-tracks")
+                .len(),
+            1
+        );
+        for (id, snapshot) in snapshots.iter() {
+            assert_eq!(ids(&reused[id].tracks), ids(&snapshot.tracks));
+        }
+    }
+
+    #[test]
+    fn explain_records_where_tracks_came_from() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on(
+                "GET",
+                "/v1/artists/artistA/top-tracks",
+                serde_json::json!({ "tracks": [track("a"), track("both")] }),
+            )
+            .on(
+                "GET",
+                "/v1/artists/artistB/top-tracks",
+                serde_json::json!({ "tracks": [track("both"), track("b")] }),
+            );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistA
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistB
+    377033c8-c36c-4f04-a716-5e1736f4dfdc:
+        component: combiner:shuffle_union
+        parameters:
+            seed: 1
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+    - [b38547f9-22cc-47ab-94bb-da695ee3ac4b, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+"#,
+        )
+        .unwrap();
+        let id = |s: &str| Uuid::from_str(s).unwrap();
+        let (source_a, source_b, union) = (
+            id("da0e029b-7a25-424e-b031-fc1271e38069"),
+            id("b38547f9-22cc-47ab-94bb-da695ee3ac4b"),
+            id("377033c8-c36c-4f04-a716-5e1736f4dfdc"),
+        );
+
+        let options = RunOptions {
+            explain: true,
+            ..Default::default()
+        };
+        let (report, _) = flow.execute_with(&spotify.client(), options).unwrap();
+        let origins = &report.provenance[&union];
+        assert_eq!(origins.len(), 3);
+        assert_eq!(origins["a"], [source_a]);
+        assert_eq!(origins["b"], [source_b]);
+        assert_eq!(origins["both"], [source_a, source_b]);
+
+        // Provenance is only recorded on request
+        let report = flow.execute(&spotify.client()).unwrap();
+        assert!(report.provenance.is_empty());
+    }
+
+    #[test]
+    fn flow_seed_reproduces_the_same_output() {
+        let spotify = MockSpotify::start();
+        let tracks: Vec<_> = (0..20).map(|i| track(&format!("t{i}"))).collect();
+        spotify.on(
+            "GET",
+            "/v1/artists/artistA/top-tracks",
+            serde_json::json!({ "tracks": tracks }),
+        );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistA
+    377033c8-c36c-4f04-a716-5e1736f4dfdc:
+        component: combiner:shuffle_union
+        parameters: {}
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 377033c8-c36c-4f04-a716-5e1736f4dfdc]
+"#,
+        )
+        .unwrap();
+        let union = Uuid::from_str("377033c8-c36c-4f04-a716-5e1736f4dfdc").unwrap();
+        let run = |seed| {
+            let options = RunOptions {
+                seed,
+                ..Default::default()
+            };
+            let (_, snapshots) = flow.execute_with(&spotify.client(), options).unwrap();
+            ids(&snapshots[&union].tracks)
+        };
+
+        assert_eq!(run(Some(42)), run(Some(42)));
+        assert_ne!(run(Some(42)), run(Some(43)));
+
+        // A seed set on the node wins over the flow seed
+        let seeded = flow.clone().with_seed(Some(42));
+        let own = |flow: &UserDefinedFlow| serde_json::to_value(&flow.nodes[&union]).unwrap();
+        assert!(own(&seeded)["parameters"]["seed"].is_u64());
+        assert_eq!(own(&seeded.clone().with_seed(Some(43))), own(&seeded));
+    }
+
+    #[test]
+    fn can_run_flow_to_end() {
+        let spotify = MockSpotify::start();
+        spotify
+            .on("GET", "/v1/me/", me("owner"))
+            .on(
+                "GET",
+                "/v1/me/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/me/playlists?offset=0",
+                page(vec![playlist("p1", "Weekly Mix", "owner")]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks",
+                page::<PlaylistItem>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/playlists/p1/tracks?offset=0",
+                page(vec![playlist_item(track("a")), playlist_item(track("b"))]),
+            );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(RUN_YAML).unwrap();
+        let tracks = flow.run_to_end(&spotify.client()).unwrap();
+
+        assert_eq!(ids(&tracks), ["b"]);
+    }
+
+    #[test]
+    fn can_run_to_end_without_outputs() {
+        let spotify = MockSpotify::start();
+        spotify.on(
+            "GET",
+            "/v1/artists/artistA/top-tracks",
+            serde_json::json!({ "tracks": [track("a"), track("b")] }),
+        );
+
+        let flow: UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:artist_top_tracks
+        parameters:
+            id: artistA
+    f0cb5d21-abad-4d11-9dbf-12855a01c463:
+        component: output:overwrite
+        parameters:
+            by_name: Output
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, f0cb5d21-abad-4d11-9dbf-12855a01c463]
+"#,
+        )
+        .unwrap();
+        let flow = flow.without_outputs();
+        assert_eq!(flow.nodes.len(), 1);
+        assert!(flow.edges.is_empty());
+
+        let tracks = flow.run_to_end(&spotify.client()).unwrap();
+        assert_eq!(ids(&tracks), ["a", "b"]);
+        // The output never ran, so never looked up its playlist
+        assert!(spotify.requests("GET", "/v1/me/").is_empty());
+    }
+
+    #[test]
+    fn cannot_run_to_end_with_several_last_nodes() {
+        let err = parallel(2)
+            .run_to_end(&MockSpotify::start().client())
+            .unwrap_err();
+
+        assert_bad_request(err);
+    }
+
+    #[test]
+    fn cannot_execute_unknown_components() {
+        let err = chain(2)
+            .execute(&MockSpotify::start().client())
+            .unwrap_err();
+
+        assert_bad_request(err);
+    }
+
+    fn artist_flow(id: &str) -> UserDefinedFlow {
+        serde_yaml::from_str(&format!(
+            r#"
+nodes:
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:artist_top_tracks
+        parameters:
+            id: "{id}"
+edges: []
+"#
+        ))
+        .unwrap()
+    }
+
+    fn artist_id(flow: &UserDefinedFlow) -> serde_json::Value {
+        let node = Uuid::from_str("b38547f9-22cc-47ab-94bb-da695ee3ac4b").unwrap();
+        serde_json::to_value(&flow.nodes[&node]).unwrap()["parameters"]["id"].clone()
+    }
+
+    #[test]
+    fn normalize_keeps_valid_uri() {
+        let mut flow = artist_flow("spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH");
+        flow.normalize().unwrap();
+
+        assert_eq!(artist_id(&flow), "spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH");
+    }
+
+    #[test]
+    fn normalize_converts_bare_id_to_uri() {
+        let mut flow = artist_flow("6qqNVTkY8uBg9cP3Jd7DAH");
+        flow.normalize().unwrap();
+
+        assert_eq!(artist_id(&flow), "spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH");
+    }
+
+    #[test]
+    fn normalize_rejects_malformed_id() {
+        let err = artist_flow("spotify:album:not an id")
+            .normalize()
+            .unwrap_err();
+
+        let PublicError::BadRequest { message } = err else {
+            panic!("expected BadRequest, found {:?}", err);
+        };
+        assert_eq!(
+            message,
+            "Node b38547f9-22cc-47ab-94bb-da695ee3ac4b: Invalid id: spotify:album:not an id is not a valid id or URI"
+        );
+    }
+
+    #[test]
+    fn fresh_ids_keep_the_graph() {
+        let flow: UserDefinedFlow = serde_yaml::from_str(TEST_YAML).unwrap();
+        let remapped = flow.with_fresh_ids();
+
+        let old_ids: HashSet<Uuid> = flow.nodes.keys().copied().collect();
+        assert_eq!(remapped.nodes.len(), flow.nodes.len());
+        assert!(remapped.nodes.keys().all(|id| !old_ids.contains(id)));
+
+        // Follow each edge on both sides - the same components must be connected
+        let component = |flow: &UserDefinedFlow, id: &Uuid| {
+            serde_json::to_value(&flow.nodes[id]).unwrap()["component"].clone()
+        };
+        let edges = |flow: &UserDefinedFlow| {
+            let mut edges: Vec<String> = flow
+                .edges
+                .iter()
+                .map(|(lhs, rhs)| format!("{} -> {}", component(flow, lhs), component(flow, rhs)))
+                .collect();
+            edges.sort();
+            edges
+        };
+        assert_eq!(edges(&remapped), edges(&flow));
+        for (lhs, rhs) in remapped.edges.iter() {
+            assert!(!old_ids.contains(lhs) && !old_ids.contains(rhs));
+        }
+
+        // Map each node to its component, and its number of incoming and outgoing edges
+        let degrees = |flow: &UserDefinedFlow| {
+            let mut degrees: Vec<(String, usize, usize)> = flow
+                .nodes
+                .iter()
+                .map(|(id, node)| {
+                    let component = serde_json::to_value(node).unwrap()["component"].to_string();
+                    let incoming = flow.edges.iter().filter(|(_, rhs)| rhs == id).count();
+                    let outgoing = flow.edges.iter().filter(|(lhs, _)| lhs == id).count();
+                    (component, incoming, outgoing)
+                })
+                .collect();
+            degrees.sort();
+            degrees
+        };
+
+        assert_eq!(degrees(&remapped), degrees(&flow));
+
+        // An edge to an unknown node keeps pointing at a single (fresh) id
+        let mut dangling = flow.clone();
+        let unknown = Uuid::new_v4();
+        let node = *flow.nodes.keys().next().unwrap();
+        dangling.edges = vec![(node, unknown), (unknown, node)];
+        let remapped = dangling.with_fresh_ids();
+        let [(_, a), (b, _)] = remapped.edges[..] else {
+            panic!("expected two edges");
+        };
+        assert_eq!(a, b);
+        assert_ne!(a, unknown);
+        assert!(!remapped.nodes.contains_key(&a));
+    }
+
+    #[test]
+    fn default_market_fills_in_unset_markets() {
+        let node = Uuid::from_str("b38547f9-22cc-47ab-94bb-da695ee3ac4b").unwrap();
+        let market = |flow: &UserDefinedFlow| {
+            serde_json::to_value(&flow.nodes[&node]).unwrap()["parameters"]["market"].clone()
+        };
+
+        let flow = artist_flow("artist").with_default_market(Market::FromToken);
+        assert_eq!(market(&flow), serde_json::Value::Null);
+
+        let flow = artist_flow("artist").with_default_market(Market::Country(Country::Sweden));
+        assert_eq!(market(&flow), "SE");
+
+        // A market set on the node wins over the default
+        let flow = flow.with_default_market(Market::Country(Country::Norway));
+        assert_eq!(market(&flow), "SE");
+    }
+
+    fn assert_bad_request(err: PublicError) {
+        assert!(matches!(err, PublicError::BadRequest { .. }), "{:?}", err);
+    }
+
     fn assert_batches(schedule: Schedule, expected: &[&str]) {
         for (i, batch) in schedule.iter().enumerate() {
             let expected_nodes: HashSet<Uuid> = expected[i]
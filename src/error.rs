@@ -1,4 +1,4 @@
-use crate::cache;
+use crate::{cache, components::ComponentError, controller::ValidationError};
 use actix_web::{
     http::{header::ContentType, StatusCode},
     HttpResponse,
@@ -12,33 +12,88 @@ pub type Result<T> = std::result::Result<T, PublicError>;
 #[derive(Debug, Display, Error)]
 pub enum PublicError {
     #[display(fmt = "An internal error occurred. Please try again later.")]
-    InternalError { inner: Box<dyn std::error::Error> },
+    InternalError {
+        inner: Box<dyn std::error::Error + Send + Sync>,
+    },
     #[display(fmt = "Unauthorized. You are not allowed to access that resource.")]
     Unauthorized,
+    #[display(fmt = "Not found. That resource does not exist.")]
+    NotFound,
+    #[display(fmt = "Bad request. {}", message)]
+    BadRequest { message: String },
+    #[display(
+        fmt = "Bad request. The flow has {} validation error(s).",
+        "errors.len()"
+    )]
+    Invalid {
+        #[error(not(source))]
+        errors: Vec<ValidationError>,
+    },
+    #[display(fmt = "Locked. Another flow is already running, please try again later.")]
+    Locked,
+    #[display(fmt = "Cancelled. The flow run was cancelled before it finished.")]
+    Cancelled,
+    #[display(fmt = "Unavailable. The server is shutting down, please try again later.")]
+    ShuttingDown,
+}
+
+impl PublicError {
+    /// Build a PublicError::BadRequest with the given message.
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        PublicError::BadRequest {
+            message: message.into(),
+        }
+    }
 }
 
 impl actix_web::error::ResponseError for PublicError {
     /// Override the default HTML response to return
     /// a JSON object.
     fn error_response(&self) -> HttpResponse {
+        let mut body = serde_json::json!({
+            "status": "error",
+            "code": self.status_code().as_u16(),
+            "message": self.to_string(),
+        });
+
+        // Locate every problem, so the UI can highlight the offending nodes and fields
+        if let PublicError::Invalid { errors } = self {
+            body["errors"] = serde_json::json!(errors);
+        }
+
         HttpResponse::build(self.status_code())
             .insert_header(ContentType::json())
-            .body(format!(
-                r#"{{"status": "error", "code": {}, "message": "{}"}}"#,
-                self.status_code().as_u16(),
-                self
-            ))
+            .body(body.to_string())
     }
 
     // Map the error to an HTTP status code
     fn status_code(&self) -> StatusCode {
         match *self {
             PublicError::Unauthorized => StatusCode::UNAUTHORIZED, // 401
+            PublicError::NotFound => StatusCode::NOT_FOUND,        // 404
+            PublicError::BadRequest { message: _ } => StatusCode::BAD_REQUEST, // 400
+            PublicError::Invalid { errors: _ } => StatusCode::BAD_REQUEST, // 400
+            PublicError::Locked => StatusCode::LOCKED,             // 423
+            PublicError::Cancelled => StatusCode::CONFLICT,        // 409
+            PublicError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE, // 503
             PublicError::InternalError { inner: _ } => StatusCode::INTERNAL_SERVER_ERROR, // 500
         }
     }
 }
 
+impl From<ComponentError> for PublicError {
+    fn from(err: ComponentError) -> Self {
+        match err {
+            // Problems with the flow itself, the user can fix these
+            ComponentError::InvalidId { .. }
+            | ComponentError::InvalidArgument { .. }
+            | ComponentError::Unsupported { .. }
+            | ComponentError::NotFound => PublicError::bad_request(err.to_string()),
+            ComponentError::Upstream(inner) => PublicError::from(inner),
+        }
+    }
+}
+
 //
 
 macro_rules! map_internal_error {
@@ -61,7 +116,9 @@ map_internal_error![
     rspotify::ClientError,
     sqlx::Error,
     serde_json::Error,
+    serde_yaml::Error,
     cache::Error,
+    csv::Error,
     // Map string types to internal error
     // USAGE:
     //     call_will_fail().map_err(|_| "Oh no! This call has failed")?
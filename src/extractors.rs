@@ -0,0 +1,217 @@
+//! Request extractors and responders shared by the API handlers
+use actix_web::{
+    body::BoxBody,
+    dev::Payload,
+    http::header::{self, HeaderName},
+    web, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{PublicError, Result};
+
+/// The serialization formats supported by [`YamlOrJson`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// Pick the format from the given request header, defaulting to JSON.
+    ///
+    /// E.g. `Content-Type: application/yaml` or `Accept: text/yaml` select YAML.
+    fn from_header(req: &HttpRequest, name: HeaderName) -> Self {
+        let value = req
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        if value.contains("yaml") {
+            Format::Yaml
+        } else {
+            Format::Json
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml",
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, body: &[u8]) -> Result<T> {
+        match self {
+            Format::Json => serde_json::from_slice(body)
+                .map_err(|err| PublicError::bad_request(format!("Invalid JSON body: {}", err))),
+            Format::Yaml => serde_yaml::from_slice(body)
+                .map_err(|err| PublicError::bad_request(format!("Invalid YAML body: {}", err))),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        match self {
+            Format::Json => Ok(serde_json::to_string(value)?),
+            Format::Yaml => Ok(serde_yaml::to_string(value)?),
+        }
+    }
+}
+
+/// YamlOrJson extracts a request body as either YAML or JSON, based on the `Content-Type`
+/// header. When used as a responder, the body is serialized based on the `Accept` header.
+///
+/// Power users version-control their flows as YAML, while the web UI speaks JSON.
+///
+/// **Example**
+///
+/// ```rust
+/// #[post("/api/v1/flows/validate")]
+/// async fn handler(flow: YamlOrJson<UserDefinedFlow>) -> Result<impl Responder> {
+///     Ok(YamlOrJson(flow.into_inner()))
+/// }
+/// ```
+pub struct YamlOrJson<T>(pub T);
+
+impl<T> YamlOrJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for YamlOrJson<T> {
+    type Error = PublicError;
+    type Future = LocalBoxFuture<'static, Result<Self>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = Format::from_header(req, header::CONTENT_TYPE);
+        let body = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let body = body
+                .await
+                .map_err(|err| PublicError::bad_request(err.to_string()))?;
+
+            format.deserialize(&body).map(YamlOrJson)
+        })
+    }
+}
+
+impl<T: Serialize> Responder for YamlOrJson<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let format = Format::from_header(req, header::ACCEPT);
+
+        match format.serialize(&self.0) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(format.content_type())
+                .body(body),
+            Err(err) => err.error_response(),
+        }
+    }
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::UserDefinedFlow;
+    use actix_web::{body::to_bytes, test::TestRequest};
+
+    const FLOW_YAML: &str = r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters:
+            limit: 75
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 25
+            from: start
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#;
+
+    const FLOW_JSON: &str = r#"{
+        "nodes": {
+            "da0e029b-7a25-424e-b031-fc1271e38069": {
+                "component": "source:user_liked_tracks",
+                "parameters": { "limit": 75 }
+            },
+            "587d87da-0b5b-4b89-a41b-63414b93235c": {
+                "component": "filter:take",
+                "parameters": { "limit": 25, "from": "start" }
+            }
+        },
+        "edges": [
+            ["da0e029b-7a25-424e-b031-fc1271e38069", "587d87da-0b5b-4b89-a41b-63414b93235c"]
+        ]
+    }"#;
+
+    async fn extract(content_type: &str, body: &'static str) -> Result<UserDefinedFlow> {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_http_parts();
+
+        YamlOrJson::<UserDefinedFlow>::from_request(&req, &mut payload)
+            .await
+            .map(YamlOrJson::into_inner)
+    }
+
+    #[actix_web::test]
+    async fn can_extract_yaml_and_json_flows() {
+        let from_yaml = extract("application/yaml", FLOW_YAML).await.unwrap();
+        let from_json = extract("application/json", FLOW_JSON).await.unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&from_yaml).unwrap(),
+            serde_json::to_value(&from_json).unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn defaults_to_json_without_content_type() {
+        let (req, mut payload) = TestRequest::post().set_payload(FLOW_JSON).to_http_parts();
+
+        assert!(
+            YamlOrJson::<UserDefinedFlow>::from_request(&req, &mut payload)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_malformed_body() {
+        let err = extract("application/json", FLOW_YAML).await.unwrap_err();
+
+        assert!(matches!(err, PublicError::BadRequest { .. }));
+    }
+
+    #[actix_web::test]
+    async fn can_respond_based_on_accept_header() {
+        let value = serde_json::json!({ "limit": 25 });
+
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, "application/yaml"))
+            .to_http_request();
+        let res = YamlOrJson(value.clone()).respond_to(&req);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/yaml"
+        );
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "limit: 25\n");
+
+        let req = TestRequest::default().to_http_request();
+        let res = YamlOrJson(value).respond_to(&req);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), r#"{"limit":25}"#);
+    }
+}
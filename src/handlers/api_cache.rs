@@ -0,0 +1,76 @@
+use actix_session::Session;
+use actix_web::{post, web, Responder};
+use serde::Serialize;
+
+use crate::{cache, error::PublicError, macros, ApplicationState};
+
+#[derive(Serialize)]
+pub struct FlushCacheResponse {
+    deleted: usize,
+}
+
+/// Delete everything cached for the current user, forcing a clean re-fetch from Spotify.
+///
+/// n.b. Run locks are not namespaced to the user, so a flush can't unlock a running flow.
+#[post("/api/v1/cache/flush")]
+pub async fn api_v1_cache_flush(
+    session: Session,
+    app: web::Data<ApplicationState>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let deleted = cache::flush_user(&app.cache, &user_id).await?;
+
+    Ok(web::Json(FlushCacheResponse { deleted }))
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache, testing};
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn can_flush_only_current_users_cache() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let other = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_cache_flush),
+        )
+        .await;
+
+        for user_id in [&user.id, &other.id] {
+            let key = cache::user_key(user_id, "playlists");
+            cache::get_or_create_with_stats(&state.cache, &key, 60, false, || Ok(1))
+                .await
+                .unwrap();
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/cache/flush")
+            .cookie(testing::login(&app, &user).await)
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["deleted"], 1);
+
+        // The callback only runs when the key is missing
+        let cached = |user_id: &str| {
+            let key = cache::user_key(user_id, "playlists");
+            let state = state.clone();
+            async move {
+                let (res, _) =
+                    cache::get_or_create_with_stats(&state.cache, &key, 60, false, || Ok(2))
+                        .await
+                        .unwrap();
+                res
+            }
+        };
+        assert_eq!(cached(&user.id).await, 2);
+        assert_eq!(cached(&other.id).await, 1);
+    }
+}
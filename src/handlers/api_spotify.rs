@@ -1,13 +1,25 @@
 use actix_session::Session;
 use actix_web::{get, web, Responder};
-use rspotify::{model::SimplifiedPlaylist, prelude::*};
+use rspotify::{
+    model::{PlaylistId, SimplifiedPlaylist, UserId},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::{cache, error::PublicError, macros, models::User, spotify, ApplicationState};
+use crate::{cache, error::PublicError, macros, metrics, models::User, ApplicationState};
+
+#[derive(Deserialize)]
+pub struct RefreshParams {
+    /// Bypass (and replace) the cached value, e.g. after creating a playlist.
+    #[serde(default)]
+    pub refresh: bool,
+}
 
 #[get("/api/v1/spotify/user_playlists")]
 pub async fn api_v1_spotify_user_playlists(
     session: Session,
     app: web::Data<ApplicationState>,
+    params: web::Query<RefreshParams>,
 ) -> Result<impl Responder, PublicError> {
     let user_id = macros::user_id!(session);
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
@@ -15,15 +27,167 @@ pub async fn api_v1_spotify_user_playlists(
         .fetch_one(&app.db)
         .await?;
 
-    let key = format!("user_playlists:{user_id}");
-    let res = cache::get_or_create(&app.cache, key.as_str(), 300, false, || {
+    let res = cached_user_playlists(
+        &app.cache,
+        &user_id,
+        params.refresh,
+        &user.client(),
+        user.spotify_id(),
+    )
+    .await?;
+
+    Ok(web::Json(res))
+}
+
+/// Fetch the user's playlists through the cache, going to Spotify when the cache is cold or a
+/// refresh is requested.
+async fn cached_user_playlists(
+    pool: &cache::RedisPool,
+    user_id: &str,
+    refresh: bool,
+    client: &impl OAuthClient,
+    spotify_id: UserId<'_>,
+) -> Result<Vec<SimplifiedPlaylist>, PublicError> {
+    let key = cache::user_key(user_id, "playlists");
+    let (res, status) = cache::get_or_create_with_stats(pool, &key, 300, refresh, || {
+        metrics::global().record_spotify_call("user_playlists");
         let mut playlists: Vec<SimplifiedPlaylist> = Vec::new();
-        for plst in spotify::init(user.token()).user_playlists(user.spotify_id()) {
+        for plst in client.user_playlists(spotify_id.clone()) {
             playlists.push(plst?);
         }
         Ok(playlists)
     })
     .await?;
+    log::debug!("user playlists of {}: cache {:?}", user_id, status);
+
+    Ok(res)
+}
+
+/// WritablePlaylist is the trimmed down playlist returned to the UI, when configuring outputs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WritablePlaylist {
+    pub id: PlaylistId<'static>,
+    pub name: String,
+    pub collaborative: bool,
+}
+
+#[get("/api/v1/spotify/writable_playlists")]
+pub async fn api_v1_spotify_writable_playlists(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    params: web::Query<RefreshParams>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(&app.db)
+        .await?;
+
+    let key = cache::user_key(&user_id, "writable_playlists");
+    let (res, status) =
+        cache::get_or_create_with_stats(&app.cache, &key, 300, params.refresh, || {
+            metrics::global().record_spotify_call("user_playlists");
+            let mut playlists: Vec<SimplifiedPlaylist> = Vec::new();
+            for plst in user.client().user_playlists(user.spotify_id()) {
+                playlists.push(plst?);
+            }
+            Ok(writable_playlists(playlists, &user.spotify_id()))
+        })
+        .await?;
+    log::debug!("writable playlists of {}: cache {:?}", user_id, status);
 
     Ok(web::Json(res))
 }
+
+/// Keep only the playlists the user can modify - those they own, or that are collaborative.
+fn writable_playlists(playlists: Vec<SimplifiedPlaylist>, user: &UserId) -> Vec<WritablePlaylist> {
+    playlists
+        .into_iter()
+        .filter(|p| p.owner.id.id() == user.id() || p.collaborative)
+        .map(|p| WritablePlaylist {
+            id: p.id,
+            name: p.name,
+            collaborative: p.collaborative,
+        })
+        .collect()
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, page, playlist, spotify::MockSpotify};
+
+    #[actix_web::test]
+    async fn refresh_bypasses_cached_playlists() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let spotify = MockSpotify::start();
+        spotify
+            .on(
+                "GET",
+                "/v1/users/owner/playlists",
+                page::<SimplifiedPlaylist>(vec![]),
+            )
+            .on(
+                "GET",
+                "/v1/users/owner/playlists?offset=0",
+                page(vec![playlist("fresh", "Fresh", "owner")]),
+            );
+        let client = spotify.client();
+        let owner = UserId::from_id("owner").unwrap();
+
+        let cached = vec![playlist("cached", "Cached", "owner")];
+        let key = cache::user_key(&user.id, "playlists");
+        cache::get_or_create_with_stats(&state.cache, &key, 300, false, || Ok(cached.clone()))
+            .await
+            .unwrap();
+
+        let res = cached_user_playlists(&state.cache, &user.id, false, &client, owner.clone())
+            .await
+            .unwrap();
+        assert_eq!(res[0].name, "Cached");
+        assert!(spotify
+            .requests("GET", "/v1/users/owner/playlists")
+            .is_empty());
+
+        let res = cached_user_playlists(&state.cache, &user.id, true, &client, owner.clone())
+            .await
+            .unwrap();
+        assert_eq!(res[0].name, "Fresh");
+        let calls = spotify.requests("GET", "/v1/users/owner/playlists").len();
+        assert!(calls > 0);
+
+        // The refreshed value replaces the cached one
+        let res = cached_user_playlists(&state.cache, &user.id, false, &client, owner)
+            .await
+            .unwrap();
+        assert_eq!(res[0].name, "Fresh");
+        assert_eq!(
+            spotify.requests("GET", "/v1/users/owner/playlists").len(),
+            calls
+        );
+    }
+
+    #[actix_web::test]
+    async fn can_filter_writable_playlists() {
+        let mut shared = playlist("shared", "Shared", "someone");
+        shared.collaborative = true;
+        let playlists = vec![
+            playlist("mine", "Mine", "owner"),
+            playlist("theirs", "Theirs", "someone"),
+            shared,
+        ];
+
+        let user = UserId::from_uri("spotify:user:owner").unwrap();
+        let res = writable_playlists(playlists, &user);
+
+        let names: Vec<&str> = res.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Mine", "Shared"]);
+        assert_eq!(
+            serde_json::to_value(&res[1]).unwrap(),
+            serde_json::json!({ "id": "shared", "name": "Shared", "collaborative": true })
+        );
+    }
+}
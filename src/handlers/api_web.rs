@@ -0,0 +1,256 @@
+use actix_session::Session;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{Component, ComponentError},
+    error::PublicError,
+    macros,
+    models::User,
+    typescript, ApplicationState,
+};
+
+/// Download the component parameter types, as TypeScript definitions for the frontend.
+#[get("/api/v1/web/components/schema.ts")]
+pub async fn api_v1_web_components_schema_ts() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/typescript")
+        .body(typescript::components(&Component::json_schema()))
+}
+
+/// Return the JSON schema of a single component's parameters, e.g. `filter:take`.
+///
+/// Lets the frontend lazily load the form for the selected component.
+#[get("/api/v1/web/components/{name}/schema")]
+pub async fn api_v1_web_component_schema(
+    name: web::Path<String>,
+) -> Result<impl Responder, PublicError> {
+    let schema = Component::args_schema(&name).ok_or(PublicError::NotFound)?;
+    Ok(web::Json(schema))
+}
+
+#[derive(Deserialize)]
+pub struct ValidateComponentRequest {
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+}
+
+/// ComponentValidation is the outcome of validating a single component's parameters.
+#[derive(Serialize, Debug)]
+pub struct ComponentValidation {
+    pub valid: bool,
+    pub errors: Vec<ParameterError>,
+}
+
+/// ParameterError describes why a component's parameters are invalid.
+#[derive(Serialize, Debug)]
+pub struct ParameterError {
+    /// The offending argument, `parameters` if they couldn't be read at all, or None if the
+    /// error isn't tied to a single argument.
+    pub argument: Option<&'static str>,
+    pub message: String,
+}
+
+impl From<ComponentError> for ParameterError {
+    fn from(err: ComponentError) -> Self {
+        match err {
+            ComponentError::InvalidArgument { name, message } => ParameterError {
+                argument: Some(name),
+                message,
+            },
+            err => ParameterError {
+                argument: None,
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Validate the parameters of a single component, e.g. `filter:take`, without saving a flow.
+///
+/// Lets the frontend give feedback while a node is being edited. Invalid parameters are reported
+/// in the body, returns PublicError::NotFound for unknown component names.
+#[post("/api/v1/web/components/{name}/validate")]
+pub async fn api_v1_web_component_validate(
+    name: web::Path<String>,
+    body: web::Json<ValidateComponentRequest>,
+) -> Result<impl Responder, PublicError> {
+    if !Component::names().contains(&name.as_str()) {
+        return Err(PublicError::NotFound);
+    }
+
+    let res = Component::from_parts(&name, body.into_inner().parameters)
+        .and_then(|mut component| component.normalize());
+    let errors: Vec<ParameterError> = res.err().map(ParameterError::from).into_iter().collect();
+
+    Ok(web::Json(ComponentValidation {
+        valid: errors.is_empty(),
+        errors,
+    }))
+}
+
+/// ComponentAvailability reports whether the current user has granted the scopes a component
+/// needs to run.
+#[derive(Serialize, Debug)]
+pub struct ComponentAvailability {
+    pub component: &'static str,
+    pub available: bool,
+    /// The required scopes missing from the user's token, empty when available.
+    pub missing_scopes: Vec<&'static str>,
+}
+
+/// Return which components the current user's token has the scopes to run.
+///
+/// Lets the frontend grey out the components a user would need to re-authorize for.
+#[get("/api/v1/web/components/available")]
+pub async fn api_v1_web_components_available(
+    session: Session,
+    app: web::Data<ApplicationState>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(&app.db)
+        .await?;
+    let granted = user.token().map(|t| t.scopes).unwrap_or_default();
+
+    let components: Vec<ComponentAvailability> = Component::names()
+        .iter()
+        .map(|&component| {
+            let missing_scopes: Vec<&'static str> = Component::scopes_of(component)
+                .unwrap_or_default()
+                .iter()
+                .copied()
+                .filter(|scope| !granted.contains(*scope))
+                .collect();
+            ComponentAvailability {
+                component,
+                available: missing_scopes.is_empty(),
+                missing_scopes,
+            }
+        })
+        .collect();
+
+    Ok(web::Json(components))
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use actix_web::{http::StatusCode, test, App};
+    use std::collections::HashSet;
+
+    #[actix_web::test]
+    async fn can_fetch_single_component_schema() {
+        let app = test::init_service(App::new().service(super::api_v1_web_component_schema)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/web/components/filter:take/schema")
+            .to_request();
+        let schema: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(schema["title"], "TakeArgs");
+        assert!(schema["properties"]["limit"].is_object());
+        assert!(schema["properties"]["from"].is_object());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/web/components/filter:nope/schema")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn can_validate_component_parameters() {
+        let app =
+            test::init_service(App::new().service(super::api_v1_web_component_validate)).await;
+        let validate = |name: &str, parameters: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/web/components/{name}/validate"))
+                .set_json(serde_json::json!({ "parameters": parameters }))
+                .to_request()
+        };
+
+        let req = validate(
+            "filter:take",
+            serde_json::json!({ "limit": 10, "from": "end" }),
+        );
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res, serde_json::json!({ "valid": true, "errors": [] }));
+
+        // Parameters that don't match the component's arguments
+        let req = validate("filter:take", serde_json::json!({ "limit": "ten" }));
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["valid"], false);
+        assert_eq!(res["errors"][0]["argument"], "parameters");
+
+        // Parameters that are well-formed, but out of range
+        let req = validate(
+            "filter:balance_explicit",
+            serde_json::json!({ "max_explicit_ratio": 1.5 }),
+        );
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["valid"], false);
+        assert_eq!(res["errors"][0]["argument"], "max_explicit_ratio");
+        assert_eq!(res["errors"][0]["message"], "must be between 0 and 1");
+
+        let req = validate("filter:nope", serde_json::json!({}));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn can_list_components_available_to_token() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+
+        // Grant everything but user-library-read
+        let token = rspotify::Token {
+            scopes: HashSet::from([
+                "playlist-read-private".to_owned(),
+                "playlist-modify-private".to_owned(),
+                "playlist-modify-public".to_owned(),
+            ]),
+            ..Default::default()
+        };
+        sqlx::query("UPDATE users SET spotify_access_token = ? WHERE id = ?")
+            .bind(serde_json::to_string(&Some(token)).unwrap())
+            .bind(&user.id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_web_components_available),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/web/components/available")
+            .cookie(cookie)
+            .to_request();
+        let res: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let find = |name: &str| {
+            res.iter()
+                .find(|c| c["component"] == name)
+                .cloned()
+                .unwrap()
+        };
+
+        let liked = find("source:user_liked_tracks");
+        assert_eq!(liked["available"], false);
+        assert_eq!(
+            liked["missing_scopes"],
+            serde_json::json!(["user-library-read"])
+        );
+
+        assert_eq!(find("source:artist_top_tracks")["available"], true);
+        assert_eq!(find("output:overwrite")["available"], true);
+    }
+}
@@ -28,6 +28,35 @@ pub async fn auth_sso_redirect_handler() -> impl Responder {
         .finish()
 }
 
+// Re-authorize with the scopes needed to run one of the user's flows, keeping every scope
+// already granted
+#[get("/auth/spotify/sso/{flow_id}")]
+pub async fn auth_sso_flow_redirect_handler(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+) -> Result<impl Responder> {
+    let user_id = macros::user_id!(session);
+    let flow = sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ? AND user_id = ?")
+        .bind(flow_id.as_str())
+        .bind(&user_id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(PublicError::NotFound)?;
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&user_id)
+        .fetch_one(&app.db)
+        .await?;
+
+    let granted = user.token().map(|token| token.scopes).unwrap_or_default();
+    Ok(HttpResponse::TemporaryRedirect()
+        .insert_header((
+            "Location",
+            crate::spotify::auth::authorize_uri_for(&flow.definition, &granted),
+        ))
+        .finish())
+}
+
 //
 
 #[derive(Deserialize)]
@@ -47,6 +76,8 @@ pub async fn auth_sso_callback_handler(
 
     // Request the user data
     let spotify_user = crate::spotify::init(Some(token)).me()?;
+    let country = spotify_user.country.map(<&str>::from);
+    let product = spotify_user.product.map(<&str>::from);
 
     // Check if we already know that user
     // If not, insert the initial database record
@@ -58,27 +89,33 @@ pub async fn auth_sso_callback_handler(
     let id;
 
     match query {
-        // We do know this user, just replace the access token
+        // We do know this user, just replace the access token, country, and product
         Some(user) => {
             id = user.id.to_owned();
-            sqlx::query("UPDATE users SET spotify_access_token = ? WHERE id = ?")
-                .bind(&token_json)
-                .bind(&user.id)
-                .execute(&app.db)
-                .await?;
+            sqlx::query(
+                "UPDATE users SET spotify_access_token = ?, spotify_country = ?, spotify_product = ? WHERE id = ?",
+            )
+            .bind(&token_json)
+            .bind(country)
+            .bind(product)
+            .bind(&user.id)
+            .execute(&app.db)
+            .await?;
         }
 
         // We don't know this user
         None => {
             id = Ulid::new().to_string();
             sqlx::query(
-                "INSERT INTO users (id, spotify_id, spotify_username, spotify_email, spotify_access_token) VALUES (?, ?, ?, ?, ?)"
+                "INSERT INTO users (id, spotify_id, spotify_username, spotify_email, spotify_access_token, spotify_country, spotify_product) VALUES (?, ?, ?, ?, ?, ?, ?)"
             )
                 .bind(&id)
                 .bind(&spotify_user.id.to_string())
                 .bind(&spotify_user.display_name)
                 .bind(&spotify_user.email)
                 .bind(&token_json)
+                .bind(country)
+                .bind(product)
                 .execute(&app.db)
                 .await?;
         }
@@ -92,3 +129,102 @@ pub async fn auth_sso_callback_handler(
         .insert_header(("Location", "/"))
         .finish())
 }
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use actix_web::{http::header, test, App};
+
+    #[actix_web::test]
+    async fn profile_includes_country_and_product() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        sqlx::query("UPDATE users SET spotify_country = ?, spotify_product = ? WHERE id = ?")
+            .bind("SE")
+            .bind("premium")
+            .bind(&user.id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::auth_me_handler),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let req = test::TestRequest::get()
+            .uri("/auth/me")
+            .cookie(cookie)
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res["id"], user.id);
+        assert_eq!(res["spotify_country"], "SE");
+        assert_eq!(res["spotify_product"], "premium");
+    }
+
+    #[actix_web::test]
+    async fn flow_authorize_keeps_granted_scopes() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let token = rspotify::Token {
+            scopes: ["playlist-read-private".to_owned()].into(),
+            ..Default::default()
+        };
+        sqlx::query("UPDATE users SET spotify_access_token = ? WHERE id = ?")
+            .bind(serde_json::to_string(&Some(token)).unwrap())
+            .bind(&user.id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(crate::handlers::flows::api_v1_flows_import)
+                .service(super::auth_sso_flow_redirect_handler),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+        let flow = testing::import_flow(
+            &app,
+            &cookie,
+            r#"
+name: Liked
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters: {}
+edges: []
+"#,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/spotify/sso/{}",
+                flow["id"].as_str().unwrap()
+            ))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        assert!(location.contains("user-library-read"));
+        assert!(location.contains("playlist-read-private"));
+    }
+}
@@ -0,0 +1,1651 @@
+use actix_session::Session;
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    post, put, rt, web, HttpResponse, Responder,
+};
+use chrono::Utc;
+use rspotify::{model::Market, prelude::*, AuthCodeSpotify as Client};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::{
+    assets::FlowExamples,
+    cache,
+    components::TrackList,
+    controller::{
+        store_snapshots, ExecutionReport, FlowLimits, RunOptions, Schedule, StoredSnapshots,
+        UserDefinedFlow,
+    },
+    error::PublicError,
+    extractors::{Format, YamlOrJson},
+    legacy::LegacyProgram,
+    macros,
+    models::{Flow, FlowRun, FlowSchedule, RunStatus, User},
+    request_id,
+    scheduler::CronExpr,
+    shutdown::RunGuard,
+    ApplicationState,
+};
+
+/// How long a run lock is held before Redis expires it, in seconds.
+/// n.b. This only matters if the server dies mid-run - the lock is released when the run completes.
+pub(crate) const FLOW_RUN_LOCK_TTL_SECONDS: usize = 600;
+
+/// How long the node snapshots of an incremental run are kept, in seconds.
+const FLOW_SNAPSHOTS_TTL_SECONDS: usize = 7 * 24 * 60 * 60;
+
+/// The most flows `execute_batch` runs in a single request.
+const BATCH_EXECUTE_MAX_FLOWS: usize = 10;
+
+/// How often a background run checks whether it has been cancelled, in milliseconds.
+const RUN_CANCEL_POLL_INTERVAL_MILLIS: u64 = 500;
+
+/// FlowDocument is the portable representation of a saved flow, used for import and export.
+///
+/// The flow definition is flattened alongside the name, so an exported document is also a valid
+/// [`UserDefinedFlow`].
+#[derive(Serialize, Deserialize)]
+pub struct FlowDocument {
+    pub name: String,
+    #[serde(flatten)]
+    pub definition: UserDefinedFlow,
+}
+
+/// FlowWarning flags a non-fatal problem with a saved flow, e.g. a component this build doesn't
+/// know about. The flow is still saved, but will fail when run.
+#[derive(Serialize, Debug)]
+pub struct FlowWarning {
+    pub node_id: Uuid,
+    pub component: String,
+    pub message: String,
+}
+
+/// SaveFlowResponse is returned when a flow is created or updated.
+#[derive(Serialize)]
+pub struct SaveFlowResponse {
+    #[serde(flatten)]
+    pub flow: Flow,
+    pub warnings: Vec<FlowWarning>,
+}
+
+impl From<Flow> for SaveFlowResponse {
+    fn from(flow: Flow) -> Self {
+        let warnings = flow
+            .definition
+            .unknown_components()
+            .into_iter()
+            .map(|(node_id, component)| FlowWarning {
+                node_id,
+                message: format!("Unknown component: {}", component),
+                component,
+            })
+            .collect();
+
+        SaveFlowResponse { flow, warnings }
+    }
+}
+
+/// Build the key of the lock held while one of the user's flows is running.
+/// n.b. This is deliberately not a `cache::user_key`, so flushing the cache can't release it.
+pub(crate) fn run_lock(user_id: &str) -> String {
+    format!("flow_run_lock:{user_id}")
+}
+
+/// Load one of the current user's flows.
+///
+/// Returns PublicError::NotFound if the flow doesn't exist, or is owned by a different user.
+async fn find_flow(
+    app: &ApplicationState,
+    user_id: &str,
+    flow_id: &str,
+) -> Result<Flow, PublicError> {
+    sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ? AND user_id = ?")
+        .bind(flow_id)
+        .bind(user_id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(PublicError::NotFound)
+}
+
+/// List the built-in example flows, as [`FlowDocument`]s ready to import.
+#[get("/api/v1/flows/examples")]
+pub async fn api_v1_flows_examples() -> Result<impl Responder, PublicError> {
+    Ok(web::Json(examples()?))
+}
+
+/// Parse the embedded example flows, ordered by filename.
+fn examples() -> Result<Vec<FlowDocument>, PublicError> {
+    let mut filenames: Vec<_> = FlowExamples::iter().collect();
+    filenames.sort();
+
+    filenames
+        .iter()
+        .filter_map(|filename| FlowExamples::get(filename))
+        .map(|file| Ok(serde_yaml::from_slice(&file.data)?))
+        .collect()
+}
+
+/// Save a new flow for the current user.
+///
+/// Accepts a [`FlowDocument`] as either JSON or YAML, e.g. one previously exported.
+#[post("/api/v1/flows")]
+pub async fn api_v1_flows_import(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    document: YamlOrJson<FlowDocument>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let mut document = document.into_inner();
+
+    // Refuse to save flows we would never be able to run
+    document.definition.validate(&FlowLimits::from_env())?;
+    document.definition.normalize()?;
+
+    let flow = Flow {
+        id: Ulid::new().to_string(),
+        user_id,
+        name: document.name,
+        definition: Json(document.definition),
+        last_run_at: None,
+    };
+
+    insert_flow(&app, &flow).await?;
+
+    Ok(YamlOrJson(SaveFlowResponse::from(flow)))
+}
+
+/// Save a new flow for the current user, translated from a SmarterPlaylists.com program.
+///
+/// Legacy components without an equivalent are saved as unknown `legacy:{type}` nodes, and
+/// reported in the response warnings. See [`LegacyProgram`] for the accepted format.
+#[post("/api/v1/flows/import")]
+pub async fn api_v1_flows_import_legacy(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    program: web::Json<LegacyProgram>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let mut definition = program.to_flow()?;
+
+    // Refuse to save flows we would never be able to run
+    definition.validate(&FlowLimits::from_env())?;
+    definition.normalize()?;
+
+    let flow = Flow {
+        id: Ulid::new().to_string(),
+        user_id,
+        name: program.into_inner().name,
+        definition: Json(definition),
+        last_run_at: None,
+    };
+    insert_flow(&app, &flow).await?;
+
+    Ok(web::Json(SaveFlowResponse::from(flow)))
+}
+
+/// Save a copy of one of the current user's flows, named "Copy of {name}".
+///
+/// Every node is given a fresh UUID, so the copy is fully independent of the original.
+#[post("/api/v1/flows/{id}/clone")]
+pub async fn api_v1_flows_clone(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let original = find_flow(&app, &user_id, &flow_id).await?;
+
+    let flow = Flow {
+        id: Ulid::new().to_string(),
+        user_id,
+        name: format!("Copy of {}", original.name),
+        definition: Json(original.definition.with_fresh_ids()),
+        last_run_at: None,
+    };
+    insert_flow(&app, &flow).await?;
+
+    Ok(YamlOrJson(SaveFlowResponse::from(flow)))
+}
+
+/// Insert a new flow row.
+async fn insert_flow(app: &ApplicationState, flow: &Flow) -> Result<(), PublicError> {
+    sqlx::query("INSERT INTO flows (id, user_id, name, definition) VALUES (?, ?, ?, ?)")
+        .bind(&flow.id)
+        .bind(&flow.user_id)
+        .bind(&flow.name)
+        .bind(&flow.definition)
+        .execute(&app.db)
+        .await?;
+    Ok(())
+}
+
+/// Replace the name and definition of one of the current user's flows.
+///
+/// Accepts a [`FlowDocument`] as either JSON or YAML.
+#[put("/api/v1/flows/{id}")]
+pub async fn api_v1_flows_update(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+    document: YamlOrJson<FlowDocument>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let mut document = document.into_inner();
+
+    document.definition.validate(&FlowLimits::from_env())?;
+    document.definition.normalize()?;
+
+    let mut flow = find_flow(&app, &user_id, &flow_id).await?;
+    flow.name = document.name;
+    flow.definition = Json(document.definition);
+
+    sqlx::query("UPDATE flows SET name = ?, definition = ? WHERE id = ? AND user_id = ?")
+        .bind(&flow.name)
+        .bind(&flow.definition)
+        .bind(&flow.id)
+        .bind(&flow.user_id)
+        .execute(&app.db)
+        .await?;
+
+    Ok(YamlOrJson(SaveFlowResponse::from(flow)))
+}
+
+/// Download one of the current user's flows as a YAML file.
+#[get("/api/v1/flows/{id}/export")]
+pub async fn api_v1_flows_export(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let flow = find_flow(&app, &user_id, &flow_id).await?;
+
+    let document = FlowDocument {
+        name: flow.name,
+        definition: flow.definition.0,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(Format::Yaml.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("flow-{}.yaml", flow.id))],
+        })
+        .body(Format::Yaml.serialize(&document)?))
+}
+
+/// Run `f` with the user's spotify client, on the blocking thread pool.
+///
+/// The run is tracked by `guard` (see [`crate::shutdown::RunTracker::start`]) until it completes, so shutdown can
+/// wait for it. Callers with work to do after the run (e.g. releasing the run lock) should pass
+/// a clone, and hold their own guard until that is done. If the client refreshed the access
+/// token during the run, the new token is saved for the next run.
+pub(crate) async fn run_blocking<T, F>(
+    app: &ApplicationState,
+    user_id: &str,
+    guard: RunGuard,
+    f: F,
+) -> Result<T, PublicError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Client, Market) -> Result<T, PublicError> + Send + 'static,
+{
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(&app.db)
+        .await?;
+
+    let token = user.token();
+
+    // n.b. The guard is moved into the closure, as the run outlives a dropped request
+    let request_id = request_id::current();
+    let (res, refreshed) = web::block(move || {
+        let _guard = guard;
+        request_id::scope_blocking(request_id, || {
+            let client = user.client();
+            let res = f(&client, user.market());
+            let refreshed = client.token.lock().unwrap().clone();
+            (res, refreshed)
+        })
+    })
+    .await
+    .map_err(|err| PublicError::from(err.to_string()))?;
+
+    if refreshed.is_some() && refreshed != token {
+        sqlx::query("UPDATE users SET spotify_access_token = ? WHERE id = ?")
+            .bind(serde_json::to_string(&refreshed)?)
+            .bind(user_id)
+            .execute(&app.db)
+            .await?;
+    }
+
+    res
+}
+
+/// Build the key of the flag set to cancel a run.
+pub(crate) fn cancel_key(run_id: &str) -> String {
+    format!("flow_run_cancel:{run_id}")
+}
+
+/// Build the key the node snapshots of a flow's last incremental run are stored under.
+fn snapshots_key(user_id: &str, flow_id: &str) -> String {
+    cache::user_key(user_id, &format!("flow_snapshots:{flow_id}"))
+}
+
+#[derive(Deserialize)]
+pub struct RunParams {
+    /// Reuse the output of nodes unchanged since the last incremental run, see [`RunOptions`].
+    /// n.b. Unchanged sources aren't refetched and unchanged outputs aren't rewritten
+    #[serde(default)]
+    pub incremental: bool,
+    /// Record which source node each track came from in the report, see [`RunOptions`].
+    #[serde(default)]
+    pub explain: bool,
+    /// Seed every randomized node without its own seed, see [`RunOptions`].
+    pub seed: Option<u64>,
+}
+
+/// Start a run of one of the current user's flows.
+///
+/// The flow runs in the background, the returned [`FlowRun`] carries the id used to cancel it.
+/// Only one flow may run per user at a time, as overlapping runs can corrupt the target playlist.
+/// Returns PublicError::Locked if another run is already in progress, or
+/// PublicError::ShuttingDown if the server is shutting down.
+#[post("/api/v1/flows/{id}/run")]
+pub async fn api_v1_flows_run(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+    params: web::Query<RunParams>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+
+    // n.b. Taken before the run is accepted, and held until its outcome is recorded, so shutdown
+    // waits for every accepted run
+    let guard = app.runs.start(&flow_id)?;
+    let flow = find_flow(&app, &user_id, &flow_id).await?;
+
+    let previous = match params.incremental {
+        true => Some(
+            cache::get::<StoredSnapshots>(&app.cache, &snapshots_key(&user_id, &flow.id))
+                .await?
+                .unwrap_or_default(),
+        ),
+        false => None,
+    };
+
+    // n.b. The run id doubles as the lock token, see `cache::release_lock`
+    let run_id = Ulid::new().to_string();
+    let lock = run_lock(&user_id);
+    if !cache::acquire_lock(&app.cache, &lock, &run_id, FLOW_RUN_LOCK_TTL_SECONDS).await? {
+        return Err(PublicError::Locked);
+    }
+
+    let run = FlowRun {
+        id: run_id,
+        flow_id: flow.id.clone(),
+        user_id,
+        status: RunStatus::Running,
+        started_at: Utc::now().timestamp(),
+        finished_at: None,
+        report: None,
+        error: None,
+    };
+
+    let res = sqlx::query(
+        "INSERT INTO flow_runs (id, flow_id, user_id, status, started_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&run.id)
+    .bind(&run.flow_id)
+    .bind(&run.user_id)
+    .bind(run.status)
+    .bind(run.started_at)
+    .execute(&app.db)
+    .await;
+    if let Err(err) = res {
+        cache::release_lock(&app.cache, &lock, &run.id).await?;
+        return Err(err.into());
+    }
+
+    rt::spawn(request_id::scope(
+        request_id::current(),
+        run_in_background(
+            app.clone(),
+            run.id.clone(),
+            flow,
+            previous,
+            params.into_inner(),
+            guard,
+            lock,
+        ),
+    ));
+
+    Ok(HttpResponse::Accepted().json(run))
+}
+
+/// Run the flow to completion, recording the outcome against the run and releasing the run lock.
+///
+/// The cancel flag lives in Redis, so it is polled here and mirrored into the flag the
+/// controller checks between nodes. Incremental runs (with `previous` snapshots) store their
+/// snapshots for the next one. The run stays tracked by `guard` until the lock is released.
+async fn run_in_background(
+    app: web::Data<ApplicationState>,
+    run_id: String,
+    flow: Flow,
+    previous: Option<StoredSnapshots>,
+    params: RunParams,
+    guard: RunGuard,
+    lock: String,
+) {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let watcher = rt::spawn(watch_cancel_flag(
+        app.clone(),
+        cancel_key(&run_id),
+        cancelled.clone(),
+    ));
+
+    let is_cancelled = move || cancelled.load(Ordering::Relaxed);
+    let (user_id, flow_id) = (flow.user_id.clone(), flow.id.clone());
+    let incremental = previous.is_some();
+    let started_at = Utc::now().timestamp();
+    let res = run_blocking(&app, &user_id, guard.clone(), move |client, market| {
+        let options = RunOptions {
+            cancelled: &is_cancelled,
+            previous: previous.as_ref(),
+            explain: params.explain,
+            seed: params.seed,
+        };
+        flow.definition
+            .0
+            .with_default_market(market)
+            .with_last_run(flow.last_run_at)
+            .execute_with(client, options)
+    })
+    .await;
+    watcher.abort();
+
+    if res.is_ok() {
+        if let Err(err) = advance_last_run(&app, &flow_id, started_at).await {
+            log::error!("failed to record the last run of flow {}: {}", flow_id, err);
+        }
+    }
+
+    if let (true, Ok((_, snapshots))) = (incremental, &res) {
+        let key = snapshots_key(&user_id, &flow_id);
+        let stored = store_snapshots(snapshots);
+        if let Err(err) = cache::set(&app.cache, &key, &stored, FLOW_SNAPSHOTS_TTL_SECONDS).await {
+            log::error!("failed to store the snapshots of run {}: {}", run_id, err);
+        }
+    }
+
+    let (status, report, error) = match res {
+        Ok((report, _)) => (RunStatus::Succeeded, Some(Json(report)), None),
+        Err(PublicError::Cancelled) => (RunStatus::Cancelled, None, None),
+        Err(err) => {
+            // n.b. The recorded message hides internal errors, so log the full error here
+            log::error!("run {} failed: {:?}", run_id, err);
+            (RunStatus::Failed, None, Some(err.to_string()))
+        }
+    };
+
+    let res = sqlx::query(
+        "UPDATE flow_runs SET status = ?, finished_at = ?, report = ?, error = ? WHERE id = ?",
+    )
+    .bind(status)
+    .bind(Utc::now().timestamp())
+    .bind(report)
+    .bind(error)
+    .bind(&run_id)
+    .execute(&app.db)
+    .await;
+    if let Err(err) = res {
+        log::error!("failed to record the outcome of run {}: {}", run_id, err);
+    }
+
+    // Always release the lock, even if the run failed
+    if let Err(err) = cache::release_lock(&app.cache, &lock, &run_id).await {
+        log::error!("failed to release the lock for run {}: {}", run_id, err);
+    }
+    drop(guard);
+}
+
+/// Move the flow's `last_run_at` cursor on to the start of a successful run.
+pub(crate) async fn advance_last_run(
+    app: &ApplicationState,
+    flow_id: &str,
+    started_at: i64,
+) -> Result<(), PublicError> {
+    sqlx::query("UPDATE flows SET last_run_at = ? WHERE id = ?")
+        .bind(started_at)
+        .bind(flow_id)
+        .execute(&app.db)
+        .await?;
+    Ok(())
+}
+
+/// Poll the cancel flag in Redis until it is set, then set `cancelled`.
+async fn watch_cancel_flag(
+    app: web::Data<ApplicationState>,
+    key: String,
+    cancelled: Arc<AtomicBool>,
+) {
+    let mut interval = rt::time::interval(Duration::from_millis(RUN_CANCEL_POLL_INTERVAL_MILLIS));
+    loop {
+        interval.tick().await;
+        match cache::is_flag_set(&app.cache, &key).await {
+            Ok(true) => {
+                cancelled.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(false) => (),
+            Err(err) => log::error!("failed to check {}: {}", key, err),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CancelRunParams {
+    pub run_id: String,
+}
+
+/// Cancel a run of one of the current user's flows.
+///
+/// The run stops once the node currently executing finishes, and is recorded as cancelled.
+/// Returns PublicError::BadRequest if the run has already finished.
+#[post("/api/v1/flows/{id}/run/cancel")]
+pub async fn api_v1_flows_run_cancel(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+    params: web::Json<CancelRunParams>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let run = sqlx::query_as::<_, FlowRun>(
+        "SELECT * FROM flow_runs WHERE id = ? AND flow_id = ? AND user_id = ?",
+    )
+    .bind(&params.run_id)
+    .bind(flow_id.as_str())
+    .bind(&user_id)
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or(PublicError::NotFound)?;
+
+    cancel_run(&app, run).await
+}
+
+/// Cancel one of the current user's runs by its id alone, as returned when the run started.
+///
+/// See `api_v1_flows_run_cancel`.
+#[post("/api/v1/flows/{run_id}/cancel")]
+pub async fn api_v1_flows_cancel(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    run_id: web::Path<String>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let run = sqlx::query_as::<_, FlowRun>("SELECT * FROM flow_runs WHERE id = ? AND user_id = ?")
+        .bind(run_id.as_str())
+        .bind(&user_id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(PublicError::NotFound)?;
+
+    cancel_run(&app, run).await
+}
+
+/// Set the cancel flag of a running run, which `run_in_background` passes on to the controller.
+async fn cancel_run(app: &ApplicationState, run: FlowRun) -> Result<HttpResponse, PublicError> {
+    if run.status != RunStatus::Running {
+        return Err(PublicError::bad_request("Run has already finished"));
+    }
+
+    // n.b. The flag outlives any run, as runs are bounded by the run lock
+    cache::set_flag(&app.cache, &cancel_key(&run.id), FLOW_RUN_LOCK_TTL_SECONDS).await?;
+
+    Ok(HttpResponse::Accepted().json(run))
+}
+
+#[derive(Deserialize)]
+pub struct ExecuteBatchParams {
+    pub flow_ids: Vec<String>,
+}
+
+/// BatchExecutionResult is the outcome of one flow in a batch, with either a report or an error.
+#[derive(Serialize)]
+pub struct BatchExecutionResult {
+    pub flow_id: String,
+    pub report: Option<ExecutionReport>,
+    pub error: Option<String>,
+}
+
+/// BatchExecutionResponse is returned by `POST /api/v1/flows/execute_batch`.
+#[derive(Serialize)]
+pub struct BatchExecutionResponse {
+    /// One result per flow, in the order requested.
+    pub results: Vec<BatchExecutionResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Run several of the current user's flows, returning a report for each.
+///
+/// The flows run one at a time, each holding the run lock like a single run, so flows writing to
+/// the same playlist can't interleave. A failing flow doesn't stop the others, its error is
+/// reported in its place. Returns PublicError::BadRequest for more than BATCH_EXECUTE_MAX_FLOWS
+/// flows, or PublicError::NotFound if any of the flows don't exist, before running any of them.
+#[post("/api/v1/flows/execute_batch")]
+pub async fn api_v1_flows_execute_batch(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    params: web::Json<ExecuteBatchParams>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    if params.flow_ids.len() > BATCH_EXECUTE_MAX_FLOWS {
+        return Err(PublicError::bad_request(format!(
+            "Batch has {} flows, the maximum allowed is {}",
+            params.flow_ids.len(),
+            BATCH_EXECUTE_MAX_FLOWS
+        )));
+    }
+
+    let mut flows = Vec::with_capacity(params.flow_ids.len());
+    for flow_id in params.flow_ids.iter() {
+        flows.push(find_flow(&app, &user_id, flow_id).await?);
+    }
+
+    let mut results = Vec::with_capacity(flows.len());
+    for flow in flows {
+        let flow_id = flow.id.clone();
+        results.push(match execute_locked(&app, &user_id, flow).await {
+            Ok(report) => BatchExecutionResult {
+                flow_id,
+                report: Some(report),
+                error: None,
+            },
+            Err(err) => {
+                log::error!("batch run of flow {} failed: {:?}", flow_id, err);
+                BatchExecutionResult {
+                    flow_id,
+                    report: None,
+                    error: Some(err.to_string()),
+                }
+            }
+        });
+    }
+
+    let succeeded = results.iter().filter(|r| r.report.is_some()).count();
+    Ok(web::Json(BatchExecutionResponse {
+        failed: results.len() - succeeded,
+        succeeded,
+        results,
+    }))
+}
+
+/// Run the flow to completion while holding the user's run lock, moving its last run cursor on
+/// if it succeeds. Returns PublicError::Locked if another run is already in progress.
+async fn execute_locked(
+    app: &ApplicationState,
+    user_id: &str,
+    flow: Flow,
+) -> Result<ExecutionReport, PublicError> {
+    // n.b. Held until the lock is released, so shutdown can't exit while the user is locked out
+    let guard = app.runs.start(&flow.id)?;
+    let lock = run_lock(user_id);
+    let token = Ulid::new().to_string();
+    if !cache::acquire_lock(&app.cache, &lock, &token, FLOW_RUN_LOCK_TTL_SECONDS).await? {
+        return Err(PublicError::Locked);
+    }
+
+    let flow_id = flow.id.clone();
+    let started_at = Utc::now().timestamp();
+    let res = run_blocking(app, user_id, guard.clone(), move |client, market| {
+        flow.definition
+            .0
+            .with_default_market(market)
+            .with_last_run(flow.last_run_at)
+            .execute(client)
+    })
+    .await;
+    let res = match res {
+        Ok(report) => advance_last_run(app, &flow_id, started_at)
+            .await
+            .map(|_| report),
+        Err(err) => Err(err),
+    };
+
+    // Always release the lock, even if the run failed
+    cache::release_lock(&app.cache, &lock, &token).await?;
+    drop(guard);
+    res
+}
+
+#[derive(Deserialize)]
+pub struct CreateScheduleParams {
+    pub cron_expr: String,
+}
+
+/// Schedule one of the current user's flows to run automatically.
+///
+/// The schedule uses a 5-field cron expression, see [`CronExpr`] for the supported syntax.
+#[post("/api/v1/flows/{id}/schedule")]
+pub async fn api_v1_flows_schedule(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow_id: web::Path<String>,
+    params: web::Json<CreateScheduleParams>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let flow = find_flow(&app, &user_id, &flow_id).await?;
+
+    let cron: CronExpr = params
+        .cron_expr
+        .parse()
+        .map_err(|err| PublicError::bad_request(format!("Invalid cron expression: {}", err)))?;
+    let next_run_at = cron
+        .next_after(Utc::now())
+        .ok_or_else(|| PublicError::bad_request("Cron expression never matches"))?;
+
+    let schedule = FlowSchedule {
+        id: Ulid::new().to_string(),
+        flow_id: flow.id,
+        user_id,
+        cron_expr: params.into_inner().cron_expr,
+        next_run_at: next_run_at.timestamp(),
+        enabled: true,
+    };
+
+    sqlx::query(
+        "INSERT INTO schedules (id, flow_id, user_id, cron_expr, next_run_at, enabled) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&schedule.id)
+    .bind(&schedule.flow_id)
+    .bind(&schedule.user_id)
+    .bind(&schedule.cron_expr)
+    .bind(schedule.next_run_at)
+    .bind(schedule.enabled)
+    .execute(&app.db)
+    .await?;
+
+    Ok(web::Json(schedule))
+}
+
+/// Run a flow for download, returning the tracks that would be written by its output.
+///
+/// Output nodes are removed before the flow runs, so nothing is written to Spotify - which is
+/// also why no run lock is needed.
+async fn run_for_export(
+    app: &ApplicationState,
+    user_id: &str,
+    flow: UserDefinedFlow,
+) -> Result<TrackList, PublicError> {
+    let guard = app.runs.start("<unsaved>")?;
+    run_blocking(app, user_id, guard, move |client, market| {
+        flow.without_outputs()
+            .with_default_market(market)
+            .run_to_end(client)
+    })
+    .await
+}
+
+/// Run a flow, downloading the tracks produced by its last node as an M3U playlist.
+///
+/// Accepts the flow as either JSON or YAML.
+/// n.b. Output nodes are skipped, see [`run_for_export`]
+#[post("/api/v1/flows/export/m3u")]
+pub async fn api_v1_flows_export_m3u(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow: YamlOrJson<UserDefinedFlow>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let flow = flow.into_inner();
+
+    let tracks = run_for_export(&app, &user_id, flow).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("audio/x-mpegurl")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("flow.m3u".to_owned())],
+        })
+        .body(to_m3u(&tracks)))
+}
+
+/// Render the tracks as an extended M3U playlist, one `#EXTINF` line and spotify URI per track.
+/// Note: Tracks without an id have no URI, so are skipped
+fn to_m3u(tracks: &TrackList) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    for track in tracks {
+        let Some(id) = &track.id else {
+            continue;
+        };
+
+        let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+        m3u.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            track.duration.num_seconds(),
+            artists.join(", "),
+            track.name,
+            id.uri()
+        ));
+    }
+    m3u
+}
+
+/// Run a flow, downloading the tracks produced by its last node as a CSV file.
+///
+/// Accepts the flow as either JSON or YAML.
+/// n.b. Output nodes are skipped, see [`run_for_export`]
+#[post("/api/v1/flows/export/csv")]
+pub async fn api_v1_flows_export_csv(
+    session: Session,
+    app: web::Data<ApplicationState>,
+    flow: YamlOrJson<UserDefinedFlow>,
+) -> Result<impl Responder, PublicError> {
+    let user_id = macros::user_id!(session);
+    let flow = flow.into_inner();
+
+    let tracks = run_for_export(&app, &user_id, flow).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("flow.csv".to_owned())],
+        })
+        .body(to_csv(&tracks)?))
+}
+
+/// Render the tracks as CSV, with a header row.
+fn to_csv(tracks: &TrackList) -> Result<String, PublicError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "id",
+        "name",
+        "artists",
+        "album",
+        "duration_seconds",
+        "popularity",
+    ])?;
+
+    for track in tracks {
+        let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+        writer.write_record([
+            track.id.as_ref().map(|id| id.id()).unwrap_or_default(),
+            &track.name,
+            &artists.join(", "),
+            &track.album.name,
+            &track.duration.num_seconds().to_string(),
+            &track.popularity.to_string(),
+        ])?;
+    }
+
+    let csv = writer.into_inner().map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(csv).map_err(|err| err.to_string())?)
+}
+
+#[derive(Serialize)]
+pub struct ValidateFlowResponse {
+    schedule: Schedule,
+    /// The Spotify scopes needed to run the flow, so the UI can prompt the user to re-authorize.
+    required_scopes: BTreeSet<&'static str>,
+}
+
+/// Validate a flow definition without saving or running it.
+///
+/// Accepts the flow as either JSON or YAML, responding with the schedule the flow would run with
+/// and the scopes it needs. Invalid flows are rejected with every problem found, see
+/// [`UserDefinedFlow::check`].
+#[post("/api/v1/flows/validate")]
+pub async fn api_v1_flows_validate(
+    flow: YamlOrJson<UserDefinedFlow>,
+) -> Result<impl Responder, PublicError> {
+    let flow = flow.into_inner();
+    let schedule = flow.check(&FlowLimits::from_env())?;
+
+    Ok(YamlOrJson(ValidateFlowResponse {
+        schedule,
+        required_scopes: flow.required_scopes(),
+    }))
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::{cancel_key, examples, to_csv, to_m3u};
+    use crate::controller::FlowLimits;
+    use crate::{
+        cache,
+        models::{Flow, RunStatus},
+        testing,
+    };
+    use actix_web::{http::header, test, App};
+    use std::time::Duration;
+
+    const FLOW_YAML: &str = r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters:
+            limit: 75
+edges: []
+"#;
+
+    #[actix_web::test]
+    async fn examples_are_valid_flows() {
+        let examples = examples().unwrap();
+        assert_eq!(examples.len(), 3);
+
+        for example in examples {
+            let flow = example.definition;
+            assert!(
+                flow.validate(&FlowLimits::default()).is_ok(),
+                "{}",
+                example.name
+            );
+            assert!(flow.unknown_components().is_empty(), "{}", example.name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn can_render_m3u() {
+        let mut a = testing::track("a");
+        a.artists.push(testing::artist("feature"));
+        let mut local = testing::track("local");
+        local.id = None;
+
+        let m3u = to_m3u(&vec![a, local, testing::track("b")]);
+
+        assert_eq!(
+            m3u,
+            "#EXTM3U\n\
+             #EXTINF:180,Artist artist, Artist feature - Track a\n\
+             spotify:track:a\n\
+             #EXTINF:180,Artist artist - Track b\n\
+             spotify:track:b\n"
+        );
+        assert_eq!(m3u.matches("#EXTINF").count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn can_render_csv() {
+        let mut a = testing::track("a");
+        a.name = "Hello, \"World\"".to_owned();
+        a.artists.push(testing::artist("feature"));
+        a.popularity = 42;
+
+        let csv = to_csv(&vec![a, testing::track("b")]).unwrap();
+
+        assert_eq!(
+            csv,
+            "id,name,artists,album,duration_seconds,popularity\n\
+             a,\"Hello, \"\"World\"\"\",\"Artist artist, Artist feature\",Album album,180,42\n\
+             b,Track b,Artist artist,Album album,180,0\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn can_validate_yaml_flow() {
+        let app = test::init_service(App::new().service(super::api_v1_flows_validate)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/validate")
+            .insert_header((header::CONTENT_TYPE, "application/yaml"))
+            .insert_header((header::ACCEPT, "application/yaml"))
+            .set_payload(FLOW_YAML)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert!(res.status().is_success());
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/yaml"
+        );
+
+        let body = test::read_body(res).await;
+        assert_eq!(
+            body,
+            "schedule:\n\
+             - - da0e029b-7a25-424e-b031-fc1271e38069\n\
+             required_scopes:\n\
+             - user-library-read\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn can_reject_invalid_flow() {
+        let app = test::init_service(App::new().service(super::api_v1_flows_validate)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/validate")
+            .set_payload(r#"{"nodes": {}, "edges": [["da0e029b-7a25-424e-b031-fc1271e38069", "587d87da-0b5b-4b89-a41b-63414b93235c"]]}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn can_locate_invalid_node_arguments() {
+        let app = test::init_service(App::new().service(super::api_v1_flows_validate)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/validate")
+            .insert_header((header::CONTENT_TYPE, "application/yaml"))
+            .set_payload(
+                r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters:
+            limit: 75
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 10
+            from: middle
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: filter:balance_explicit
+        parameters:
+            max_explicit_ratio: 1.5
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 587d87da-0b5b-4b89-a41b-63414b93235c]
+    - [587d87da-0b5b-4b89-a41b-63414b93235c, b38547f9-22cc-47ab-94bb-da695ee3ac4b]
+"#,
+            )
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(
+            body["errors"],
+            serde_json::json!([
+                {
+                    "node_id": "587d87da-0b5b-4b89-a41b-63414b93235c",
+                    "field": "parameters.from",
+                    "kind": "invalid_argument",
+                    "message": "Invalid from: must be start or end, found middle",
+                },
+                {
+                    "node_id": "b38547f9-22cc-47ab-94bb-da695ee3ac4b",
+                    "field": "parameters.max_explicit_ratio",
+                    "kind": "invalid_argument",
+                    "message": "Invalid max_explicit_ratio: must be between 0 and 1",
+                },
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn can_clone_flow_with_fresh_node_ids() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_clone),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let original = testing::import_flow(
+            &app,
+            &cookie,
+            r#"
+name: Mix
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters:
+            limit: 75
+    587d87da-0b5b-4b89-a41b-63414b93235c:
+        component: filter:take
+        parameters:
+            limit: 10
+            from: start
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, 587d87da-0b5b-4b89-a41b-63414b93235c]
+"#,
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/api/v1/flows/{}/clone",
+                original["id"].as_str().unwrap()
+            ))
+            .cookie(cookie)
+            .to_request();
+        let clone: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_ne!(clone["id"], original["id"]);
+        assert_eq!(clone["name"], "Copy of Mix");
+
+        // Every node is new, but each still has the same component and the same edge between them
+        let definition = &clone["definition"];
+        let nodes = definition["nodes"].as_object().unwrap();
+        assert_eq!(nodes.len(), 2);
+        for id in original["definition"]["nodes"].as_object().unwrap().keys() {
+            assert!(!nodes.contains_key(id));
+        }
+        let edge = &definition["edges"][0];
+        let component = |i: usize| nodes[edge[i].as_str().unwrap()]["component"].clone();
+        assert_eq!(component(0), "source:user_liked_tracks");
+        assert_eq!(component(1), "filter:take");
+    }
+
+    const UNKNOWN_FLOW_YAML: &str = r#"
+name: Future flow
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters:
+            limit: 75
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:future_thing
+        parameters:
+            mood: [chill, "late night"]
+            nested:
+                depth: 2
+                enabled: true
+edges:
+    - [da0e029b-7a25-424e-b031-fc1271e38069, b38547f9-22cc-47ab-94bb-da695ee3ac4b]
+"#;
+
+    #[actix_web::test]
+    async fn can_import_and_export_unknown_components() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_export),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        // Import
+        let flow = testing::import_flow(&app, &cookie, UNKNOWN_FLOW_YAML).await;
+        let flow_id = flow["id"].as_str().unwrap();
+
+        // Export
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/v1/flows/{}/export", flow_id))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert!(res.status().is_success());
+        assert_eq!(
+            res.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            &format!("attachment; filename=\"flow-{}.yaml\"", flow_id)
+        );
+
+        let exported: serde_yaml::Value =
+            serde_yaml::from_slice(&test::read_body(res).await).unwrap();
+        let original: serde_yaml::Value = serde_yaml::from_str(UNKNOWN_FLOW_YAML).unwrap();
+
+        let unknown = "b38547f9-22cc-47ab-94bb-da695ee3ac4b";
+        assert_eq!(exported["nodes"][unknown], original["nodes"][unknown]);
+        assert_eq!(exported, original);
+    }
+
+    #[actix_web::test]
+    async fn can_save_flow_with_unknown_component_warnings() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_update),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        // Create - the unknown node is reported, but the flow is still saved
+        let res = testing::import_flow(&app, &cookie, UNKNOWN_FLOW_YAML).await;
+        let flow_id = res["id"].as_str().unwrap().to_owned();
+
+        assert_eq!(
+            res["warnings"],
+            serde_json::json!([{
+                "node_id": "b38547f9-22cc-47ab-94bb-da695ee3ac4b",
+                "component": "source:future_thing",
+                "message": "Unknown component: source:future_thing",
+            }])
+        );
+
+        let flow = sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ?")
+            .bind(&flow_id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(flow.definition.nodes.len(), 2);
+
+        // Update - removing the unknown node clears the warning
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/v1/flows/{}", flow_id))
+            .cookie(cookie)
+            .insert_header((header::CONTENT_TYPE, "application/yaml"))
+            .set_payload(format!("name: Renamed{}", FLOW_YAML))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res["id"], flow_id.as_str());
+        assert_eq!(res["name"], "Renamed");
+        assert_eq!(res["warnings"], serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn can_import_legacy_program() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import_legacy),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/import")
+            .cookie(cookie)
+            .set_json(serde_json::json!({
+                "name": "Legacy",
+                "components": {
+                    "1": { "type": "MySavedTracks" },
+                    "2": { "type": "Shuffler", "sources": ["1"] },
+                    "3": { "type": "PlaylistSave", "params": { "name": "Out" }, "sources": ["2"] },
+                },
+            }))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res["name"], "Legacy");
+        assert_eq!(res["definition"]["nodes"].as_object().unwrap().len(), 3);
+        assert_eq!(res["warnings"].as_array().unwrap().len(), 1);
+        assert_eq!(res["warnings"][0]["component"], "legacy:Shuffler");
+    }
+
+    #[actix_web::test]
+    async fn cannot_run_flows_concurrently() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_run),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let flow = testing::import_flow(&app, &cookie, &format!("name: Test{}", FLOW_YAML)).await;
+
+        // Simulate a run already in progress
+        let lock = format!("flow_run_lock:{}", user.id);
+        assert!(cache::acquire_lock(&state.cache, &lock, "test", 60)
+            .await
+            .unwrap());
+
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/api/v1/flows/{}/run",
+                flow["id"].as_str().unwrap()
+            ))
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), 423);
+
+        // The rejected run must not release the lock held by the other run
+        assert!(!cache::acquire_lock(&state.cache, &lock, "test", 60)
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn cannot_run_flows_while_shutting_down() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_run),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        state.runs.stop_accepting();
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/missing/run")
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn shutdown_waits_for_accepted_runs_to_be_recorded() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_run),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        // An empty flow succeeds without calling Spotify
+        let flow = testing::import_flow(&app, &cookie, "name: Empty\nnodes: {}\nedges: []\n").await;
+        let flow_id = flow["id"].as_str().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/v1/flows/{}/run", flow_id))
+            .cookie(cookie)
+            .to_request();
+        let run: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        // Once drained, everything after the run itself has happened too
+        assert!(state.runs.drain(Duration::from_secs(5)).await);
+
+        let (status,): (RunStatus,) = sqlx::query_as("SELECT status FROM flow_runs WHERE id = ?")
+            .bind(run["id"].as_str().unwrap())
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(status, RunStatus::Succeeded);
+
+        let flow = sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ?")
+            .bind(flow_id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert!(flow.last_run_at.is_some());
+
+        let lock = format!("flow_run_lock:{}", user.id);
+        assert!(cache::acquire_lock(&state.cache, &lock, "test", 60)
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn cannot_export_another_users_flow() {
+        let state = testing::state().await;
+        let owner = testing::create_user(&state.db).await;
+        let other = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_export),
+        )
+        .await;
+
+        let cookie = testing::login(&app, &owner).await;
+        let flow = testing::import_flow(&app, &cookie, UNKNOWN_FLOW_YAML).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/api/v1/flows/{}/export",
+                flow["id"].as_str().unwrap()
+            ))
+            .cookie(testing::login(&app, &other).await)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn can_schedule_flow() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_schedule),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let flow = testing::import_flow(&app, &cookie, &format!("name: Test{}", FLOW_YAML)).await;
+        let uri = format!("/api/v1/flows/{}/schedule", flow["id"].as_str().unwrap());
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .cookie(cookie.clone())
+            .set_json(serde_json::json!({ "cron_expr": "0 * * * *" }))
+            .to_request();
+        let schedule: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(schedule["flow_id"], flow["id"]);
+        assert_eq!(schedule["enabled"], true);
+        assert_eq!(schedule["next_run_at"].as_i64().unwrap() % 3600, 0);
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .cookie(cookie)
+            .set_json(serde_json::json!({ "cron_expr": "every hour" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn can_cancel_running_flow() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_run_cancel),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        let flow = testing::import_flow(&app, &cookie, &format!("name: Test{}", FLOW_YAML)).await;
+        let flow_id = flow["id"].as_str().unwrap();
+
+        // Simulate a run in progress, and one that has already finished
+        for (run_id, status) in [("running", "running"), ("finished", "succeeded")] {
+            sqlx::query(
+                "INSERT INTO flow_runs (id, flow_id, user_id, status, started_at) VALUES (?, ?, ?, ?, 0)",
+            )
+            .bind(run_id)
+            .bind(flow_id)
+            .bind(&user.id)
+            .bind(status)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        }
+
+        let cancel = |run_id: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/flows/{}/run/cancel", flow_id))
+                .cookie(cookie.clone())
+                .set_json(serde_json::json!({ "run_id": run_id }))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, cancel("running")).await;
+        assert_eq!(res.status(), 202);
+        assert!(cache::is_flag_set(&state.cache, &cancel_key("running"))
+            .await
+            .unwrap());
+
+        let res = test::call_service(&app, cancel("finished")).await;
+        assert_eq!(res.status(), 400);
+        assert!(!cache::is_flag_set(&state.cache, &cancel_key("finished"))
+            .await
+            .unwrap());
+
+        let res = test::call_service(&app, cancel("missing")).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn can_execute_batch_of_flows() {
+        let state = testing::state().await;
+        let user = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_execute_batch),
+        )
+        .await;
+        let cookie = testing::login(&app, &user).await;
+
+        // An empty flow succeeds without calling Spotify, an unknown component fails when run
+        let mut flow_ids = Vec::new();
+        let unknown = "name: Future\nnodes:\n  b38547f9-22cc-47ab-94bb-da695ee3ac4b:\n    component: source:future_thing\nedges: []\n";
+        for yaml in ["name: Empty\nnodes: {}\nedges: []\n", unknown] {
+            let flow = testing::import_flow(&app, &cookie, yaml).await;
+            flow_ids.push(flow["id"].as_str().unwrap().to_owned());
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/execute_batch")
+            .cookie(cookie.clone())
+            .set_json(serde_json::json!({ "flow_ids": flow_ids }))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res["succeeded"], 1);
+        assert_eq!(res["failed"], 1);
+
+        let results = res["results"].as_array().unwrap();
+        assert_eq!(results[0]["flow_id"], flow_ids[0].as_str());
+        assert!(results[0]["report"].is_object());
+        assert_eq!(results[1]["flow_id"], flow_ids[1].as_str());
+        assert!(results[1]["report"].is_null());
+        assert!(results[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Unknown component"));
+
+        // Only the successful run moves the flow's last run cursor on
+        for (flow_id, ran) in flow_ids.iter().zip([true, false]) {
+            let flow = sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ?")
+                .bind(flow_id)
+                .fetch_one(&state.db)
+                .await
+                .unwrap();
+            assert_eq!(flow.last_run_at.is_some(), ran, "{}", flow.name);
+        }
+
+        // The lock is released once the batch completes
+        let lock = format!("flow_run_lock:{}", user.id);
+        assert!(cache::acquire_lock(&state.cache, &lock, "test", 60)
+            .await
+            .unwrap());
+
+        // Oversized batches are rejected before anything runs
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/execute_batch")
+            .cookie(cookie)
+            .set_json(
+                serde_json::json!({ "flow_ids": vec![&flow_ids[0]; super::BATCH_EXECUTE_MAX_FLOWS + 1] }),
+            )
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn can_cancel_run_by_id() {
+        let state = testing::state().await;
+        let owner = testing::create_user(&state.db).await;
+        let other = testing::create_user(&state.db).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(testing::session())
+                .app_data(state.clone())
+                .service(testing::login_handler)
+                .service(super::api_v1_flows_import)
+                .service(super::api_v1_flows_cancel),
+        )
+        .await;
+        let cookie = testing::login(&app, &owner).await;
+
+        let flow = testing::import_flow(&app, &cookie, &format!("name: Test{}", FLOW_YAML)).await;
+
+        sqlx::query(
+            "INSERT INTO flow_runs (id, flow_id, user_id, status, started_at) VALUES ('run', ?, ?, 'running', 0)",
+        )
+        .bind(flow["id"].as_str().unwrap())
+        .bind(&owner.id)
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        // Only the owner of the run may cancel it
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/run/cancel")
+            .cookie(testing::login(&app, &other).await)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        assert!(!cache::is_flag_set(&state.cache, &cancel_key("run"))
+            .await
+            .unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/flows/run/cancel")
+            .cookie(cookie)
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["id"], "run");
+        assert!(cache::is_flag_set(&state.cache, &cancel_key("run"))
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,77 @@
+use actix_web::{get, http::header, HttpRequest, HttpResponse};
+
+use crate::metrics;
+
+/// Report the metrics accumulated since the server started.
+///
+/// Served in the Prometheus text exposition format, unless JSON is explicitly accepted.
+#[get("/metrics")]
+pub async fn metrics_get_handler(req: HttpRequest) -> HttpResponse {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if accept.contains("application/json") {
+        return HttpResponse::Ok().json(metrics::global().snapshot());
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::global().prometheus())
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics;
+    use actix_web::{http::header, test, App};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn can_report_component_metrics() {
+        metrics::global().record_component("combiner:zip_test", Duration::from_millis(4), Some(3));
+
+        let app = test::init_service(App::new().service(super::metrics_get_handler)).await;
+        let req = test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let zip = &res["components"]["combiner:zip_test"];
+        assert_eq!(zip["executions"], 1);
+        assert_eq!(zip["failures"], 0);
+        assert_eq!(zip["tracks"], 3);
+        assert!(zip["average_duration_ms"].as_f64().unwrap() >= 4.0);
+    }
+
+    #[actix_web::test]
+    async fn can_scrape_prometheus_metrics() {
+        metrics::global().record_request("GET", "/api/v1/flows", Duration::from_millis(12));
+
+        let app = test::init_service(App::new().service(super::metrics_get_handler)).await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4"
+        );
+
+        let body = test::read_body(res).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        for name in [
+            "spl_http_request_duration_seconds_bucket",
+            "spl_http_request_duration_seconds_count",
+            "spl_flow_executions_total",
+            "spl_spotify_api_calls_total",
+            "spl_cache_hits_total",
+            "spl_cache_misses_total",
+            "spl_component_executions_total",
+        ] {
+            assert!(body.contains(name), "missing {name} in\n{body}");
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod api_cache;
 pub mod api_spotify;
+pub mod api_web;
 pub mod auth;
+pub mod flows;
+pub mod metrics;
@@ -0,0 +1,187 @@
+//! Legacy - Translate SmarterPlaylists.com programs into flows
+//!
+//! A legacy program is a set of components keyed by id, each naming the components it reads
+//! from, e.g.
+//!
+//! ```json
+//! {
+//!     "name": "Top 10",
+//!     "components": {
+//!         "1": { "type": "ArtistTopTracks", "params": { "uri": "spotify:artist:..." } },
+//!         "2": { "type": "First", "params": { "count": 10 }, "sources": ["1"] },
+//!         "3": { "type": "PlaylistSave", "params": { "name": "Top 10" }, "sources": ["2"] }
+//!     }
+//! }
+//! ```
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{
+    components::{Component, NonExhaustive},
+    controller::UserDefinedFlow,
+    error::{PublicError, Result},
+};
+
+/// LegacyProgram is a SmarterPlaylists.com program, as exported by the original site.
+#[derive(Deserialize, Debug)]
+pub struct LegacyProgram {
+    pub name: String,
+    pub components: HashMap<String, LegacyComponent>,
+}
+
+/// LegacyComponent is a single node of a [`LegacyProgram`].
+#[derive(Deserialize, Debug)]
+pub struct LegacyComponent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub params: Map<String, Value>,
+    /// The ids of the components this one reads from, in order.
+    #[serde(default)]
+    pub sources: Vec<String>,
+}
+
+impl LegacyProgram {
+    /// Translate the program into a flow, giving every component a fresh node id.
+    ///
+    /// Components without an equivalent here (or whose parameters don't translate) are kept as
+    /// `NonExhaustive::Unknown` nodes named `legacy:{type}`, so they are reported as warnings
+    /// when the flow is saved. Returns PublicError::BadRequest if a component reads from one
+    /// that doesn't exist.
+    pub fn to_flow(&self) -> Result<UserDefinedFlow> {
+        // Sort to give the same edge order across HashMap iteration orders
+        let mut ids: Vec<&String> = self.components.keys().collect();
+        ids.sort();
+
+        let node_ids: HashMap<&String, Uuid> = ids.iter().map(|id| (*id, Uuid::new_v4())).collect();
+
+        let mut nodes = HashMap::new();
+        let mut edges = Vec::new();
+        for id in ids {
+            let component = &self.components[id];
+            nodes.insert(node_ids[id], component.translate());
+
+            for source in component.sources.iter() {
+                let lhs = node_ids.get(source).ok_or_else(|| {
+                    PublicError::bad_request(format!(
+                        "Component {} reads from {}, which doesn't exist",
+                        id, source
+                    ))
+                })?;
+                edges.push((*lhs, node_ids[id]));
+            }
+        }
+
+        Ok(UserDefinedFlow { nodes, edges })
+    }
+}
+
+impl LegacyComponent {
+    /// Map the component onto its equivalent, falling back to an unknown `legacy:{type}` node.
+    fn translate(&self) -> NonExhaustive<Component> {
+        let param = |name: &str| self.params.get(name).cloned().unwrap_or(Value::Null);
+
+        let (component, parameters) = match self.kind.as_str() {
+            "ArtistTopTracks" => ("source:artist_top_tracks", json!({ "id": param("uri") })),
+            "AlbumSource" => ("source:album", json!({ "id": param("uri") })),
+            "MySavedTracks" => ("source:user_liked_tracks", json!({})),
+            "First" => (
+                "filter:take",
+                json!({ "limit": param("count"), "from": "start" }),
+            ),
+            "Last" => (
+                "filter:take",
+                json!({ "limit": param("count"), "from": "end" }),
+            ),
+            "DeDup" => ("filter:dedup_track", json!({})),
+            "Alternate" => ("combiner:round_robin", json!({})),
+            "PlaylistSave" => match param("append").as_bool().unwrap_or_default() {
+                true => ("output:append", json!({ "by_name": param("name") })),
+                false => ("output:overwrite", json!({ "by_name": param("name") })),
+            },
+            _ => return self.unknown(),
+        };
+
+        match Component::from_parts(component, parameters) {
+            Ok(component) => NonExhaustive::Known(component),
+            Err(_) => self.unknown(),
+        }
+    }
+
+    fn unknown(&self) -> NonExhaustive<Component> {
+        NonExhaustive::Unknown(json!({
+            "component": format!("legacy:{}", self.kind),
+            "parameters": self.params,
+        }))
+    }
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"{
+        "name": "Top 10",
+        "components": {
+            "1": { "type": "ArtistTopTracks", "params": { "uri": "spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH" } },
+            "2": { "type": "MySavedTracks" },
+            "3": { "type": "Alternate", "sources": ["1", "2"] },
+            "4": { "type": "Shuffler", "params": { "seed": 1 }, "sources": ["3"] },
+            "5": { "type": "First", "params": { "count": 10 }, "sources": ["4"] },
+            "6": { "type": "PlaylistSave", "params": { "name": "Top 10", "append": true }, "sources": ["5"] }
+        }
+    }"#;
+
+    #[test]
+    fn can_translate_legacy_program() {
+        let program: LegacyProgram = serde_json::from_str(PROGRAM).unwrap();
+        let flow = program.to_flow().unwrap();
+
+        let name = |id: &Uuid| match &flow.nodes[id] {
+            NonExhaustive::Known(c) => c.name().to_owned(),
+            NonExhaustive::Unknown(v) => v["component"].as_str().unwrap().to_owned(),
+        };
+        let mut edges: Vec<(String, String)> =
+            flow.edges.iter().map(|(l, r)| (name(l), name(r))).collect();
+        edges.sort();
+
+        assert_eq!(flow.nodes.len(), 6);
+        assert_eq!(
+            edges,
+            [
+                ("combiner:round_robin", "legacy:Shuffler"),
+                ("filter:take", "output:append"),
+                ("legacy:Shuffler", "filter:take"),
+                ("source:artist_top_tracks", "combiner:round_robin"),
+                ("source:user_liked_tracks", "combiner:round_robin"),
+            ]
+            .map(|(l, r)| (l.to_owned(), r.to_owned()))
+        );
+
+        // Unsupported components keep their parameters, so nothing is lost
+        let unknown = flow.unknown_components();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].1, "legacy:Shuffler");
+        assert_eq!(
+            serde_json::to_value(&flow.nodes[&unknown[0].0]).unwrap()["parameters"],
+            json!({ "seed": 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_source() {
+        let program: LegacyProgram = serde_json::from_str(
+            r#"{ "name": "Broken", "components": { "1": { "type": "DeDup", "sources": ["2"] } } }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            program.to_flow(),
+            Err(PublicError::BadRequest { .. })
+        ));
+    }
+}
@@ -3,28 +3,48 @@ mod cache;
 mod components;
 mod controller;
 mod error;
+mod extractors;
 mod handlers;
+mod legacy;
 mod macros;
+mod metrics;
 mod models;
+mod request_id;
 mod routes;
+mod scheduler;
+mod shutdown;
 mod spotify;
+#[cfg(test)]
+mod testing;
+mod typescript;
 
 use actix_session::{storage::CookieSessionStore, SessionMiddleware};
 use actix_web::{
     cookie::Key,
+    dev::Service,
     http::StatusCode,
     main,
     middleware::{ErrorHandlerResponse, ErrorHandlers},
-    web, App, HttpServer,
+    rt, web, App, HttpServer,
 };
 use cache::RedisPool;
 use dotenv::dotenv;
+use futures_util::FutureExt;
+use shutdown::RunTracker;
 use sqlx::sqlite::SqlitePool;
-use std::env;
+use std::{
+    env,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for in-flight requests and flow runs to finish on shutdown.
+const SHUTDOWN_TIMEOUT_SECONDS: u64 = 60;
 
 pub struct ApplicationState {
     db: SqlitePool,
     cache: RedisPool,
+    runs: RunTracker,
 }
 
 #[main]
@@ -33,7 +53,23 @@ async fn main() -> std::io::Result<()> {
 
     std::env::set_var("RUST_LOG", "warn");
     std::env::set_var("RUST_BACKTRACE", "0");
-    env_logger::init();
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            // Tag every line logged while handling a request with its ID, see `request_id`
+            let request_id = request_id::current()
+                .map(|id| format!(" request_id={}", id))
+                .unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {} {}{}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            )
+        })
+        .init();
 
     // SQLite DB Connection Pool
     let db_pool = SqlitePool::connect("smarterplaylists-rs.db3?mode=rwc")
@@ -64,23 +100,64 @@ async fn main() -> std::io::Result<()> {
     let state = web::Data::new(ApplicationState {
         db: db_pool,
         cache: cache_pool,
+        runs: RunTracker::default(),
     });
+    let runs = state.runs.clone();
+
+    // Run scheduled flows in the background
+    rt::spawn(scheduler::run(state.clone()));
 
     // --
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(SessionMiddleware::new(
                 CookieSessionStore::default(),
                 session_key.clone(),
             ))
             .wrap(ErrorHandlers::new().handler(StatusCode::INTERNAL_SERVER_ERROR, error_logger))
+            .wrap_fn(|req, srv| {
+                // Group by route pattern rather than path, so ids don't explode the label set
+                let started = Instant::now();
+                let method = req.method().to_string();
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_owned());
+                srv.call(req).map(move |res| {
+                    metrics::global().record_request(&method, &route, started.elapsed());
+                    res
+                })
+            })
+            .wrap_fn(request_id::middleware)
             .app_data(state.clone())
             .service(routes::router())
     })
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_SECONDS)
     .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .run();
+
+    // On shutdown stop accepting new flow runs, wait for the in-flight ones, then stop accepting
+    // connections, letting in-flight requests finish
+    let handle = server.handle();
+    rt::spawn(async move {
+        shutdown::signal().await;
+        log::warn!("shutdown signal received, waiting for in-flight flow runs");
+
+        // Flows run on the blocking thread pool and may outlive their request - Wait for them
+        // so we don't leave playlists half updated.
+        // n.b. This must happen before the server stops, as stopping it stops the worker
+        // arbiters, dropping the tasks that record each run's outcome and release its lock
+        runs.drain(Duration::from_secs(SHUTDOWN_TIMEOUT_SECONDS))
+            .await;
+
+        log::warn!("waiting for in-flight requests");
+        handle.stop(true).await;
+    });
+
+    server.await?;
+
+    Ok(())
 }
 
 //
@@ -0,0 +1,402 @@
+//! Metrics - Process-wide counters describing what the server has been doing
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+/// Upper bounds of the request latency histogram buckets, in seconds.
+const REQUEST_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds of the flow execution duration histogram buckets, in seconds.
+/// n.b. Flows page through the Spotify API, so take far longer than a request
+const FLOW_DURATION_BUCKETS: [f64; 9] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// ComponentStats accumulates the executions of a single component type.
+#[derive(Clone, Copy, Debug, Default)]
+struct ComponentStats {
+    executions: u64,
+    failures: u64,
+    tracks: u64,
+    duration: Duration,
+}
+
+/// Histogram counts observations into N buckets, e.g. the REQUEST_DURATION_BUCKETS.
+/// n.b. Bucket counts are not cumulative here, they are summed when rendered.
+#[derive(Clone, Copy, Debug)]
+struct Histogram<const N: usize> {
+    buckets: [u64; N],
+    count: u64,
+    sum: f64,
+}
+
+impl<const N: usize> Default for Histogram<N> {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; N],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    fn observe(&mut self, bounds: &[f64; N], value: f64) {
+        if let Some(i) = bounds.iter().position(|le| value <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    components: BTreeMap<&'static str, ComponentStats>,
+    /// (Method, Route pattern) -> Latency
+    requests: BTreeMap<(String, String), Histogram<{ REQUEST_DURATION_BUCKETS.len() }>>,
+    /// Succeeded, Failed
+    flow_executions: (u64, u64),
+    flow_durations: Histogram<{ FLOW_DURATION_BUCKETS.len() }>,
+    spotify_calls: BTreeMap<&'static str, u64>,
+    /// Hits, Misses
+    cache: (u64, u64),
+}
+/// ComponentMetrics is the public snapshot of a component's stats.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ComponentMetrics {
+    pub executions: u64,
+    pub failures: u64,
+    /// The total number of tracks returned by successful executions.
+    pub tracks: u64,
+    pub average_duration_ms: f64,
+}
+
+/// MetricsSnapshot is the point-in-time view of the registry returned by `GET /metrics`.
+#[derive(Serialize, Debug)]
+pub struct MetricsSnapshot {
+    /// Component name -> Metrics, sorted by name.
+    pub components: BTreeMap<&'static str, ComponentMetrics>,
+}
+
+/// Registry holds the metrics for the lifetime of the process.
+///
+/// Components run on scoped threads deep inside the controller, so they record into the
+/// [`global`] registry rather than having one threaded through to them.
+#[derive(Clone, Default)]
+pub struct Registry {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Registry {
+    /// Record one execution of the named component, with the number of tracks it returned, or
+    /// None if it failed.
+    pub fn record_component(&self, name: &'static str, duration: Duration, tracks: Option<usize>) {
+        let mut all = self.stats.lock().unwrap();
+        let stats = all.components.entry(name).or_default();
+
+        stats.executions += 1;
+        stats.duration += duration;
+        match tracks {
+            Some(n) => stats.tracks += n as u64,
+            None => stats.failures += 1,
+        }
+    }
+
+    /// Record the latency of an HTTP request, grouped by method and matched route pattern.
+    pub fn record_request(&self, method: &str, route: &str, duration: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        stats
+            .requests
+            .entry((method.to_owned(), route.to_owned()))
+            .or_default()
+            .observe(&REQUEST_DURATION_BUCKETS, duration.as_secs_f64());
+    }
+
+    /// Record a complete run of a flow.
+    pub fn record_flow_execution(&self, succeeded: bool, duration: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        match succeeded {
+            true => stats.flow_executions.0 += 1,
+            false => stats.flow_executions.1 += 1,
+        }
+        stats
+            .flow_durations
+            .observe(&FLOW_DURATION_BUCKETS, duration.as_secs_f64());
+    }
+
+    /// Record a request made to the Spotify Web API.
+    /// n.b. Paginated listings are recorded once, rather than once per page fetched.
+    pub fn record_spotify_call(&self, endpoint: &'static str) {
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .spotify_calls
+            .entry(endpoint)
+            .or_default() += 1;
+    }
+
+    /// Record a cache lookup in `cache::get_or_create_with_stats`.
+    pub fn record_cache(&self, hit: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        match hit {
+            true => stats.cache.0 += 1,
+            false => stats.cache.1 += 1,
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let stats = self.stats.lock().unwrap();
+        MetricsSnapshot {
+            components: stats
+                .components
+                .iter()
+                .map(|(name, stats)| {
+                    let average = stats.duration.as_secs_f64() * 1000.0 / stats.executions as f64;
+                    (
+                        *name,
+                        ComponentMetrics {
+                            executions: stats.executions,
+                            failures: stats.failures,
+                            tracks: stats.tracks,
+                            average_duration_ms: average,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Render the registry in the Prometheus text exposition format.
+    /// @ref https://prometheus.io/docs/instrumenting/exposition_formats
+    pub fn prometheus(&self) -> String {
+        let stats = self.stats.lock().unwrap();
+        let mut out = String::new();
+
+        let name = "spl_http_request_duration_seconds";
+        header(&mut out, name, "histogram", "HTTP request latency.");
+        for ((method, route), h) in stats.requests.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            histogram(&mut out, name, &labels, &REQUEST_DURATION_BUCKETS, h);
+        }
+
+        let name = "spl_flow_execution_duration_seconds";
+        header(&mut out, name, "histogram", "Flow run duration.");
+        histogram(
+            &mut out,
+            name,
+            "",
+            &FLOW_DURATION_BUCKETS,
+            &stats.flow_durations,
+        );
+
+        header(
+            &mut out,
+            "spl_flow_executions_total",
+            "counter",
+            "Flow runs, by result.",
+        );
+        let (succeeded, failed) = stats.flow_executions;
+        writeln!(
+            out,
+            "spl_flow_executions_total{{result=\"success\"}} {}",
+            succeeded
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "spl_flow_executions_total{{result=\"failure\"}} {}",
+            failed
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "spl_spotify_api_calls_total",
+            "counter",
+            "Spotify Web API requests, by endpoint.",
+        );
+        for (endpoint, count) in stats.spotify_calls.iter() {
+            writeln!(
+                out,
+                "spl_spotify_api_calls_total{{endpoint=\"{}\"}} {}",
+                endpoint, count
+            )
+            .unwrap();
+        }
+
+        let (hits, misses) = stats.cache;
+        header(
+            &mut out,
+            "spl_cache_hits_total",
+            "counter",
+            "Cache lookups served from Redis.",
+        );
+        writeln!(out, "spl_cache_hits_total {}", hits).unwrap();
+        header(
+            &mut out,
+            "spl_cache_misses_total",
+            "counter",
+            "Cache lookups that ran their callback.",
+        );
+        writeln!(out, "spl_cache_misses_total {}", misses).unwrap();
+
+        header(
+            &mut out,
+            "spl_component_executions_total",
+            "counter",
+            "Component executions, by component.",
+        );
+        for (name, c) in stats.components.iter() {
+            writeln!(
+                out,
+                "spl_component_executions_total{{component=\"{}\"}} {}",
+                name, c.executions
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "spl_component_failures_total",
+            "counter",
+            "Failed component executions, by component.",
+        );
+        for (name, c) in stats.components.iter() {
+            writeln!(
+                out,
+                "spl_component_failures_total{{component=\"{}\"}} {}",
+                name, c.failures
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Render the `_bucket`, `_sum` and `_count` series of a histogram with the given labels.
+fn histogram<const N: usize>(
+    out: &mut String,
+    name: &str,
+    labels: &str,
+    bounds: &[f64; N],
+    h: &Histogram<N>,
+) {
+    let prefix = match labels {
+        "" => String::new(),
+        labels => format!("{},", labels),
+    };
+    let mut cumulative = 0;
+    for (le, count) in bounds.iter().zip(h.buckets) {
+        cumulative += count;
+        writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, prefix, le, cumulative
+        )
+        .unwrap();
+    }
+    writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, prefix, h.count).unwrap();
+
+    let labels = match labels {
+        "" => String::new(),
+        labels => format!("{{{}}}", labels),
+    };
+    writeln!(out, "{}_sum{} {}", name, labels, h.sum).unwrap();
+    writeln!(out, "{}_count{} {}", name, labels, h.count).unwrap();
+}
+
+/// Escape a label value - backslashes, quotes and newlines must be escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Return the process-wide metrics registry.
+pub fn global() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_record_component_executions() {
+        let registry = Registry::default();
+        registry.record_component("filter:take", Duration::from_millis(10), Some(5));
+        registry.record_component("filter:take", Duration::from_millis(30), None);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(
+            snapshot.components["filter:take"],
+            ComponentMetrics {
+                executions: 2,
+                failures: 1,
+                tracks: 5,
+                average_duration_ms: 20.0,
+            }
+        );
+    }
+
+    #[test]
+    fn can_render_prometheus_format() {
+        let registry = Registry::default();
+        registry.record_request("GET", "/api/v1/flows", Duration::from_millis(20));
+        registry.record_request("GET", "/api/v1/flows", Duration::from_millis(200));
+        registry.record_flow_execution(true, Duration::from_secs(3));
+        registry.record_spotify_call("me");
+        registry.record_cache(true);
+        registry.record_cache(false);
+        registry.record_cache(false);
+
+        let text = registry.prometheus();
+        let labels = "method=\"GET\",route=\"/api/v1/flows\"";
+        for line in [
+            "# TYPE spl_http_request_duration_seconds histogram".to_owned(),
+            format!(
+                "spl_http_request_duration_seconds_bucket{{{},le=\"0.01\"}} 0",
+                labels
+            ),
+            format!(
+                "spl_http_request_duration_seconds_bucket{{{},le=\"0.025\"}} 1",
+                labels
+            ),
+            format!(
+                "spl_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                labels
+            ),
+            format!("spl_http_request_duration_seconds_count{{{}}} 2", labels),
+            "spl_flow_executions_total{result=\"success\"} 1".to_owned(),
+            "spl_flow_executions_total{result=\"failure\"} 0".to_owned(),
+            "# TYPE spl_flow_execution_duration_seconds histogram".to_owned(),
+            "spl_flow_execution_duration_seconds_bucket{le=\"2.5\"} 0".to_owned(),
+            "spl_flow_execution_duration_seconds_bucket{le=\"5\"} 1".to_owned(),
+            "spl_flow_execution_duration_seconds_sum 3".to_owned(),
+            "spl_flow_execution_duration_seconds_count 1".to_owned(),
+            "spl_spotify_api_calls_total{endpoint=\"me\"} 1".to_owned(),
+            "spl_cache_hits_total 1".to_owned(),
+            "spl_cache_misses_total 2".to_owned(),
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+}
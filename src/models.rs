@@ -1,7 +1,10 @@
-use rspotify::model::UserId;
+use rspotify::model::{Market, UserId};
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 use ulid::Ulid;
 
+use crate::controller::{ExecutionReport, UserDefinedFlow};
+
 /// User holds the details of an authenticated spotify user.
 ///
 /// The most up-to-date spotify token is stored in the `spotify_access_token` row as a JSON string.
@@ -14,6 +17,12 @@ pub struct User {
     pub spotify_email: String,
     #[sqlx(default, try_from = "String")]
     pub spotify_access_token: Token,
+    #[sqlx(default)]
+    pub spotify_country: Option<String>,
+    /// The user's subscription level, `free` or `premium`. Some features (e.g. playback) need
+    /// premium.
+    #[sqlx(default)]
+    pub spotify_product: Option<String>,
 }
 
 impl User {
@@ -28,6 +37,17 @@ impl User {
     pub fn token(&self) -> Option<rspotify::Token> {
         Some(self.spotify_access_token.0.to_owned().unwrap())
     }
+
+    /// Build a spotify client authenticated with the user's stored token.
+    pub fn client(&self) -> rspotify::AuthCodeSpotify {
+        crate::spotify::init(self.token())
+    }
+
+    /// The market to use when a flow doesn't specify one, from the user's home country (or the
+    /// configured default, see `spotify::market`).
+    pub fn market(&self) -> Market {
+        crate::spotify::market(self.spotify_country.as_deref())
+    }
 }
 
 /// Token holds the spotify auth details
@@ -51,3 +71,98 @@ impl Into<String> for Token {
         serde_json::to_string(&self.0).unwrap()
     }
 }
+
+/// Flow holds a user's saved flow.
+///
+/// The flow definition is stored in the `definition` row as a JSON string.
+/// `last_run_at` is the unix timestamp (in seconds) that the last successful run started at.
+#[derive(sqlx::FromRow, Serialize, Deserialize)]
+pub struct Flow {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub definition: Json<UserDefinedFlow>,
+    #[sqlx(default)]
+    pub last_run_at: Option<i64>,
+}
+
+/// FlowSchedule runs one of a user's flows automatically.
+///
+/// `next_run_at` is a unix timestamp (in seconds), computed from the `cron_expr` after each run.
+/// Schedules whose expression never matches again are disabled.
+#[derive(sqlx::FromRow, Serialize, Deserialize)]
+pub struct FlowSchedule {
+    pub id: String,
+    pub flow_id: String,
+    pub user_id: String,
+    pub cron_expr: String,
+    pub next_run_at: i64,
+    pub enabled: bool,
+}
+
+/// RunStatus is the state of a single run of a flow.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// FlowRun records a single run of one of a user's flows.
+///
+/// Runs execute in the background, so the report (or error) is only set once the run finishes.
+#[derive(sqlx::FromRow, Serialize, Deserialize)]
+pub struct FlowRun {
+    pub id: String,
+    pub flow_id: String,
+    pub user_id: String,
+    pub status: RunStatus,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub report: Option<Json<ExecutionReport>>,
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rspotify::model::Country;
+
+    #[actix_web::test]
+    async fn client_carries_user_token() {
+        let app = testing::state().await;
+        let user = testing::create_user(&app.db).await;
+
+        let client = user.client();
+        let token = client.token.lock().unwrap().clone();
+
+        assert!(token.is_some());
+        assert_eq!(token, user.token());
+    }
+
+    #[actix_web::test]
+    async fn market_defaults_to_stored_country() {
+        let app = testing::state().await;
+        let mut user = testing::create_user(&app.db).await;
+        // Without a stored country the configured default is used, see `spotify::market`
+        assert_eq!(user.market(), crate::spotify::market(None));
+
+        sqlx::query("UPDATE users SET spotify_country = ? WHERE id = ?")
+            .bind("SE")
+            .bind(&user.id)
+            .execute(&app.db)
+            .await
+            .unwrap();
+        user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(&user.id)
+            .fetch_one(&app.db)
+            .await
+            .unwrap();
+
+        assert_eq!(user.market(), Market::Country(Country::Sweden));
+    }
+}
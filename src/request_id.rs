@@ -0,0 +1,155 @@
+//! Request IDs - Correlate the log lines written while handling a request
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage,
+};
+use std::{cell::RefCell, future::Future};
+use ulid::Ulid;
+
+/// The header a request ID is read from, and echoed back in.
+pub const HEADER: &str = "x-request-id";
+
+/// The longest request ID accepted from a client, longer IDs are replaced.
+const MAX_LENGTH: usize = 128;
+
+/// RequestId is the correlation ID of the current request, available as a request extension.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+thread_local! {
+    // n.b. Flows run on the blocking thread pool, where the task-local isn't visible
+    static BLOCKING: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Return the ID of the request being handled by the current task or blocking thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT
+        .try_with(Clone::clone)
+        .ok()
+        .or_else(|| BLOCKING.with(|id| id.borrow().clone()))
+}
+
+/// Run `f` with `id` as the current request ID, e.g. inside `web::block`.
+pub fn scope_blocking<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    let _restore = RestoreBlocking(BLOCKING.with(|current| current.replace(id)));
+    f()
+}
+
+/// RestoreBlocking puts back the previous blocking request ID when dropped, so a panicking `f`
+/// can't leave its ID behind on a reused pool thread.
+struct RestoreBlocking(Option<String>);
+
+impl Drop for RestoreBlocking {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        BLOCKING.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Run the future with `id` as the current request ID, e.g. for a spawned background task.
+pub async fn scope<F: Future>(id: Option<String>, f: F) -> F::Output {
+    match id {
+        Some(id) => CURRENT.scope(id, f).await,
+        None => f.await,
+    }
+}
+
+/// Middleware assigning each request an ID, used with `App::wrap_fn`.
+///
+/// The ID is taken from the X-Request-Id header when it is a reasonable value, otherwise a ULID
+/// is generated. It is set as the current request ID while the request is handled, inserted as
+/// a [`RequestId`] extension, and echoed back in the response headers.
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let id = req
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Ulid::new().to_string());
+
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let fut = srv.call(req);
+
+    CURRENT.scope(id.clone(), async move {
+        let mut res = fut.await?;
+        // n.b. Valid IDs are visible ASCII, so are always valid header values
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(HEADER), value);
+        }
+        Ok(res)
+    })
+}
+
+/// Accept IDs of visible ASCII characters only, so they can't break up a log line.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{get, test, App, HttpRequest, HttpResponse, Responder};
+
+    #[get("/request_id")]
+    async fn request_id_handler(req: HttpRequest) -> impl Responder {
+        let extension = req.extensions().get::<RequestId>().cloned().unwrap();
+        assert_eq!(current(), Some(extension.0.clone()));
+        HttpResponse::Ok().body(extension.0)
+    }
+
+    #[actix_web::test]
+    async fn echoes_provided_request_id() {
+        let app =
+            test::init_service(App::new().wrap_fn(middleware).service(request_id_handler)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/request_id")
+            .insert_header((HEADER, "abc-123"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(HEADER).unwrap(), "abc-123");
+        assert_eq!(test::read_body(res).await, "abc-123");
+
+        // Missing or unreasonable IDs are replaced with a generated one
+        let too_long = "x".repeat(MAX_LENGTH + 1);
+        for header in [None, Some("has spaces"), Some(too_long.as_str())] {
+            let mut req = test::TestRequest::get().uri("/request_id");
+            if let Some(header) = header {
+                req = req.insert_header((HEADER, header));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            let id = res.headers().get(HEADER).unwrap().to_str().unwrap();
+            assert!(Ulid::from_string(id).is_ok(), "{}", id);
+        }
+    }
+
+    #[actix_web::test]
+    async fn blocking_scope_sets_current_id() {
+        assert_eq!(current(), None);
+        let id = scope_blocking(Some("blocking".to_owned()), current);
+        assert_eq!(id.as_deref(), Some("blocking"));
+        assert_eq!(current(), None);
+
+        // The previous ID is restored even if `f` panics
+        let res = std::panic::catch_unwind(|| {
+            scope_blocking(Some("panicking".to_owned()), || panic!("run failed"))
+        });
+        assert!(res.is_err());
+        assert_eq!(current(), None);
+    }
+}
@@ -5,10 +5,32 @@ use std::io;
 pub fn router() -> Scope {
     web::scope("")
         // API Routes
+        .service(crate::handlers::api_cache::api_v1_cache_flush)
         .service(crate::handlers::api_spotify::api_v1_spotify_user_playlists)
+        .service(crate::handlers::api_spotify::api_v1_spotify_writable_playlists)
+        .service(crate::handlers::api_web::api_v1_web_components_schema_ts)
+        .service(crate::handlers::api_web::api_v1_web_components_available)
+        .service(crate::handlers::api_web::api_v1_web_component_schema)
+        .service(crate::handlers::api_web::api_v1_web_component_validate)
+        .service(crate::handlers::flows::api_v1_flows_validate)
+        .service(crate::handlers::flows::api_v1_flows_examples)
+        .service(crate::handlers::flows::api_v1_flows_export_m3u)
+        .service(crate::handlers::flows::api_v1_flows_export_csv)
+        .service(crate::handlers::flows::api_v1_flows_import)
+        .service(crate::handlers::flows::api_v1_flows_import_legacy)
+        .service(crate::handlers::flows::api_v1_flows_update)
+        .service(crate::handlers::flows::api_v1_flows_export)
+        .service(crate::handlers::flows::api_v1_flows_clone)
+        .service(crate::handlers::flows::api_v1_flows_execute_batch)
+        .service(crate::handlers::flows::api_v1_flows_run)
+        .service(crate::handlers::flows::api_v1_flows_run_cancel)
+        .service(crate::handlers::flows::api_v1_flows_cancel)
+        .service(crate::handlers::flows::api_v1_flows_schedule)
+        .service(crate::handlers::metrics::metrics_get_handler)
         // Auth Routes
         .service(crate::handlers::auth::auth_me_handler)
         .service(crate::handlers::auth::auth_sso_redirect_handler)
+        .service(crate::handlers::auth::auth_sso_flow_redirect_handler)
         .service(crate::handlers::auth::auth_sso_callback_handler)
         // --
         .service(index_get_handler)
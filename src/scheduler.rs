@@ -0,0 +1,324 @@
+//! Scheduled runs - Run saved flows automatically, on a cron-like schedule
+use actix_web::{rt, web};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use std::str::FromStr;
+use ulid::Ulid;
+
+use crate::{
+    cache,
+    error::PublicError,
+    handlers::flows::{advance_last_run, run_blocking, run_lock, FLOW_RUN_LOCK_TTL_SECONDS},
+    models::{Flow, FlowSchedule},
+    ApplicationState,
+};
+
+/// How often the scheduler wakes to look for due schedules, in seconds.
+const SCHEDULER_POLL_INTERVAL_SECONDS: u64 = 60;
+
+/// How far ahead to search for the next run, before deciding an expression never matches.
+/// n.b. Leap days only come around every four years, e.g. `0 0 29 2 *`
+const CRON_MAX_SEARCH_YEARS: i32 = 5;
+
+/// CronExpr is a parsed 5-field cron expression - `minute hour day-of-month month day-of-week`.
+///
+/// Each field supports `*`, single values, ranges (`1-5`), lists (`1,3,5`) and steps (`*/15`,
+/// `0-30/10`). Day-of-week is 0-6 starting on Sunday, with 7 also accepted for Sunday.
+/// As with cron, when both day fields are restricted a day matching either one is a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, dom, month, dow] = fields[..] else {
+            return Err(format!("expected 5 fields, found {}", fields.len()));
+        };
+
+        // Fold Sunday = 7 into Sunday = 0
+        let mut days_of_week = parse_field(dow, 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        Ok(CronExpr {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days_of_month: parse_field(dom, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            days_of_week,
+            any_day_of_month: dom == "*",
+            any_day_of_week: dow == "*",
+        })
+    }
+}
+
+impl CronExpr {
+    /// Return the first time strictly after `after` that matches the expression.
+    ///
+    /// Returns None if the expression never matches, e.g. `0 0 31 2 *`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let limit = after.year() + CRON_MAX_SEARCH_YEARS;
+
+        // Start from the next whole minute
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        while t.year() <= limit {
+            if !bit(self.months, t.month()) {
+                // Skip to the start of the next month
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    m => (t.year(), m + 1),
+                };
+                t = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.matches_day(t) {
+                t = Utc.from_utc_datetime(&t.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?);
+            } else if !bit(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if !bit(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, t: DateTime<Utc>) -> bool {
+        let dom = bit(self.days_of_month, t.day());
+        let dow = bit(self.days_of_week, t.weekday().num_days_from_sunday());
+
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => dom || dow,
+            (true, false) => dow,
+            (false, true) => dom,
+            (true, true) => true,
+        }
+    }
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// Parse a single cron field into a bitmask of the allowed values.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|s| *s > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(|| format!("invalid step in {}", part))?;
+
+        let value = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("{} is not a value between {} and {}", s, min, max))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // n.b. A stepped single value runs to the end of the range, e.g. `5/15`
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("invalid range {}", range));
+        }
+
+        for n in (start..=end).step_by(step) {
+            mask |= 1 << n;
+        }
+    }
+
+    Ok(mask)
+}
+
+// --
+
+/// Run the scheduler until the server shuts down, waking every poll interval to run the due flows.
+pub async fn run(app: web::Data<ApplicationState>) {
+    let mut interval = rt::time::interval(std::time::Duration::from_secs(
+        SCHEDULER_POLL_INTERVAL_SECONDS,
+    ));
+
+    loop {
+        interval.tick().await;
+        if let Err(err) = run_due(&app, Utc::now()).await {
+            log::error!("scheduler failed to load due schedules: {}", err);
+        }
+    }
+}
+
+/// Run every enabled schedule that is due at `now`.
+///
+/// A failed run is logged and skipped, so one broken flow can't stop the others from running.
+async fn run_due(app: &ApplicationState, now: DateTime<Utc>) -> Result<(), PublicError> {
+    let due = sqlx::query_as::<_, FlowSchedule>(
+        "SELECT * FROM schedules WHERE enabled = TRUE AND next_run_at <= ? ORDER BY next_run_at",
+    )
+    .bind(now.timestamp())
+    .fetch_all(&app.db)
+    .await?;
+
+    for schedule in due {
+        // Move the schedule on before running it, so a failing flow isn't retried every tick
+        let next_run_at = schedule
+            .cron_expr
+            .parse::<CronExpr>()
+            .ok()
+            .and_then(|cron| cron.next_after(now));
+        sqlx::query("UPDATE schedules SET next_run_at = ?, enabled = ? WHERE id = ?")
+            .bind(next_run_at.map_or(schedule.next_run_at, |t| t.timestamp()))
+            .bind(next_run_at.is_some())
+            .bind(&schedule.id)
+            .execute(&app.db)
+            .await?;
+
+        if let Err(err) = run_schedule(app, &schedule).await {
+            log::error!("scheduled run of flow {} failed: {}", schedule.flow_id, err);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_schedule(app: &ApplicationState, schedule: &FlowSchedule) -> Result<(), PublicError> {
+    let flow = sqlx::query_as::<_, Flow>("SELECT * FROM flows WHERE id = ? AND user_id = ?")
+        .bind(&schedule.flow_id)
+        .bind(&schedule.user_id)
+        .fetch_optional(&app.db)
+        .await?
+        .ok_or(PublicError::NotFound)?;
+
+    // n.b. Held until the lock is released, so shutdown can't exit while the user is locked out
+    let guard = app.runs.start(&flow.id)?;
+
+    // Share the lock with manual runs, so the two can't overlap
+    let lock = run_lock(&schedule.user_id);
+    let token = Ulid::new().to_string();
+    if !cache::acquire_lock(&app.cache, &lock, &token, FLOW_RUN_LOCK_TTL_SECONDS).await? {
+        return Err(PublicError::Locked);
+    }
+
+    let started_at = Utc::now().timestamp();
+    let flow_id = flow.id.clone();
+    let res = run_blocking(
+        app,
+        &schedule.user_id,
+        guard.clone(),
+        move |client, market| {
+            flow.definition
+                .0
+                .with_default_market(market)
+                .with_last_run(flow.last_run_at)
+                .execute(client)
+        },
+    )
+    .await;
+    let res = match res {
+        Ok(_) => advance_last_run(app, &flow_id, started_at).await,
+        Err(err) => Err(err),
+    };
+
+    cache::release_lock(&app.cache, &lock, &token).await?;
+    drop(guard);
+    res
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expr: &str, after: &str) -> Option<DateTime<Utc>> {
+        expr.parse::<CronExpr>().unwrap().next_after(at(after))
+    }
+
+    #[test]
+    fn can_parse_cron_fields() {
+        let cron: CronExpr = "*/15 9-17 1,15 * 7".parse().unwrap();
+
+        assert_eq!(cron.minutes, 1 << 0 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, (9..=17).fold(0, |m, h| m | 1 << h));
+        assert_eq!(cron.days_of_month, 1 << 1 | 1 << 15);
+        assert_eq!(cron.months, (1..=12).fold(0, |m, n| m | 1 << n));
+        // Sunday may be written as 7
+        assert_eq!(cron.days_of_week, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for expr in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expr.parse::<CronExpr>().is_err(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn next_run_is_strictly_after() {
+        assert_eq!(
+            next("30 6 * * *", "2023-06-10T06:30:00Z"),
+            Some(at("2023-06-11T06:30:00Z"))
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2023-06-10T06:31:42Z"),
+            Some(at("2023-06-10T06:45:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_run_rolls_over_months_and_years() {
+        assert_eq!(
+            next("0 0 1 * *", "2023-12-15T12:00:00Z"),
+            Some(at("2024-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2023-03-01T00:00:00Z"),
+            Some(at("2024-02-29T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_run_matches_either_restricted_day() {
+        // 2023-06-10 is a Saturday - the next Monday is the 12th, before the 15th
+        assert_eq!(
+            next("0 8 15 * 1", "2023-06-10T00:00:00Z"),
+            Some(at("2023-06-12T08:00:00Z"))
+        );
+        assert_eq!(
+            next("0 8 * * 1-5", "2023-06-10T00:00:00Z"),
+            Some(at("2023-06-12T08:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_run_is_none_when_never_matching() {
+        assert_eq!(next("0 0 31 2 *", "2023-06-10T00:00:00Z"), None);
+    }
+}
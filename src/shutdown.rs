@@ -0,0 +1,186 @@
+//! Graceful shutdown - Wait for in-flight flow runs before the server exits
+use actix_web::rt;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use ulid::Ulid;
+
+use crate::error::PublicError;
+
+/// How often `drain` checks whether the in-flight runs have finished.
+const DRAIN_POLL_INTERVAL_MILLIS: u64 = 100;
+
+/// RunTracker keeps track of the flows currently being executed.
+///
+/// Flows run on the blocking thread pool, so they outlive the HTTP request that started them.
+/// On shutdown we stop accepting new runs, and wait for the tracked runs to finish, rather than
+/// killing them mid-write and leaving the target playlist half updated.
+#[derive(Clone, Default)]
+pub struct RunTracker {
+    // Run id -> Flow id
+    running: Arc<Mutex<HashMap<Ulid, String>>>,
+    draining: Arc<AtomicBool>,
+}
+
+impl RunTracker {
+    /// Register a new run of the given flow. The run is tracked until the guard is dropped.
+    ///
+    /// Returns PublicError::ShuttingDown once the tracker is draining.
+    pub fn start(&self, flow_id: &str) -> Result<RunGuard, PublicError> {
+        // n.b. Checked under the lock, so a run can't slip in after `drain` sees none running
+        let mut running = self.running.lock().unwrap();
+        if self.is_draining() {
+            return Err(PublicError::ShuttingDown);
+        }
+
+        let id = Ulid::new();
+        running.insert(id, flow_id.to_owned());
+
+        Ok(RunGuard {
+            _entry: Arc::new(RunEntry {
+                id,
+                tracker: self.clone(),
+            }),
+        })
+    }
+
+    /// Reject any new runs, leaving the in-flight runs to finish.
+    pub fn stop_accepting(&self) {
+        let _running = self.running.lock().unwrap();
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Return whether new runs are being rejected, as the server is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Return the ids of the flows currently running.
+    pub fn running(&self) -> Vec<String> {
+        self.running.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Stop accepting new runs, and wait for all in-flight runs to finish.
+    ///
+    /// Returns false if runs were still in-flight when the timeout elapsed.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.stop_accepting();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = self.running();
+            if running.is_empty() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                log::error!("shutdown timed out waiting for flows: {:?}", running);
+                return false;
+            }
+
+            rt::time::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MILLIS)).await;
+        }
+    }
+}
+
+/// RunGuard marks a run as finished once it, and every clone of it, is dropped.
+///
+/// Clones let the blocking thread keep the run tracked if the request that started it is
+/// dropped, while the caller keeps it tracked until its own bookkeeping is done.
+#[derive(Clone)]
+pub struct RunGuard {
+    // n.b. Only held for its Drop, see RunEntry
+    _entry: Arc<RunEntry>,
+}
+
+struct RunEntry {
+    id: Ulid,
+    tracker: RunTracker,
+}
+
+impl Drop for RunEntry {
+    fn drop(&mut self) {
+        self.tracker.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Wait for a shutdown signal - Ctrl+C, or SIGTERM on unix.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use futures_util::future::{select, FutureExt};
+        use rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        select(rt::signal::ctrl_c().boxed(), terminate.recv().boxed()).await;
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = rt::signal::ctrl_c().await;
+    }
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[actix_web::test]
+    async fn drain_waits_for_in_flight_runs() {
+        let runs = RunTracker::default();
+        let guard = runs.start("flow").unwrap();
+        assert_eq!(runs.running(), ["flow"]);
+
+        let started = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(guard);
+        });
+
+        assert!(runs.drain(Duration::from_secs(5)).await);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(runs.running().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn run_is_tracked_until_every_guard_is_dropped() {
+        let runs = RunTracker::default();
+        let guard = runs.start("flow").unwrap();
+        let clone = guard.clone();
+
+        drop(guard);
+        assert_eq!(runs.running(), ["flow"]);
+        drop(clone);
+        assert!(runs.running().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn drain_gives_up_after_timeout() {
+        let runs = RunTracker::default();
+        let _guard = runs.start("flow").unwrap();
+
+        assert!(!runs.drain(Duration::from_millis(200)).await);
+        assert_eq!(runs.running(), ["flow"]);
+    }
+
+    #[actix_web::test]
+    async fn draining_rejects_new_runs() {
+        let runs = RunTracker::default();
+        let guard = runs.start("running").unwrap();
+
+        runs.stop_accepting();
+        assert!(runs.is_draining());
+        assert!(matches!(runs.start("new"), Err(PublicError::ShuttingDown)));
+
+        // The in-flight run is still tracked, and is waited for
+        assert_eq!(runs.running(), ["running"]);
+        drop(guard);
+        assert!(runs.drain(Duration::from_secs(1)).await);
+    }
+}
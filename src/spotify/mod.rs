@@ -1,8 +1,74 @@
 use rspotify;
+use rspotify::model::{Country, Market};
 use rspotify::Token;
 use std::env;
 
+/// The largest page most Spotify list endpoints will return.
+pub const MAX_PAGE_SIZE: u32 = 50;
+
+/// Return the page size used when fetching paginated lists.
+///
+/// Defaults to [`MAX_PAGE_SIZE`], and can be lowered with $SPL_SPOTIFY_PAGE_SIZE - values outside
+/// 1-50 are clamped.
+pub fn page_size() -> u32 {
+    parse_page_size(env::var("SPL_SPOTIFY_PAGE_SIZE").ok().as_deref())
+}
+
+fn parse_page_size(value: Option<&str>) -> u32 {
+    value
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .map_or(MAX_PAGE_SIZE, |size| size.clamp(1, MAX_PAGE_SIZE))
+}
+
+/// The market used when a user's country is unknown, and $SPL_DEFAULT_MARKET isn't set.
+pub const DEFAULT_MARKET: Country = Country::UnitedStates;
+
+/// Resolve the market to request tracks for, from a user's ISO 3166-1 alpha-2 country code.
+///
+/// Falls back to $SPL_DEFAULT_MARKET (or [`DEFAULT_MARKET`]) when the country is missing or isn't
+/// a known code - `Market::FromToken` fails for tokens without a country, so isn't used.
+pub fn market(country: Option<&str>) -> Market {
+    resolve_market(country, env::var("SPL_DEFAULT_MARKET").ok().as_deref())
+}
+
+fn resolve_market(country: Option<&str>, default: Option<&str>) -> Market {
+    if let Some(country) = country.and_then(parse_country) {
+        return Market::Country(country);
+    }
+
+    let fallback = default.and_then(parse_country).unwrap_or(DEFAULT_MARKET);
+    log::warn!(
+        "unknown market for country {:?}, falling back to {:?}",
+        country,
+        fallback
+    );
+    Market::Country(fallback)
+}
+
+fn parse_country(code: &str) -> Option<Country> {
+    serde_json::from_value(serde_json::Value::from(code.trim().to_uppercase())).ok()
+}
+
+/// The scopes requested at login, enough to run every component.
+/// @ref https://developer.spotify.com/documentation/general/guides/authorization/scopes
+pub const DEFAULT_SCOPES: [&str; 6] = [
+    "playlist-read-private",   // Read access to user's private playlists.
+    "playlist-modify-private", // Write access to a user's private playlists.
+    "playlist-modify-public",  // Write access to a user's public playlists.
+    "user-follow-read", // Read access to the list of artists and other users that the user follows.
+    "user-read-email",  // Read access to user’s email address.
+    "user-library-read", // Read access to a user's library.
+];
+
 pub fn init(token: Option<Token>) -> rspotify::AuthCodeSpotify {
+    init_with_scopes(token, DEFAULT_SCOPES)
+}
+
+/// Build a client as with [`init`], requesting the given scopes when authorizing.
+pub fn init_with_scopes<'a>(
+    token: Option<Token>,
+    scopes: impl IntoIterator<Item = &'a str>,
+) -> rspotify::AuthCodeSpotify {
     // RSpotify Instance
     // Note: Pull OAuth client id/client secret from environment variables, panicing if not found
     let spotify_creds = rspotify::Credentials::new(
@@ -11,16 +77,8 @@ pub fn init(token: Option<Token>) -> rspotify::AuthCodeSpotify {
     );
 
     let spotify_oauth = rspotify::OAuth {
-        // Scopes - Add scopes for reading and writing to a users playlists
-        // @ref https://developer.spotify.com/documentation/general/guides/authorization/scopes
-        scopes: rspotify::scopes!(
-            "playlist-read-private",   // Read access to user's private playlists.
-            "playlist-modify-private", // Write access to a user's private playlists.
-            "playlist-modify-public",  // Write access to a user's public playlists.
-            "user-follow-read", // Read access to the list of artists and other users that the user follows.
-            "user-read-email",  // Read access to user’s email address.
-            "user-library-read"  // Read access to a user's library.
-        ),
+        // Scopes - See DEFAULT_SCOPES for the full set
+        scopes: scopes.into_iter().map(str::to_owned).collect(),
 
         // Redirect URI
         // TODO: Dynamicly build this based on production/public URL environment variable
@@ -43,9 +101,11 @@ pub fn init(token: Option<Token>) -> rspotify::AuthCodeSpotify {
 
 pub mod auth {
 
+    use crate::controller::UserDefinedFlow;
     use crate::error::Result;
     use rspotify::prelude::*;
     use rspotify::Token;
+    use std::collections::{BTreeSet, HashSet};
 
     // Request the access/refresh token using the given auth code.
     // The return tokens should be persisted in the database
@@ -76,4 +136,104 @@ pub mod auth {
     pub fn authorize_uri() -> String {
         crate::spotify::init(None).get_authorize_url(true).unwrap()
     }
+
+    // Build an authorize URL requesting the scopes needed to run the given flow.
+    // Note: A new token replaces the old one, so the user's granted scopes are requested too -
+    // re-authorizing for one flow must never revoke a scope another flow relies on
+    pub fn authorize_uri_for(flow: &UserDefinedFlow, granted: &HashSet<String>) -> String {
+        let scopes: BTreeSet<&str> = flow
+            .required_scopes()
+            .into_iter()
+            .chain(granted.iter().map(String::as_str))
+            .collect();
+
+        crate::spotify::init_with_scopes(None, scopes)
+            .get_authorize_url(true)
+            .unwrap()
+    }
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_page_size() {
+        assert_eq!(parse_page_size(None), MAX_PAGE_SIZE);
+        assert_eq!(parse_page_size(Some("not a number")), MAX_PAGE_SIZE);
+        assert_eq!(parse_page_size(Some("10")), 10);
+        assert_eq!(parse_page_size(Some("0")), 1);
+        assert_eq!(parse_page_size(Some("500")), MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn can_build_minimal_scope_authorize_uri() {
+        std::env::set_var("SPL_SPOTIFY_CLIENT_ID", "test-client-id");
+        std::env::set_var("SPL_SPOTIFY_CLIENT_SECRET", "test-client-secret");
+
+        let flow: crate::controller::UserDefinedFlow = serde_yaml::from_str(
+            r#"
+nodes:
+    da0e029b-7a25-424e-b031-fc1271e38069:
+        component: source:user_liked_tracks
+        parameters: {}
+    b38547f9-22cc-47ab-94bb-da695ee3ac4b:
+        component: source:artist_top_tracks
+        parameters:
+            id: spotify:artist:6qqNVTkY8uBg9cP3Jd7DAH
+edges: []
+"#,
+        )
+        .unwrap();
+
+        let scopes = |uri: &str| {
+            let param = uri
+                .split(['?', '&'])
+                .find_map(|p| p.strip_prefix("scope="))
+                .unwrap()
+                .replace("%20", "+");
+            let mut scopes: Vec<String> = param.split('+').map(str::to_owned).collect();
+            scopes.sort();
+            scopes
+        };
+
+        assert_eq!(
+            scopes(&auth::authorize_uri_for(&flow, &Default::default())),
+            ["user-library-read"]
+        );
+        assert_eq!(scopes(&auth::authorize_uri()).len(), DEFAULT_SCOPES.len());
+
+        // Scopes the user already granted are kept, so re-authorizing never revokes them
+        let granted = ["playlist-modify-private", "user-library-read"]
+            .map(str::to_owned)
+            .into();
+        assert_eq!(
+            scopes(&auth::authorize_uri_for(&flow, &granted)),
+            ["playlist-modify-private", "user-library-read"]
+        );
+    }
+
+    #[test]
+    fn can_resolve_market() {
+        let resolve = |country| resolve_market(country, None);
+        assert_eq!(resolve(Some("GB")), Market::Country(Country::UnitedKingdom));
+        assert_eq!(
+            resolve(Some("not a country")),
+            Market::Country(DEFAULT_MARKET)
+        );
+        assert_eq!(resolve(None), Market::Country(DEFAULT_MARKET));
+    }
+
+    #[test]
+    fn market_falls_back_to_configured_default() {
+        let sweden = Market::Country(Country::Sweden);
+        assert_eq!(resolve_market(None, Some("se")), sweden);
+        assert_eq!(resolve_market(Some("GB"), Some("SE")), market(Some("GB")));
+
+        // An invalid default is ignored
+        let us = Market::Country(DEFAULT_MARKET);
+        assert_eq!(resolve_market(None, Some("not a country")), us);
+    }
 }
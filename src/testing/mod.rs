@@ -0,0 +1,348 @@
+//! Shared helpers for unit tests. Only compiled with `cfg(test)`.
+pub mod redis;
+pub mod spotify;
+
+use actix_http::Request;
+use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, Key},
+    dev::{Service, ServiceResponse},
+    get,
+    http::header,
+    test, web, HttpResponse,
+};
+use chrono::Duration;
+use rspotify::model::{
+    AlbumId, ArtistId, AudioFeatures, FullPlaylist, FullTrack, Modality, Page, PlayableItem,
+    PlaylistId, PlaylistItem, PlaylistTracksRef, PrivateUser, PublicUser, SimplifiedAlbum,
+    SimplifiedArtist, SimplifiedPlaylist, SimplifiedTrack, TrackId, UserId,
+};
+use rspotify::prelude::Id;
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::collections::HashMap;
+use ulid::Ulid;
+
+use crate::{cache, components::Track, models::User, shutdown::RunTracker, ApplicationState};
+
+/// Build the application state, backed by a fresh in-memory SQLite database and Redis stub.
+pub async fn state() -> web::Data<ApplicationState> {
+    // Flows run through `spotify::init`, which needs credentials to build the client
+    std::env::set_var("SPL_SPOTIFY_CLIENT_ID", "test-client-id");
+    std::env::set_var("SPL_SPOTIFY_CLIENT_SECRET", "test-client-secret");
+
+    // Each in-memory connection is its own database - only ever open one
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+    let redis = redis::MockRedis::start();
+    let cache = cache::connect(&redis.host(), &redis.port(), "", "")
+        .await
+        .unwrap();
+
+    web::Data::new(ApplicationState {
+        db,
+        cache,
+        runs: RunTracker::default(),
+    })
+}
+
+/// Insert a new user into the database.
+pub async fn create_user(db: &SqlitePool) -> User {
+    let id = Ulid::new().to_string();
+    sqlx::query(
+        "INSERT INTO users (id, spotify_id, spotify_username, spotify_email, spotify_access_token) VALUES (?, ?, ?, ?, ?)"
+    )
+        .bind(&id)
+        .bind(format!("spotify:user:{}", id.to_lowercase()))
+        .bind("Test User")
+        .bind(format!("{}@example.com", id.to_lowercase()))
+        .bind(serde_json::to_string(&Some(rspotify::Token::default())).unwrap())
+        .execute(db)
+        .await
+        .unwrap();
+
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&id)
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+/// Session middleware using a fixed test key.
+pub fn session() -> SessionMiddleware<CookieSessionStore> {
+    SessionMiddleware::new(CookieSessionStore::default(), Key::from(&[0; 64]))
+}
+
+/// Test-only route that authenticates the session as the given user.
+#[get("/test/login/{user_id}")]
+pub async fn login_handler(
+    session: Session,
+    user_id: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    session.insert("user_id", user_id.into_inner())?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Log in as the given user, returning the session cookie.
+///
+/// n.b. The app must be wrapped with [`session`] and include the [`login_handler`] service.
+pub async fn login<S, B>(app: &S, user: &User) -> Cookie<'static>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::get()
+        .uri(&format!("/test/login/{}", user.id))
+        .to_request();
+    let res = test::call_service(app, req).await;
+
+    res.response().cookies().next().unwrap().into_owned()
+}
+
+/// Save a flow from the given YAML document as the logged in user, returning the saved flow.
+///
+/// n.b. The app must include the `api_v1_flows_import` service.
+pub async fn import_flow<S, B>(app: &S, cookie: &Cookie<'static>, yaml: &str) -> serde_json::Value
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::post()
+        .uri("/api/v1/flows")
+        .cookie(cookie.clone())
+        .insert_header((header::CONTENT_TYPE, "application/yaml"))
+        .set_payload(yaml.to_owned())
+        .to_request();
+
+    test::call_and_read_body_json(app, req).await
+}
+
+/// Build a minimal Track fixture with the given (base62) id.
+///
+/// The track has a single artist (`artist`), belongs to a single album (`album`) and
+/// is otherwise empty - tests should override the fields they care about.
+pub fn track(id: &str) -> Track {
+    Track::from(FullTrack {
+        album: album("album"),
+        artists: vec![artist("artist")],
+        available_markets: Vec::new(),
+        disc_number: 1,
+        duration: Duration::seconds(180),
+        explicit: false,
+        external_ids: HashMap::new(),
+        external_urls: HashMap::new(),
+        href: None,
+        id: Some(TrackId::from_id(id.to_owned()).unwrap()),
+        is_local: false,
+        is_playable: None,
+        linked_from: None,
+        restrictions: None,
+        name: format!("Track {id}"),
+        popularity: 0,
+        preview_url: None,
+        track_number: 1,
+    })
+}
+
+/// Convert a Track fixture into the SimplifiedTrack returned by e.g. album endpoints.
+pub fn simplified_track(track: Track) -> SimplifiedTrack {
+    let track = track.track;
+    SimplifiedTrack {
+        artists: track.artists,
+        available_markets: Some(track.available_markets),
+        disc_number: track.disc_number,
+        duration: track.duration,
+        explicit: track.explicit,
+        external_urls: track.external_urls,
+        href: track.href,
+        id: track.id,
+        is_local: track.is_local,
+        is_playable: track.is_playable,
+        linked_from: track.linked_from,
+        restrictions: track.restrictions,
+        name: track.name,
+        preview_url: track.preview_url,
+        track_number: track.track_number,
+    }
+}
+
+/// Build an AudioFeatures fixture for the given (base62) track id.
+///
+/// Every normalized feature is 0.5 and the tempo is 125 BPM, tests should override the
+/// features they care about.
+pub fn features(id: &str) -> AudioFeatures {
+    AudioFeatures {
+        acousticness: 0.5,
+        analysis_url: String::new(),
+        danceability: 0.5,
+        duration: Duration::seconds(180),
+        energy: 0.5,
+        id: TrackId::from_id(id.to_owned()).unwrap(),
+        instrumentalness: 0.5,
+        key: 0,
+        liveness: 0.5,
+        loudness: -10.0,
+        mode: Modality::Major,
+        speechiness: 0.5,
+        tempo: 125.0,
+        time_signature: 4,
+        track_href: String::new(),
+        valence: 0.5,
+    }
+}
+
+/// Build a SimplifiedArtist fixture with the given (base62) id.
+pub fn artist(id: &str) -> SimplifiedArtist {
+    SimplifiedArtist {
+        id: Some(ArtistId::from_id(id.to_owned()).unwrap()),
+        name: format!("Artist {id}"),
+        ..Default::default()
+    }
+}
+
+/// Build a SimplifiedAlbum fixture with the given (base62) id.
+pub fn album(id: &str) -> SimplifiedAlbum {
+    SimplifiedAlbum {
+        id: Some(AlbumId::from_id(id.to_owned()).unwrap()),
+        name: format!("Album {id}"),
+        ..Default::default()
+    }
+}
+
+/// Return the ids of the given tracks, in order.
+pub fn ids(tracks: &[Track]) -> Vec<String> {
+    tracks
+        .iter()
+        .map(|t| {
+            t.id.as_ref()
+                .map(|id| id.id().to_owned())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Build a PublicUser fixture with the given id.
+pub fn user(id: &str) -> PublicUser {
+    PublicUser {
+        display_name: Some(format!("User {id}")),
+        external_urls: HashMap::new(),
+        followers: None,
+        href: String::new(),
+        id: UserId::from_id(id.to_owned()).unwrap(),
+        images: Vec::new(),
+    }
+}
+
+/// Build a PrivateUser fixture (the result of `me()`) with the given id.
+pub fn me(id: &str) -> PrivateUser {
+    PrivateUser {
+        country: None,
+        display_name: Some(format!("User {id}")),
+        email: Some(format!("{id}@example.com")),
+        external_urls: HashMap::new(),
+        explicit_content: None,
+        followers: None,
+        href: String::new(),
+        id: UserId::from_id(id.to_owned()).unwrap(),
+        images: None,
+        product: None,
+    }
+}
+
+/// Build a SimplifiedPlaylist fixture with the given (base62) id, name, and owner id.
+pub fn playlist(id: &str, name: &str, owner: &str) -> SimplifiedPlaylist {
+    SimplifiedPlaylist {
+        collaborative: false,
+        external_urls: HashMap::new(),
+        href: String::new(),
+        id: PlaylistId::from_id(id.to_owned()).unwrap(),
+        images: Vec::new(),
+        name: name.to_owned(),
+        owner: user(owner),
+        public: Some(false),
+        snapshot_id: String::new(),
+        tracks: PlaylistTracksRef::default(),
+    }
+}
+
+/// Build a FullPlaylist fixture with the given (base62) id, name, and owner id.
+pub fn full_playlist(id: &str, name: &str, owner: &str) -> FullPlaylist {
+    FullPlaylist {
+        collaborative: false,
+        description: None,
+        external_urls: HashMap::new(),
+        followers: Default::default(),
+        href: String::new(),
+        id: PlaylistId::from_id(id.to_owned()).unwrap(),
+        images: Vec::new(),
+        name: name.to_owned(),
+        owner: user(owner),
+        public: Some(false),
+        snapshot_id: String::new(),
+        tracks: Page::default(),
+    }
+}
+
+/// Wrap a track in a PlaylistItem.
+pub fn playlist_item(track: Track) -> PlaylistItem {
+    PlaylistItem {
+        added_at: track.added_at,
+        track: Some(PlayableItem::Track(track.track)),
+        ..Default::default()
+    }
+}
+
+/// Wrap the given items in a single Page.
+pub fn page<T>(items: Vec<T>) -> Page<T> {
+    Page {
+        href: String::new(),
+        limit: items.len() as u32,
+        next: None,
+        offset: 0,
+        previous: None,
+        total: items.len() as u32,
+        items,
+    }
+}
+
+/// Return the field names serde reads when deserializing the struct `T` (aliases included), or
+/// none for any other type, e.g. to check a hand-written schema against it.
+pub fn serde_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    // n.b. Always fails, as there is no value to read - the fields are recorded on the way
+    let _ = T::deserialize(FieldsOf(&mut fields));
+    fields
+}
+
+/// FieldsOf is a Deserializer that only records the field names of the struct it is asked for.
+struct FieldsOf<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldsOf<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("no value"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
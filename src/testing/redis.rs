@@ -0,0 +1,281 @@
+//! A minimal in-memory Redis server, speaking just enough RESP for the cache module.
+//!
+//! Each test gets its own server (and keyspace), so tests can run in parallel without a real
+//! Redis instance.
+use mobc_redis::redis;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::cache::RELEASE_LOCK_SCRIPT;
+
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Instant::now())
+    }
+}
+
+type Store = Arc<Mutex<HashMap<String, Entry>>>;
+
+pub struct MockRedis {
+    addr: SocketAddr,
+}
+
+impl MockRedis {
+    /// Start the server on a random port. The server lives until the test process exits.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Store::default();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let store = Arc::clone(&store);
+                thread::spawn(move || handle(stream, &store));
+            }
+        });
+
+        MockRedis { addr }
+    }
+
+    pub fn host(&self) -> String {
+        self.addr.ip().to_string()
+    }
+
+    pub fn port(&self) -> String {
+        self.addr.port().to_string()
+    }
+}
+
+enum Reply {
+    Status(&'static str),
+    Int(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+    Error(String),
+    NoScript,
+}
+
+impl Reply {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(s) => out.extend(format!("+{}\r\n", s).as_bytes()),
+            Reply::Int(i) => out.extend(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(s)) => out.extend(format!("${}\r\n{}\r\n", s.len(), s).as_bytes()),
+            Reply::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write(out);
+                }
+            }
+            Reply::Error(e) => out.extend(format!("-ERR {}\r\n", e).as_bytes()),
+            Reply::NoScript => out.extend(b"-NOSCRIPT No matching script\r\n"),
+        }
+    }
+}
+
+fn handle(stream: TcpStream, store: &Mutex<HashMap<String, Entry>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    while let Some(args) = read_command(&mut reader) {
+        let reply = {
+            let mut store = store.lock().unwrap();
+            store.retain(|_, e| !e.is_expired());
+            execute(&mut store, &args)
+        };
+
+        let mut out = Vec::new();
+        reply.write(&mut out);
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+/// Read one command, sent as a RESP array of bulk strings - E.g. `*2\r\n$3\r\nGET\r\n$1\r\nk\r\n`
+fn read_command(reader: &mut impl BufRead) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let len: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+
+    let mut args = Vec::with_capacity(len);
+    for _ in 0..len {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let size: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+
+        // Read the value plus the trailing CRLF
+        let mut buf = vec![0; size + 2];
+        reader.read_exact(&mut buf).ok()?;
+        buf.truncate(size);
+        args.push(String::from_utf8_lossy(&buf).into_owned());
+    }
+
+    Some(args)
+}
+
+fn execute(store: &mut HashMap<String, Entry>, args: &[String]) -> Reply {
+    let name = args[0].to_uppercase();
+    let args = &args[1..];
+
+    match (name.as_str(), args) {
+        ("PING", _) => Reply::Status("PONG"),
+        ("GET", [key]) => Reply::Bulk(store.get(key).map(|e| e.value.clone())),
+        ("SET", [key, value, options @ ..]) => {
+            let mut expires_at = None;
+            let mut nx = false;
+
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.to_uppercase().as_str() {
+                    "NX" => nx = true,
+                    "EX" => expires_at = options.next().map(|s| seconds(s)),
+                    _ => return Reply::Error(format!("unsupported SET option {}", option)),
+                }
+            }
+
+            if nx && store.contains_key(key) {
+                return Reply::Bulk(None);
+            }
+
+            store.insert(
+                key.clone(),
+                Entry {
+                    value: value.clone(),
+                    expires_at,
+                },
+            );
+            Reply::Status("OK")
+        }
+        ("SETEX", [key, ttl, value]) => {
+            store.insert(
+                key.clone(),
+                Entry {
+                    value: value.clone(),
+                    expires_at: Some(seconds(ttl)),
+                },
+            );
+            Reply::Status("OK")
+        }
+        ("EXISTS", keys) => {
+            Reply::Int(keys.iter().filter(|k| store.contains_key(*k)).count() as i64)
+        }
+        ("DEL", keys) => {
+            Reply::Int(keys.iter().filter(|k| store.remove(*k).is_some()).count() as i64)
+        }
+        ("KEYS", [pattern]) => {
+            let mut keys: Vec<&String> = store.keys().filter(|k| matches(pattern, k)).collect();
+            keys.sort();
+            Reply::Array(
+                keys.into_iter()
+                    .map(|k| Reply::Bulk(Some(k.clone())))
+                    .collect(),
+            )
+        }
+        ("SCAN", [_cursor, options @ ..]) => {
+            // Always return everything in a single page
+            let pattern = options
+                .iter()
+                .position(|o| o.eq_ignore_ascii_case("MATCH"))
+                .and_then(|i| options.get(i + 1))
+                .map_or("*", |p| p.as_str());
+
+            let mut keys: Vec<&String> = store.keys().filter(|k| matches(pattern, k)).collect();
+            keys.sort();
+            Reply::Array(vec![
+                Reply::Bulk(Some("0".to_owned())),
+                Reply::Array(
+                    keys.into_iter()
+                        .map(|k| Reply::Bulk(Some(k.clone())))
+                        .collect(),
+                ),
+            ])
+        }
+        ("INCR", [key]) => {
+            let entry = store.entry(key.clone()).or_insert(Entry {
+                value: "0".to_owned(),
+                expires_at: None,
+            });
+            match entry.value.parse::<i64>() {
+                Ok(n) => {
+                    entry.value = (n + 1).to_string();
+                    Reply::Int(n + 1)
+                }
+                Err(_) => Reply::Error("value is not an integer".to_owned()),
+            }
+        }
+        ("EXPIRE", [key, ttl]) => match store.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = Some(seconds(ttl));
+                Reply::Int(1)
+            }
+            None => Reply::Int(0),
+        },
+        ("TTL", [key]) => match store.get(key) {
+            Some(Entry {
+                expires_at: Some(at),
+                ..
+            }) => Reply::Int(at.saturating_duration_since(Instant::now()).as_secs() as i64),
+            Some(_) => Reply::Int(-1),
+            None => Reply::Int(-2),
+        },
+        // Only the scripts the cache module uses are understood, recognised by their SHA1
+        ("SCRIPT", [sub, script]) if sub.eq_ignore_ascii_case("LOAD") => {
+            Reply::Bulk(Some(script_hash(script)))
+        }
+        ("EVALSHA", [hash, _, key, token]) if *hash == script_hash(RELEASE_LOCK_SCRIPT) => {
+            match store.get(key) {
+                Some(entry) if entry.value == *token => {
+                    store.remove(key);
+                    Reply::Int(1)
+                }
+                _ => Reply::Int(0),
+            }
+        }
+        ("EVALSHA", _) => Reply::NoScript,
+        _ => Reply::Error(format!("unsupported command {}", name)),
+    }
+}
+
+fn script_hash(script: &str) -> String {
+    redis::Script::new(script).get_hash().to_owned()
+}
+
+fn seconds(ttl: &str) -> Instant {
+    Instant::now() + Duration::from_secs(ttl.parse().unwrap_or(0))
+}
+
+/// Match a key against a glob pattern, only `*` wildcards are supported.
+fn matches(pattern: &str, key: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == key;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !key.starts_with(first) || !key[first.len()..].ends_with(last) {
+        return false;
+    }
+
+    // Find each middle part in order, between the prefix and suffix
+    let mut rest = &key[first.len()..key.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
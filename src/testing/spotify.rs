@@ -0,0 +1,204 @@
+//! A stubbed Spotify Web API served over HTTP on localhost.
+//!
+//! The returned client is a regular `AuthCodeSpotify` pointed at the stub, so components can be
+//! tested end-to-end without touching the real API.
+use rspotify::{AuthCodeSpotify as Client, Config, Credentials, OAuth, Token};
+use serde::Serialize;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// A request received by the stub.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// Return the value of the given query parameter, if present.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parse the request body as JSON.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+struct Route {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    status: u16,
+    body: String,
+}
+
+#[derive(Default)]
+struct State {
+    routes: Vec<Route>,
+    requests: Vec<Request>,
+}
+
+pub struct MockSpotify {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockSpotify {
+    /// Start the stub on a random port. The server lives until the test process exits.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(stream, &server_state);
+            }
+        });
+
+        MockSpotify { addr, state }
+    }
+
+    /// Respond to `method path` with the given JSON body.
+    ///
+    /// The path may include query parameters (e.g. `/v1/me/tracks?offset=50`), in which case
+    /// the route only matches requests carrying those parameters. When several routes match,
+    /// the one with the most query parameters wins, ties going to the last one registered.
+    pub fn on<T: Serialize>(&self, method: &str, path: &str, body: T) -> &Self {
+        self.on_status(method, path, 200, body)
+    }
+
+    /// Same as [`Self::on`] but with an explicit HTTP status.
+    pub fn on_status<T: Serialize>(&self, method: &str, path: &str, status: u16, body: T) -> &Self {
+        let (path, query) = split_query(path);
+        self.state.lock().unwrap().routes.push(Route {
+            method: method.to_owned(),
+            path,
+            query,
+            status,
+            body: serde_json::to_string(&body).unwrap(),
+        });
+        self
+    }
+
+    /// Return every request received for `method path`, in order.
+    pub fn requests(&self, method: &str, path: &str) -> Vec<Request> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|r| r.method == method && r.path == path)
+            .cloned()
+            .collect()
+    }
+
+    /// Build an authenticated client that talks to this stub.
+    pub fn client(&self) -> Client {
+        let config = Config {
+            api_base_url: format!("http://{}/v1/", self.addr),
+            ..Default::default()
+        };
+
+        let client = Client::with_config(Credentials::default(), OAuth::default(), config);
+        *client.token.lock().unwrap() = Some(Token {
+            access_token: "test-access-token".to_owned(),
+            ..Default::default()
+        });
+
+        client
+    }
+}
+
+fn split_query(path: &str) -> (String, Vec<(String, String)>) {
+    match path.split_once('?') {
+        Some((path, query)) => {
+            let pairs = query
+                .split('&')
+                .filter_map(|kv| kv.split_once('='))
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            (path.to_owned(), pairs)
+        }
+        None => (path.to_owned(), Vec::new()),
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    // Request line - E.g. "GET /v1/me/tracks?limit=50 HTTP/1.1"
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() || line.is_empty() {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let (path, query) = split_query(parts.next().unwrap_or_default());
+
+    // Headers - we only care about the body length
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let request = Request {
+        method,
+        path,
+        query,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+
+    let (status, body) = {
+        let mut state = state.lock().unwrap();
+        let route = state
+            .routes
+            .iter()
+            .filter(|r| r.method == request.method && r.path == request.path)
+            .filter(|r| r.query.iter().all(|kv| request.query.contains(kv)))
+            .max_by_key(|r| r.query.len());
+
+        let res = match route {
+            Some(route) => (route.status, route.body.clone()),
+            None => (
+                404,
+                r#"{"error": {"status": 404, "message": "no stub for route"}}"#.to_owned(),
+            ),
+        };
+
+        state.requests.push(request);
+        res
+    };
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} STUB\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
@@ -0,0 +1,152 @@
+//! TypeScript - Render JSON schemas as TypeScript type definitions for the frontend
+//!
+//! Only the subset of JSON schema used by the component schemas is supported: objects, strings,
+//! numbers, booleans, arrays, enums and consts. Anything else is rendered as `unknown`.
+use serde_json::Value;
+
+/// Render the [`Component::json_schema`](crate::components::Component::json_schema) as
+/// TypeScript.
+///
+/// Each titled `parameters` schema becomes an `export interface`, and the components are
+/// combined into a single `Component` union type.
+pub fn components(schema: &Value) -> String {
+    let mut interfaces = Vec::new();
+    let mut variants = Vec::new();
+
+    for variant in schema["oneOf"].as_array().into_iter().flatten() {
+        let name = &variant["properties"]["component"];
+        let parameters = &variant["properties"]["parameters"];
+        let title = parameters["title"].as_str().unwrap_or("unknown");
+
+        interfaces.push(format!(
+            "export interface {} {}\n",
+            title,
+            object(parameters, 0)
+        ));
+        variants.push(format!(
+            "  | {{ component: {}; parameters: {} }}",
+            literal(name.get("const").unwrap_or(&Value::Null)),
+            title
+        ));
+    }
+
+    format!(
+        "// Generated from the component JSON schema - do not edit.\n\n{}\nexport type Component =\n{};\n",
+        interfaces.join("\n"),
+        variants.join("\n")
+    )
+}
+
+/// Render a schema as a TypeScript type.
+fn type_of(schema: &Value, depth: usize) -> String {
+    if let Some(value) = schema.get("const") {
+        return literal(value);
+    }
+    if let Some(values) = schema["enum"].as_array() {
+        return values.iter().map(literal).collect::<Vec<_>>().join(" | ");
+    }
+
+    match schema["type"].as_str() {
+        Some("string") => "string".to_owned(),
+        Some("integer" | "number") => "number".to_owned(),
+        Some("boolean") => "boolean".to_owned(),
+        Some("array") => {
+            let items = type_of(&schema["items"], depth);
+            // Wrap unions so the array applies to the whole type, e.g. ("a" | "b")[]
+            if items.contains(" | ") {
+                format!("({})[]", items)
+            } else {
+                format!("{}[]", items)
+            }
+        }
+        Some("object") => object(schema, depth),
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Render an object schema as a TypeScript object type, one property per line.
+/// n.b. Properties that aren't required are optional.
+fn object(schema: &Value, depth: usize) -> String {
+    let Some(properties) = schema["properties"].as_object().filter(|p| !p.is_empty()) else {
+        return "{}".to_owned();
+    };
+
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+
+    let indent = "  ".repeat(depth + 1);
+    let mut out = String::from("{\n");
+    for (name, property) in properties {
+        let optional = if required.contains(&name.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        out += &format!(
+            "{}{}{}: {};\n",
+            indent,
+            name,
+            optional,
+            type_of(property, depth + 1)
+        );
+    }
+    out += &"  ".repeat(depth);
+    out += "}";
+    out
+}
+
+/// Render a JSON value as a TypeScript literal type.
+fn literal(value: &Value) -> String {
+    // n.b. JSON strings, numbers and booleans are also valid TypeScript literals
+    value.to_string()
+}
+
+// --
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Component;
+    use serde_json::json;
+
+    #[test]
+    fn can_render_known_component_interface() {
+        let ts = components(&Component::json_schema());
+
+        assert!(ts.contains(
+            "export interface TakeArgs {\n  limit: number;\n  from: \"start\" | \"end\";\n}\n"
+        ));
+        assert!(ts.contains(
+            "export interface OverwriteArgs {\n  by_name: string;\n  description?: string;\n  public?: boolean;\n}\n"
+        ));
+        assert!(ts.contains("export interface HasPreviewArgs {}\n"));
+        assert!(ts.contains("  | { component: \"filter:take\"; parameters: TakeArgs }\n"));
+    }
+
+    #[test]
+    fn can_render_nested_types() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "ids": { "type": "array", "items": { "type": "string" } },
+                "modes": { "type": "array", "items": { "enum": ["a", "b"] } },
+                "options": {
+                    "type": "object",
+                    "properties": { "enabled": { "type": "boolean" } },
+                    "required": ["enabled"],
+                },
+                "other": {},
+            },
+            "required": ["ids"],
+        });
+
+        assert_eq!(
+            object(&schema, 0),
+            "{\n  ids: string[];\n  modes?: (\"a\" | \"b\")[];\n  options?: {\n    enabled: boolean;\n  };\n  other?: unknown;\n}"
+        );
+    }
+}